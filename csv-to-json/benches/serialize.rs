@@ -0,0 +1,131 @@
+//! Throughput of serializing records with `serialize_json_seq`, for each output format and both when
+//! every record is flushed on its own and when records are accumulated into larger chunks.
+//!
+//! The `flush` group compares the two ways of flushing the serialization buffer on their own: copying
+//! its contents into a new `Bytes` and clearing it, or splitting them off and freezing them, which is
+//! what `serialize_json_seq` does. Run with `cargo bench --bench serialize`.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use csv_to_json::{serialize_json_seq, JsonElement, OutputFormat, OutputOptions};
+use futures::executor::block_on;
+use futures::{stream, TryStreamExt};
+use serde::Serialize;
+
+/// Number of records serialized in each iteration.
+const RECORDS: usize = 10_000;
+
+#[derive(Serialize)]
+struct Record {
+    id: u64,
+    name: &'static str,
+    email: &'static str,
+    score: f64,
+    active: bool,
+}
+
+impl JsonElement for Record {
+    fn key(&self) -> Option<&str> {
+        None
+    }
+}
+
+// NOTE: the records borrow their strings, so that creating them doesn't take up much of the measured time.
+fn records() -> impl Iterator<Item = Record> {
+    (0..RECORDS as u64).map(|id| Record {
+        id,
+        name: "Alice Example",
+        email: "alice@example.com",
+        score: id as f64 / 7.0,
+        active: id % 2 == 0,
+    })
+}
+
+/// Serializes the records, returning the total size of the output.
+fn serialize(options: OutputOptions) -> usize {
+    let values = stream::iter(records().map(Ok::<_, std::io::Error>));
+    let output = serialize_json_seq(options, values);
+    block_on(output.try_fold(0, |len, chunk| async move { Ok(len + chunk.len()) }))
+        .expect("records are serializable")
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize_json_seq");
+    for output in [
+        OutputFormat::Json,
+        OutputFormat::Ndjson,
+        OutputFormat::Msgpack,
+    ] {
+        for flush_bytes in [0, 64 * 1024] {
+            let options = OutputOptions {
+                output,
+                flush_bytes,
+                ..OutputOptions::default()
+            };
+            group.throughput(Throughput::Bytes(serialize(options) as u64));
+            let id = BenchmarkId::new(
+                output.file_extension(),
+                format!("flush_bytes={}", flush_bytes),
+            );
+            group.bench_with_input(id, &options, |b, &options| b.iter(|| serialize(options)));
+        }
+    }
+    group.finish();
+}
+
+/// Appends the serialized records to a buffer, flushing it whenever it holds at least `flush_bytes`,
+/// and returns the total size of the flushed chunks.
+fn flush_by_copying(records: &[Vec<u8>], flush_bytes: usize) -> usize {
+    let mut buffer = Vec::with_capacity(flush_bytes.max(1024));
+    let mut flushed = 0;
+    for record in records {
+        buffer.extend_from_slice(record);
+        if buffer.len() >= flush_bytes {
+            let chunk = Bytes::copy_from_slice(&buffer);
+            buffer.clear();
+            flushed += chunk.len();
+        }
+    }
+    flushed + Bytes::copy_from_slice(&buffer).len()
+}
+
+/// Like flush_by_copying, but splits the chunks off the buffer instead.
+fn flush_by_splitting(records: &[Vec<u8>], flush_bytes: usize) -> usize {
+    let mut buffer = BytesMut::with_capacity(flush_bytes.max(1024));
+    let mut flushed = 0;
+    for record in records {
+        buffer.extend_from_slice(record);
+        if buffer.len() >= flush_bytes {
+            let chunk = buffer.split().freeze();
+            flushed += chunk.len();
+        }
+    }
+    flushed + buffer.split().freeze().len()
+}
+
+fn bench_flush(c: &mut Criterion) {
+    let records: Vec<_> = records()
+        .map(|record| serde_json::to_vec(&record).expect("records are serializable"))
+        .collect();
+    let mut group = c.benchmark_group("flush");
+    group.throughput(Throughput::Bytes(
+        records.iter().map(Vec::len).sum::<usize>() as u64,
+    ));
+    for flush_bytes in [0, 64 * 1024] {
+        let parameter = format!("flush_bytes={}", flush_bytes);
+        group.bench_with_input(
+            BenchmarkId::new("copy", &parameter),
+            &flush_bytes,
+            |b, &flush_bytes| b.iter(|| flush_by_copying(&records, flush_bytes)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("split", &parameter),
+            &flush_bytes,
+            |b, &flush_bytes| b.iter(|| flush_by_splitting(&records, flush_bytes)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_serialize, bench_flush);
+criterion_main!(benches);
@@ -0,0 +1,248 @@
+use chrono::{DateTime, Utc};
+use clap::ArgEnum;
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::header::{HeaderMap, HeaderValue, REFERER, USER_AGENT};
+use hyper::{Body, Request, StatusCode, Version};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+/// Formats that access log lines can be written in.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// The Combined Log Format of Apache and nginx, followed by the duration in microseconds.
+    Clf,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+/// Writes a line for each request once its response has been sent.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, writer: Box<dyn Write + Send>) -> Self {
+        AccessLog {
+            format,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        AccessLog::new(format, Box::new(std::io::stdout()))
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            AccessLogFormat::Clf => entry.to_clf(),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        // NOTE: failing to write a log line shouldn't fail the request, which has already been sent.
+        let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What's logged about a request. The parts of the request are taken before it's handled, since it's
+/// consumed by then.
+#[derive(Debug)]
+pub struct Entry {
+    time: SystemTime,
+    started: Instant,
+    remote_addr: Option<SocketAddr>,
+    method: String,
+    uri: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: StatusCode,
+    request_id: Option<String>,
+    bytes_sent: u64,
+}
+
+impl Entry {
+    pub fn new(req: &Request<Body>, remote_addr: Option<SocketAddr>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Entry {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            remote_addr,
+            method: req.method().to_string(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string()),
+            version: req.version(),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            status: StatusCode::OK,
+            request_id: None,
+            bytes_sent: 0,
+        }
+    }
+
+    /// Records the parts of the response that are known before its body is sent.
+    pub fn set_response(
+        &mut self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        request_id_header: &str,
+    ) {
+        self.status = status;
+        self.request_id = headers
+            .get(request_id_header)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+    }
+
+    fn remote_addr(&self) -> String {
+        self.remote_addr
+            .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string())
+    }
+
+    fn to_clf(&self) -> String {
+        let time: DateTime<Utc> = self.time.into();
+        let quoted = |value: Option<&str>| escape_clf(value.unwrap_or("-"));
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {}",
+            self.remote_addr(),
+            time.format("%d/%b/%Y:%H:%M:%S +0000"),
+            escape_clf(&self.method),
+            escape_clf(&self.uri),
+            self.version,
+            self.status.as_u16(),
+            match self.bytes_sent {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            },
+            quoted(self.referer.as_deref()),
+            quoted(self.user_agent.as_deref()),
+            self.started.elapsed().as_micros(),
+        )
+    }
+
+    fn to_json(&self) -> String {
+        let time: DateTime<Utc> = self.time.into();
+        json!({
+            "time": time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            "remote_addr": self.remote_addr.map(|addr| addr.ip().to_string()),
+            "method": self.method,
+            "path": self.uri,
+            "protocol": format!("{:?}", self.version),
+            "status": self.status.as_u16(),
+            "bytes_sent": self.bytes_sent,
+            "duration_ms": self.started.elapsed().as_secs_f64() * 1000.0,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "request_id": self.request_id,
+        })
+        .to_string()
+    }
+}
+
+/// Escapes quotes and backslashes, and any control characters, in a field of a Combined Log Format
+/// line so that it can't break up the line.
+fn escape_clf(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A response body that counts the bytes that are sent, and writes the access log line once it's
+/// dropped. That happens once the body has been sent, or once the client has gone away, so that the
+/// line holds the actual number of bytes sent even for streaming responses.
+pub struct AccessLogBody {
+    body: Body,
+    log: Option<(Arc<AccessLog>, Entry)>,
+}
+
+impl AccessLogBody {
+    pub fn new(body: Body, log: Option<(Arc<AccessLog>, Entry)>) -> Self {
+        AccessLogBody { body, log }
+    }
+}
+
+impl HttpBody for AccessLogBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_data(cx);
+        if let (Poll::Ready(Some(Ok(data))), Some((_, entry))) = (&poll, &mut self.log) {
+            entry.bytes_sent += data.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for AccessLogBody {
+    fn drop(&mut self) {
+        if let Some((access_log, entry)) = &self.log {
+            access_log.write(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_clf_fields() {
+        assert_eq!(escape_clf("curl/7.79.1"), "curl/7.79.1");
+        assert_eq!(escape_clf("a \"b\" \\c"), "a \\\"b\\\" \\\\c");
+        assert_eq!(escape_clf("a\nb"), "a\\x0ab");
+    }
+}
@@ -0,0 +1,154 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+/// Machine-readable codes for the errors that requests can be rejected with, so that clients can
+/// handle them without matching on the error messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The URL query string has invalid parameters, or parameters that can't be used together.
+    InvalidQuery,
+    /// The request body, or a remote CSV, is larger than the maximum upload size.
+    PayloadTooLarge,
+    /// The request body isn't multipart/form-data or one of the accepted raw content types.
+    UnsupportedMediaType,
+    /// The multipart/form-data content type doesn't have a boundary.
+    MissingBoundary,
+    /// The multipart/form-data body doesn't have a field with the uploaded file.
+    MissingFileField,
+    /// The multipart/form-data body couldn't be read, e.g. because it's malformed or truncated.
+    MalformedMultipart,
+    /// The X-Content-SHA256 header isn't a hex encoded SHA-256 digest.
+    InvalidChecksumHeader,
+    /// The uploaded file doesn't match the X-Content-SHA256 header.
+    ChecksumMismatch,
+    /// The uploaded file couldn't be read in full.
+    UploadFailed,
+    /// The uploaded file isn't valid base64, with `field-encoding=base64`.
+    InvalidBase64,
+    /// The uploaded zip archive is invalid, or doesn't contain a CSV.
+    InvalidZip,
+    /// The uploaded file doesn't contain any bytes, with `strict-empty=true`.
+    EmptyUpload,
+    /// The uploaded CSV has a header row but no records, with `require-rows=true`.
+    NoDataRows,
+    /// The conversion failed before anything was sent, with the `buffered`, `schema-only` or
+    /// `require-rows` option, or to Parquet.
+    ConversionFailed,
+    /// The converted Parquet file is larger than the maximum Parquet file size.
+    ParquetTooLarge,
+    /// The `source-url` isn't a valid http or https URL.
+    InvalidSourceUrl,
+    /// The `source-url` isn't on one of the allowed hosts.
+    SourceHostNotAllowed,
+    /// The `source-url` couldn't be fetched, or didn't respond with 200 OK.
+    SourceUnavailable,
+    /// The `destination` isn't an `s3://bucket/key` URL, or S3 destinations aren't enabled.
+    InvalidDestination,
+    /// The converted output couldn't be uploaded to the `destination`.
+    DestinationFailed,
+    /// There's no route for the request's path.
+    NotFound,
+    /// There's a route for the request's path, but not for its method.
+    MethodNotAllowed,
+    /// The Accept header doesn't accept any of the output formats, and there's no `output` query param.
+    NotAcceptable,
+    /// The request took longer than the request timeout.
+    RequestTimeout,
+    /// The Range header of a buffered conversion is outside of the converted output.
+    RangeNotSatisfiable,
+    /// The limit on concurrent conversions was reached.
+    TooManyConversions,
+    /// The client reached its limit on requests or uploaded bytes per minute.
+    RateLimited,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidQuery
+            | ErrorCode::MissingBoundary
+            | ErrorCode::MissingFileField
+            | ErrorCode::MalformedMultipart
+            | ErrorCode::InvalidChecksumHeader
+            | ErrorCode::UploadFailed
+            | ErrorCode::InvalidBase64
+            | ErrorCode::InvalidZip
+            | ErrorCode::EmptyUpload
+            | ErrorCode::ConversionFailed
+            | ErrorCode::InvalidSourceUrl
+            | ErrorCode::InvalidDestination => StatusCode::BAD_REQUEST,
+            ErrorCode::SourceHostNotAllowed => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::PayloadTooLarge | ErrorCode::ParquetTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            ErrorCode::ChecksumMismatch | ErrorCode::NoDataRows => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::SourceUnavailable | ErrorCode::DestinationFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::TooManyConversions => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// An error that a request is rejected with. It's responded with the status of its code, and a JSON
+/// body like `{"error":"missing required multipart file field","code":"MISSING_FILE_FIELD"}`.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    /// Human-readable description of the error. It's serialized as `error` rather than `message`,
+    /// since that's what error responses have always used.
+    #[serde(rename = "error")]
+    pub message: String,
+    pub code: ErrorCode,
+    /// The routes that the server does handle, as `METHOD /path`, for requests without a route.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub available: Vec<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            message: message.into(),
+            code,
+            available: Vec::new(),
+        }
+    }
+
+    pub fn with_available(self, available: Vec<String>) -> Self {
+        ApiError { available, ..self }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.code.status())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&self).expect("errors are serializable"),
+            ))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn responds_with_status_and_code() {
+        let res = ApiError::new(ErrorCode::ChecksumMismatch, "digests differ").into_response();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            r#"{"error":"digests differ","code":"CHECKSUM_MISMATCH"}"#
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::access_log::AccessLogFormat;
+use crate::LogFormat;
+use anyhow::{anyhow, Context, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Server settings read from the `--config` file. Settings have the same names as the command line
+/// flags, which take precedence over them, and the `defaults` table holds query params that are used
+/// for requests that don't give them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub unix_socket: Option<PathBuf>,
+    pub max_upload_bytes: Option<u64>,
+    pub max_parquet_bytes: Option<usize>,
+    pub allow_source_hosts: Option<Vec<String>>,
+    pub max_columns: Option<usize>,
+    pub max_cell_bytes: Option<usize>,
+    pub max_concurrent_conversions: Option<usize>,
+    pub max_requests_per_minute_per_ip: Option<u64>,
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    pub flush_bytes: Option<usize>,
+    pub buffer_threshold: Option<usize>,
+    pub max_chunk_bytes: Option<usize>,
+    pub file_field_name: Option<String>,
+    pub default_filename: Option<String>,
+    pub request_timeout_secs: Option<u64>,
+    pub cors_origins: Option<Vec<String>>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub log_format: Option<LogFormat>,
+    pub access_log_format: Option<AccessLogFormat>,
+    #[serde(default)]
+    defaults: IndexMap<String, Value>,
+}
+
+impl ConfigFile {
+    /// Reads the config file, as JSON if it has a `.json` extension and as TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let config = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&contents).map_err(anyhow::Error::from)
+        } else {
+            toml::from_str(&contents).map_err(anyhow::Error::from)
+        };
+        config.with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Returns the `defaults` as query params. Lists are joined with commas, like the column lists
+    /// that query params take.
+    pub fn query_defaults(&self) -> Result<Vec<(String, String)>> {
+        self.defaults
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), query_value(name, value)?)))
+            .collect()
+    }
+}
+
+fn query_value(name: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(values) => Ok(values
+            .iter()
+            .map(|value| query_value(name, value))
+            .collect::<Result<Vec<_>>>()?
+            .join(",")),
+        Value::Null | Value::Object(_) => Err(anyhow!(
+            "default for query param {:?} isn't a string, number, boolean or list",
+            name
+        )),
+    }
+}
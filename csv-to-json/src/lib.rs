@@ -0,0 +1,116 @@
+//! Streaming conversion of CSV to JSON.
+//!
+//! This is the core of the csv-to-json server, without any of the HTTP handling, so that it can be used
+//! to convert CSV from any stream of bytes. Input is read and output is produced incrementally, so that
+//! arbitrarily large files can be converted in constant memory.
+//!
+//! The conversion is customized with CsvParseOptions and OutputOptions, which are the same options that
+//! the server takes from the URL query string. Their defaults are the same as when no query params are
+//! given.
+//!
+//! ```
+//! use bytes::Bytes;
+//! use csv_to_json::{csv_stream_to_json, CsvParseOptions, OutputOptions};
+//! use futures::executor::block_on;
+//! use futures::{stream, TryStreamExt};
+//!
+//! let csv = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from("id,name\n1,alice\n2,bob\n"))]);
+//! let json = csv_stream_to_json(CsvParseOptions::default(), OutputOptions::default(), csv);
+//! let json: Vec<Bytes> = block_on(json.try_collect())?;
+//! assert_eq!(
+//!     String::from_utf8(json.concat())?,
+//!     r#"[{"id":"1","name":"alice"},{"id":"2","name":"bob"}]"#
+//! );
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::Either;
+use futures::{Stream, StreamExt};
+
+mod options;
+mod parse;
+mod serialize;
+
+pub use options::{
+    BigNumbers, ByteRange, CsvFormat, CsvParseOptions, Delimiter, DuplicateHeaders, EncodingErrors,
+    FieldType, InvalidDates, KeyCase, MissingColumns, OutputFormat, OutputOptions, RaggedRows,
+    TrimMode,
+};
+pub use parse::{
+    decode_csv_input, parse_csv_records, read_csv_schema, validate_csv_records, ColumnSchema,
+    ColumnType, CsvError, CsvSchema, ErrorPosition, JsonElement, JsonRecord, ValidationSummary,
+};
+pub use serialize::serialize_json_seq;
+
+/// Stream producer that converts a stream of CSV file contents to serialized JSON, in chunks of
+/// UTF-8-encoded bytes. This combines decode_csv_input, parse_csv_records and records_to_json.
+///
+/// Errors in the CSV end the stream with an error, unless the `envelope` output option is set, in
+/// which case the error is reported in the JSON instead.
+pub fn csv_stream_to_json<S, E>(
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    csv_file: S,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let csv_file = decode_csv_input(&csv_parse_options, csv_file);
+    let csv_records = parse_csv_records(csv_parse_options.clone(), csv_file);
+    records_to_json(&csv_parse_options, output_options, csv_records)
+}
+
+/// Stream producer that serializes records parsed with parse_csv_records to JSON, applying the parse
+/// options that affect the output: the records are cut off at the `limit`, and are keyed by the
+/// `key-column` if there is one.
+///
+/// This is useful when the records are inspected along the way, e.g. to count them:
+///
+/// ```
+/// use bytes::Bytes;
+/// use csv_to_json::{
+///     decode_csv_input, parse_csv_records, records_to_json, CsvParseOptions, OutputOptions,
+/// };
+/// use futures::executor::block_on;
+/// use futures::{stream, StreamExt, TryStreamExt};
+///
+/// let csv_parse_options = CsvParseOptions {
+///     key_column: Some("id".to_string()),
+///     ..CsvParseOptions::default()
+/// };
+/// let csv = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from("id,name\n1,alice\n2,bob\n"))]);
+/// let csv = decode_csv_input(&csv_parse_options, csv);
+/// let mut count = 0;
+/// let records = parse_csv_records(csv_parse_options.clone(), csv).inspect(|_| count += 1);
+/// let json = records_to_json(&csv_parse_options, OutputOptions::default(), records);
+/// let json: Vec<Bytes> = block_on(json.try_collect())?;
+/// assert_eq!(
+///     String::from_utf8(json.concat())?,
+///     r#"{"1":{"name":"alice"},"2":{"name":"bob"}}"#
+/// );
+/// assert_eq!(count, 2);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn records_to_json<S>(
+    csv_parse_options: &CsvParseOptions,
+    output_options: OutputOptions,
+    csv_records: S,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<JsonRecord, CsvError>>,
+{
+    let output_options = OutputOptions {
+        keyed: csv_parse_options.key_column.is_some(),
+        ..output_options
+    };
+    // Once the limit is reached the rest of the input is no longer read, and it's dropped along with the
+    // output stream once that finishes.
+    let csv_records = match csv_parse_options.limit {
+        Some(limit) => Either::Left(csv_records.take(limit)),
+        None => Either::Right(csv_records),
+    };
+    serialize_json_seq(output_options, csv_records)
+}
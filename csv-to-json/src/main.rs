@@ -1,393 +1,8806 @@
+use access_log::{AccessLog, AccessLogBody, AccessLogFormat};
 use anyhow::{anyhow, Context, Result};
+use api_error::{ApiError, ErrorCode};
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use async_stream::try_stream;
-use bytes::Bytes;
-use clap::Parser;
-use futures::{pin_mut, Stream, TryStreamExt};
-use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine as _;
+use bytes::{Bytes, BytesMut};
+use clap::{ArgEnum, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use config_file::ConfigFile;
+use csv_to_json::{
+    decode_csv_input, parse_csv_records, read_csv_schema, records_to_json, validate_csv_records,
+    BigNumbers, ColumnSchema, ColumnType, CsvError, CsvFormat, CsvParseOptions, CsvSchema,
+    FieldType, OutputFormat, OutputOptions,
+};
+use futures::future::Either;
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use hyper::client::HttpConnector;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ALLOW,
+    CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ORIGIN,
+    RANGE, RETRY_AFTER, TE, TRAILER, VARY,
+};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use multer::Multipart;
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use metrics::Metrics;
+use multer::{Constraints, Field, Multipart, SizeLimit};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rate_limit::RateLimiter;
+use s3::{S3Location, UploadError};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::str::FromStr;
-
-fn replace_file_extension(path: &str, extension: &str) -> Result<String> {
-    let mut path = PathBuf::from_str(path)?;
-    path.set_extension(extension);
-    let path = path
-        .to_str()
-        .ok_or_else(|| anyhow!("unable to convert path to String"))?;
-    Ok(path.to_string())
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use to_parquet::ParquetTooLarge;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{error, info, warn, Span};
+use tracing_futures::Instrument;
+use tracing_subscriber::EnvFilter;
+use url::Url;
+use uuid::Uuid;
+
+mod access_log;
+mod api_error;
+mod config_file;
+mod metrics;
+mod rate_limit;
+mod s3;
+mod to_csv;
+mod to_parquet;
+
+/// Replaces the extension of a (sanitized) file name, i.e. whatever follows its last dot, or adds the
+/// extension if it doesn't have one. A leading dot doesn't start an extension, so `.csv` becomes
+/// `.csv.json` rather than `.json`.
+fn replace_file_extension(file_name: &str, extension: &str) -> String {
+    let stem = match file_name.rfind('.') {
+        Some(dot) if dot > 0 => &file_name[..dot],
+        _ => file_name,
+    };
+    format!("{}.{}", stem, extension)
 }
 
-/// Stream producer that takes a request body and attempts to read the first multipart/form-data
-/// field that it encounters.
-async fn read_multipart(
-    body: Body,
-    boundary: String,
-) -> Option<(String, impl Stream<Item = multer::Result<Bytes>>)> {
-    // FIXME: possible DOS attack vector by attempting to read the whole multipart/form-data field. multer provides
-    //        a constraints API to help mitigate this risk: https://github.com/rousan/multer-rs.
-    let mut multipart = Multipart::new(body, boundary);
-    // KLUDGE: a result type with an error we can match on might be better here, that way we can differentiate
-    //         between "don't have a multiple field when we were expecting one" and "there was an error reading
-    //         the multipart field".
-    let mut field = multipart.next_field().await.ok()??;
-    // FIXME: possible attack vectors here by passing through the file name from the multipart POST request. may
-    //        want to do some sanitizing.
-    let file_name = field.file_name().unwrap_or("download.csv");
-    Some((
-        file_name.to_string(),
-        try_stream! {
-            while let Some(chunk) = field.chunk().await? {
-                yield chunk;
+/// Sanitizes a client-provided file name so that it's safe to echo back in a Content-Disposition
+/// header. Any directory components are removed, along with control and quote characters. Returns
+/// None if nothing is left of the file name after sanitizing.
+fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let file_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let file_name: String = file_name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\''))
+        .collect();
+    let file_name = file_name.trim();
+    if file_name.is_empty() || file_name.chars().all(|c| c == '.') {
+        None
+    } else {
+        Some(file_name.to_string())
+    }
+}
+
+/// Characters that must be percent-encoded in an RFC 5987 extended header parameter value, i.e.
+/// everything but the `attr-char` set.
+const RFC_5987_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Builds a Content-Disposition header value that has browsers download the response as a file
+/// with the given (sanitized) name. Since the plain `filename` parameter only supports ASCII, non-ASCII
+/// characters are replaced there and the full name is provided in the RFC 5987 encoded `filename*`
+/// parameter instead.
+fn content_disposition(file_name: &str) -> String {
+    let ascii_file_name: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    format!(
+        r#"attachment; filename="{}"; filename*=UTF-8''{}"#,
+        ascii_file_name,
+        utf8_percent_encode(file_name, RFC_5987_ENCODE_SET)
+    )
+}
+
+/// Splits the parameters of a header value like Content-Disposition into their lowercased names and
+/// values, e.g. `attachment; filename="a;b.csv"` into `("filename", "a;b.csv")`. Quoted values are
+/// unquoted, and may contain semicolons and backslash escaped characters.
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars();
+    // The first segment is the disposition type, which isn't a parameter.
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        let mut name = String::new();
+        let mut has_value = false;
+        for c in chars.by_ref() {
+            match c {
+                '=' => {
+                    has_value = true;
+                    break;
+                }
+                // A parameter without a value is skipped.
+                ';' => name.clear(),
+                c => name.push(c),
             }
-        },
-    ))
+        }
+        if !has_value {
+            return params;
+        }
+        let name = name.trim().to_ascii_lowercase();
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in chars.by_ref() {
+            match c {
+                _ if escaped => {
+                    value.push(c);
+                    escaped = false;
+                }
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                ';' if !quoted => break,
+                c => value.push(c),
+            }
+        }
+        params.push((name, value.trim().to_string()));
+    }
 }
 
-const fn default_delimiter() -> char {
-    ','
+/// Takes the (sanitized) file name of a raw upload from the request's Content-Disposition header, e.g.
+/// `attachment; filename="birds.csv"`, or its `X-Filename` header otherwise. The RFC 5987 encoded
+/// `filename*` parameter takes precedence over `filename`, since it can hold any UTF-8 name.
+fn raw_body_file_name(headers: &HeaderMap) -> Option<String> {
+    let from_content_disposition = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| std::str::from_utf8(value.as_bytes()).ok())
+        .and_then(|value| {
+            let params = header_params(value);
+            let extended = params.iter().find_map(|(name, value)| {
+                let (charset, encoded) = value.split_once('\'')?;
+                let (_language, encoded) = encoded.split_once('\'')?;
+                (name == "filename*" && charset.eq_ignore_ascii_case("utf-8"))
+                    .then(|| percent_decode_str(encoded).decode_utf8().ok())
+                    .flatten()
+                    .and_then(|file_name| sanitize_file_name(&file_name))
+            });
+            extended.or_else(|| {
+                params
+                    .iter()
+                    .find(|(name, _)| name == "filename")
+                    .and_then(|(_, file_name)| sanitize_file_name(file_name))
+            })
+        });
+    from_content_disposition.or_else(|| {
+        headers
+            .get(X_FILENAME)
+            .and_then(|value| std::str::from_utf8(value.as_bytes()).ok())
+            .and_then(sanitize_file_name)
+    })
 }
 
-const fn default_quote() -> char {
-    '"'
+/// Starts reading a multipart/form-data request body.
+fn open_multipart(body: Body, boundary: String, max_upload_bytes: u64) -> Multipart<'static> {
+    // Limit the size of the whole request body so that clients can't exhaust our resources by streaming an
+    // endless field. Exceeding the limit results in an error being yielded from the field stream.
+    let constraints = Constraints::new().size_limit(
+        SizeLimit::new()
+            .whole_stream(max_upload_bytes)
+            .per_field(max_upload_bytes),
+    );
+    Multipart::with_constraints(body, boundary, constraints)
 }
 
-/// Options taken from the URL query string to customize CSV parsing behavior.
-#[derive(Deserialize)]
+/// Stream producer that yields the contents of a multipart/form-data field.
+fn field_stream(mut field: Field<'static>) -> impl Stream<Item = multer::Result<Bytes>> {
+    try_stream! {
+        while let Some(chunk) = field.chunk().await? {
+            yield chunk;
+        }
+    }
+}
+
+/// Reads the next field of a multipart/form-data body that contains a file. That's any field if
+/// `field_name` is None, otherwise fields with other names are skipped.
+async fn next_file_field(
+    multipart: &mut Multipart<'static>,
+    field_name: Option<&str>,
+) -> multer::Result<Option<Field<'static>>> {
+    while let Some(field) = multipart.next_field().await? {
+        if field_name.is_none() || field.name() == field_name {
+            return Ok(Some(field));
+        }
+    }
+    Ok(None)
+}
+
+/// Stream producer that takes a multipart/form-data request body and attempts to read the first
+/// field that contains a file, see next_file_field. `Ok(None)` is returned if the body doesn't have
+/// such a field, while an error is returned if the body couldn't be read or is malformed.
+async fn read_multipart(
+    mut multipart: Multipart<'static>,
+    field_name: Option<&str>,
+    default_file_name: &str,
+) -> multer::Result<Option<(String, impl Stream<Item = multer::Result<Bytes>>)>> {
+    let field = match next_file_field(&mut multipart, field_name).await? {
+        Some(field) => field,
+        None => return Ok(None),
+    };
+    let file_name = field
+        .file_name()
+        .and_then(sanitize_file_name)
+        .unwrap_or_else(|| default_file_name.to_string());
+    Ok(Some((file_name, field_stream(field))))
+}
+
+/// The body of a request containing uploaded files.
+enum UploadBody {
+    /// The whole body is the contents of a single file.
+    Raw(Body),
+    /// A multipart/form-data body, where each field contains a file.
+    Multipart(Multipart<'static>),
+}
+
+/// Stream adapter that decompresses a gzip compressed stream of bytes on the fly. Concatenated gzip
+/// members are decompressed one after the other, like with `gunzip`.
+fn gunzip_stream<S, E>(input: S) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let reader = StreamReader::new(input.map_err(std::io::Error::other));
+    let mut decoder = GzipDecoder::new(reader);
+    decoder.multiple_members(true);
+    ReaderStream::new(decoder)
+}
+
+/// Decompresses a request body according to the request's Content-Encoding header. Only gzip is
+/// supported, and any other encoding is rejected with an error response.
+#[allow(clippy::result_large_err)]
+fn decode_request_body(req: Request<Body>) -> Result<Request<Body>, Response<Body>> {
+    let encoding = req
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match encoding.as_str() {
+        "" | "identity" => Ok(req),
+        "gzip" | "x-gzip" => Ok(req.map(|body| Body::wrap_stream(gunzip_stream(body)))),
+        encoding => Err(ApiError::new(
+            ErrorCode::UnsupportedMediaType,
+            format!(
+                "unsupported content encoding {:?}, upload the file uncompressed or gzip compressed",
+                encoding
+            ),
+        )
+        .into_response()),
+    }
+}
+
+/// Checks that a request is a valid upload, with either a multipart/form-data body or a raw body
+/// whose Content-Type is one of `raw_content_types`. An error response is returned otherwise. A gzip
+/// compressed body is decompressed as it's read, and the size limits apply to the decompressed body.
+#[allow(clippy::result_large_err)]
+fn open_upload(
+    config: &Config,
+    req: Request<Body>,
+    raw_content_types: &[&str],
+) -> Result<UploadBody, Response<Body>> {
+    // Reject requests that we know up front are too large. Requests without a Content-Length (e.g. chunked
+    // requests) are limited while they are being read instead, see read_multipart.
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|cl| cl.to_str().ok())
+        .and_then(|cl| cl.parse::<u64>().ok());
+    if matches!(content_length, Some(length) if length > config.max_upload_bytes) {
+        return Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "request body exceeds the maximum upload size of {} bytes",
+                config.max_upload_bytes
+            ),
+        )
+        .into_response());
+    }
+    let req = decode_request_body(req)?;
+
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if raw_content_types.contains(&media_type.as_str()) {
+        return Ok(UploadBody::Raw(req.into_body()));
+    }
+    if media_type != "multipart/form-data" {
+        let content_type = match media_type.as_str() {
+            "" => "a missing content type".to_string(),
+            media_type => format!("content type {:?}", media_type),
+        };
+        let message = match raw_content_types {
+            [] => format!(
+                "unsupported {}, upload the files as multipart/form-data",
+                content_type
+            ),
+            _ => format!(
+                "unsupported {}, upload the file as multipart/form-data or as a raw {} body",
+                content_type,
+                raw_content_types.join(" or ")
+            ),
+        };
+        return Err(ApiError::new(ErrorCode::UnsupportedMediaType, message).into_response());
+    }
+
+    let boundary = multer::parse_boundary(&content_type).ok();
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => {
+            return Err(ApiError::new(
+                ErrorCode::MissingBoundary,
+                "missing boundary in multipart content type",
+            )
+            .into_response())
+        }
+    };
+    Ok(UploadBody::Multipart(open_multipart(
+        req.into_body(),
+        boundary,
+        config.max_upload_bytes,
+    )))
+}
+
+fn missing_file_field_response(field_name: Option<&str>) -> Response<Body> {
+    let message = match field_name {
+        Some(field_name) => format!("missing required multipart file field {:?}", field_name),
+        None => "missing required multipart file field".to_string(),
+    };
+    ApiError::new(ErrorCode::MissingFileField, message).into_response()
+}
+
+/// Builds the response for a multipart body that couldn't be read, either because it exceeded the
+/// upload size limit or because it's malformed.
+fn multipart_error_response(error: multer::Error) -> Response<Body> {
+    fn exceeds_size_limit(error: &multer::Error) -> bool {
+        match error {
+            multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => {
+                true
+            }
+            // NOTE: multer reports the whole stream limit as a failure to read the stream.
+            multer::Error::StreamReadFailed(error) => error
+                .downcast_ref::<multer::Error>()
+                .is_some_and(exceeds_size_limit),
+            _ => false,
+        }
+    }
+    let code = if exceeds_size_limit(&error) {
+        ErrorCode::PayloadTooLarge
+    } else {
+        ErrorCode::MalformedMultipart
+    };
+    ApiError::new(code, format!("failed to read multipart body: {}", error)).into_response()
+}
+
+const X_CONTENT_SHA256: &str = "x-content-sha256";
+const X_FILENAME: &str = "x-filename";
+
+/// Parses the hex encoded SHA-256 digest that clients can send in the `X-Content-SHA256` header to
+/// have the upload verified before it's converted. If the header is invalid an error response is
+/// returned instead.
+#[allow(clippy::result_large_err)]
+fn parse_content_sha256(headers: &HeaderMap) -> Result<Option<String>, Response<Body>> {
+    let value = match headers.get(X_CONTENT_SHA256) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(digest) if digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Ok(Some(digest.to_ascii_lowercase()))
+        }
+        _ => Err(ApiError::new(
+            ErrorCode::InvalidChecksumHeader,
+            "X-Content-SHA256 must be a hex encoded SHA-256 digest",
+        )
+        .into_response()),
+    }
+}
+
+/// How the contents of uploaded files are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-struct CsvParseOptions {
-    #[serde(default = "default_delimiter")]
-    delimiter: char,
-    #[serde(default = "default_quote")]
-    quote: char,
-}
-
-/// Representation of a single record or line in a CSV. Fields are named according to the headers
-/// in the original CSV.
-#[derive(Debug, Deserialize, Serialize)]
-struct CsvRecord(
-    // NOTE: Using a BTreeMap to keep the ordering of fields the same order as in the CSV. This
-    //       makes testing a lot easier since the output is predictable.
-    BTreeMap<String, String>,
+enum FieldEncoding {
+    /// The contents are the file itself.
+    #[default]
+    Binary,
+    /// The contents are the file encoded as base64, optionally wrapped over several lines.
+    Base64,
+}
+
+/// Base64 engine for uploads, which accepts the standard alphabet with or without padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
 );
 
-// Stream producer that takes a stream of input bytes and attempts to deserialize them as CsvRecords.
-// This assumes that the input stream represents UTF-8 encoded string data, and will produce errors
-// if input data is not properly UTF-8 encoded.
-fn parse_csv_records<S, B>(
-    options: CsvParseOptions,
-    input: S,
-) -> impl Stream<Item = csv_async::Result<CsvRecord>>
+/// Stream adapter that decodes a stream of base64 text, ignoring whitespace. Only whole groups of 4
+/// characters are decoded from each chunk, and the rest is kept until the next chunk, so the output
+/// keeps streaming.
+fn decode_base64_stream<S>(input: S) -> impl Stream<Item = Result<Bytes>>
 where
-    S: Stream<Item = std::io::Result<B>> + Send,
-    B: AsRef<[u8]> + Send,
+    S: Stream<Item = Result<Bytes>>,
 {
-    let CsvParseOptions { delimiter, quote } = options;
     try_stream! {
         pin_mut!(input);
-        let deserializer = csv_async::AsyncReaderBuilder::new()
-            .delimiter(delimiter as u8)
-            .quote(quote as u8)
-            .flexible(true)
-            .create_deserializer(input.into_async_read());
-        let records = deserializer.into_deserialize::<CsvRecord>();
-        for await record in records {
-            yield record?;
+        let mut pending = Vec::new();
+        while let Some(chunk) = input.next().await {
+            pending.extend(chunk?.iter().filter(|byte| !byte.is_ascii_whitespace()));
+            let whole_groups = pending.len() / 4 * 4;
+            if whole_groups > 0 {
+                let decoded = BASE64.decode(&pending[..whole_groups]).context("invalid base64 in upload")?;
+                pending.drain(..whole_groups);
+                yield Bytes::from(decoded);
+            }
+        }
+        if !pending.is_empty() {
+            yield Bytes::from(BASE64.decode(&pending).context("invalid base64 in upload")?);
+        }
+    }
+}
+
+/// Decodes a base64 encoded upload. The start of the upload is decoded before responding, so that an
+/// upload that isn't base64 at all can be rejected with an error response. Invalid base64 later on is
+/// an error in the returned stream.
+#[allow(clippy::result_large_err)]
+async fn decode_base64_upload<S>(
+    upload: S,
+) -> Result<impl Stream<Item = Result<Bytes>>, Response<Body>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let mut decoded = Box::pin(decode_base64_stream(upload).peekable());
+    if let Some(Err(error)) = decoded.as_mut().peek().await {
+        // Errors reading the upload are left to be reported like they would be without base64.
+        if error.downcast_ref::<base64::DecodeError>().is_some() {
+            return Err(
+                ApiError::new(ErrorCode::InvalidBase64, format!("{:#}", error)).into_response(),
+            );
+        }
+    }
+    Ok(decoded)
+}
+
+/// Reads a whole upload and checks that its SHA-256 digest is `expected_digest`, returning its
+/// contents if so. Otherwise an error response is returned, with status 422 if the digest doesn't
+/// match.
+// NOTE: the digest is only known once the upload has been read in full, and by then a streamed
+//       response would already have started, so the upload is buffered (up to --max-upload-bytes)
+//       instead of being converted as it's received.
+async fn verify_upload(
+    upload: impl Stream<Item = Result<Bytes>>,
+    expected_digest: &str,
+) -> Result<Bytes, Response<Body>> {
+    let contents = collect_bytes(upload).await.map_err(|error| {
+        ApiError::new(ErrorCode::UploadFailed, format!("{:#}", error)).into_response()
+    })?;
+    let digest = format!("{:x}", Sha256::digest(&contents));
+    if digest != expected_digest {
+        return Err(ApiError::new(
+            ErrorCode::ChecksumMismatch,
+            format!(
+                "upload has SHA-256 digest {}, but X-Content-SHA256 is {}",
+                digest, expected_digest
+            ),
+        )
+        .into_response());
+    }
+    Ok(contents)
+}
+
+/// Magic bytes that zip archives start with.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Extracts the first `.csv` entry of a zip archive, returning its name and decompressed contents,
+/// which may be at most `max_bytes` long.
+fn extract_zipped_csv(archive: Bytes, max_bytes: u64) -> Result<(String, Bytes), ApiError> {
+    use std::io::Read;
+
+    let invalid_zip = |error: zip::result::ZipError| {
+        ApiError::new(
+            ErrorCode::InvalidZip,
+            format!("invalid zip archive: {}", error),
+        )
+    };
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).map_err(invalid_zip)?;
+    let index = (0..archive.len())
+        .find(|&index| {
+            archive.by_index_raw(index).is_ok_and(|entry| {
+                entry.is_file() && entry.name().to_ascii_lowercase().ends_with(".csv")
+            })
+        })
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidZip, "zip archive has no .csv entry"))?;
+    let entry = archive.by_index(index).map_err(invalid_zip)?;
+    let name = entry.name().to_string();
+    let mut contents = Vec::new();
+    entry
+        .take(max_bytes + 1)
+        .read_to_end(&mut contents)
+        .map_err(|error| {
+            ApiError::new(
+                ErrorCode::InvalidZip,
+                format!("failed to extract {} from zip archive: {}", name, error),
+            )
+        })?;
+    if contents.len() as u64 > max_bytes {
+        return Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "{} in zip archive exceeds the maximum upload size of {} bytes",
+                name, max_bytes
+            ),
+        ));
+    }
+    Ok((name, Bytes::from(contents)))
+}
+
+/// Unzips an upload if it's a zip archive, going by its file name or its first bytes, returning the
+/// name and contents of the first CSV in it instead. Other uploads are returned as they are.
+// NOTE: zip entries are located with the archive's central directory at its very end, so the whole
+//       archive is read into memory (up to --max-upload-bytes) before its CSV can be extracted. The
+//       extracted CSV is held in memory as well, and is limited to the same size.
+async fn unzip_upload<S>(
+    config: &Config,
+    file_name: String,
+    upload: S,
+) -> Result<(String, impl Stream<Item = Result<Bytes>>), Response<Body>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let mut upload = Box::pin(upload.peekable());
+    let is_zip = file_name.to_ascii_lowercase().ends_with(".zip")
+        || matches!(upload.as_mut().peek().await, Some(Ok(chunk)) if chunk.starts_with(ZIP_MAGIC));
+    if !is_zip {
+        return Ok((file_name, Either::Left(upload)));
+    }
+    let archive = collect_bytes(upload).await.map_err(|error| {
+        ApiError::new(ErrorCode::UploadFailed, format!("{:#}", error)).into_response()
+    })?;
+    let max_bytes = config.max_upload_bytes;
+    let (name, contents) =
+        tokio::task::spawn_blocking(move || extract_zipped_csv(archive, max_bytes))
+            .await
+            .expect("zip extraction doesn't panic")
+            .map_err(ApiError::into_response)?;
+    let file_name = sanitize_file_name(&name).unwrap_or(file_name);
+    Ok((
+        file_name,
+        Either::Right(futures::stream::once(async { Ok(contents) })),
+    ))
+}
+
+/// Reads the start of an upload to check that it isn't empty, returning it with what was read put
+/// back, or an error response if it doesn't contain a single byte. Errors reading the upload are left
+/// in the stream, to be reported like they would be otherwise.
+async fn reject_empty_upload<S>(
+    upload: S,
+) -> Result<impl Stream<Item = Result<Bytes>>, Response<Body>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let mut upload = Box::pin(upload);
+    let mut start = Vec::new();
+    loop {
+        match upload.next().await {
+            Some(Ok(chunk)) if chunk.is_empty() => continue,
+            Some(chunk) => {
+                start.push(chunk);
+                break;
+            }
+            None => {
+                return Err(ApiError::new(ErrorCode::EmptyUpload, "empty upload").into_response())
+            }
+        }
+    }
+    Ok(futures::stream::iter(start).chain(upload))
+}
+
+/// Reads the start of an upload up to its first record to check that it has any, returning it with
+/// what was read put back, or an error response if it only has a header row (or nothing at all).
+/// Since the response hasn't started yet, an error before the first record is responded with too.
+async fn reject_headers_only<S>(
+    csv_parse_options: &CsvParseOptions,
+    upload: S,
+) -> Result<impl Stream<Item = Result<Bytes>>, Response<Body>>
+where
+    S: Stream<Item = Result<Bytes>> + Send,
+{
+    let mut upload = Box::pin(upload);
+    let mut start = Vec::new();
+    let first_record = {
+        let sample = upload
+            .as_mut()
+            .inspect_ok(|chunk: &Bytes| start.push(chunk.clone()));
+        let records = parse_csv_records(
+            csv_parse_options.clone(),
+            decode_csv_input(csv_parse_options, sample),
+        );
+        pin_mut!(records);
+        records.next().await
+    };
+    match first_record {
+        Some(Ok(_)) => Ok(futures::stream::iter(start.into_iter().map(Ok)).chain(upload)),
+        Some(Err(error)) => Err(ApiError::new(
+            ErrorCode::ConversionFailed,
+            format!("failed to read the CSV: {}", error),
+        )
+        .into_response()),
+        None => Err(ApiError::new(ErrorCode::NoDataRows, "no data rows").into_response()),
+    }
+}
+
+/// Reads the uploaded file from a request, returning its file name and a stream of its contents. The
+/// file is either the first field of a multipart/form-data request, or the whole request body if its
+/// Content-Type is one of `raw_content_types`. If the request doesn't contain an uploaded file, an
+/// error response is returned instead. When the request has an `X-Content-SHA256` header the file is
+/// read in full and verified before it's returned. A raw body is named after the file name in the
+/// request's headers if there is one, see raw_body_file_name.
+async fn read_upload(
+    config: &Config,
+    req: Request<Body>,
+    raw_content_types: &[&str],
+) -> Result<(String, impl Stream<Item = Result<Bytes>>), Response<Body>> {
+    let expected_digest = parse_content_sha256(req.headers())?;
+    let raw_file_name = raw_body_file_name(req.headers());
+    let (file_name, file) = match open_upload(config, req, raw_content_types)? {
+        UploadBody::Raw(body) => {
+            // Raw bodies without a file name in their headers are named the same as when a multipart file
+            // field doesn't have one.
+            let file = limit_stream_size(body, config.max_upload_bytes);
+            (
+                raw_file_name.unwrap_or_else(|| config.default_file_name.clone()),
+                Either::Left(idle_timeout_stream(file, config.request_timeout)),
+            )
+        }
+        UploadBody::Multipart(multipart) => {
+            match read_multipart(
+                multipart,
+                config.file_field_name.as_deref(),
+                &config.default_file_name,
+            )
+            .await
+            {
+                Ok(Some((file_name, file))) => (
+                    file_name,
+                    Either::Right(idle_timeout_stream(file, config.request_timeout)),
+                ),
+                Ok(None) => {
+                    return Err(missing_file_field_response(
+                        config.file_field_name.as_deref(),
+                    ))
+                }
+                Err(error) => return Err(multipart_error_response(error)),
+            }
+        }
+    };
+    match expected_digest {
+        None => Ok((file_name, Either::Left(file))),
+        Some(expected_digest) => {
+            let contents = verify_upload(file, &expected_digest).await?;
+            Ok((
+                file_name,
+                Either::Right(futures::stream::once(async { Ok(contents) })),
+            ))
+        }
+    }
+}
+
+/// Parses the options struct `T` from the request's URL query string. If the query string is invalid
+/// an error response is returned instead.
+#[allow(clippy::result_large_err)]
+fn parse_query<T: DeserializeOwned>(req: &Request<Body>) -> Result<T, Response<Body>> {
+    serde_urlencoded::from_str::<T>(req.uri().query().unwrap_or_default())
+        .map_err(invalid_query_response)
+}
+
+fn invalid_query_response(error: impl std::fmt::Display) -> Response<Body> {
+    ApiError::new(
+        ErrorCode::InvalidQuery,
+        format!("invalid query parameters: {}", error),
+    )
+    .into_response()
+}
+
+/// Parses the CsvParseOptions from the request's URL query string, also rejecting combinations of
+/// options that can't be used together. The limits on records are taken from the server config.
+#[allow(clippy::result_large_err)]
+fn parse_csv_parse_options(
+    config: &Config,
+    req: &Request<Body>,
+) -> Result<CsvParseOptions, Response<Body>> {
+    let options = parse_query::<CsvParseOptions>(req)?;
+    if options.include.is_some() && options.exclude.is_some() {
+        return Err(invalid_query_response(
+            "include and exclude can't be used together",
+        ));
+    }
+    if options.raw_rows && options.key_column.is_some() {
+        return Err(invalid_query_response(
+            "raw-rows and key-column can't be used together",
+        ));
+    }
+    if options.raw_rows && options.add_row_number.is_some() {
+        return Err(invalid_query_response(
+            "raw-rows and add-row-number can't be used together",
+        ));
+    }
+    // NOTE: lines are split on the terminator byte of the input as it was uploaded for a byte range.
+    if options.byte_range.is_some()
+        && options
+            .encoding
+            .is_some_and(|encoding| !encoding.is_ascii_compatible())
+    {
+        return Err(invalid_query_response(
+            "byte-range can't be used with encodings that aren't ASCII-compatible",
+        ));
+    }
+    match (options.format, &options.widths) {
+        (CsvFormat::Fixed, None) => {
+            return Err(invalid_query_response(
+                "format=fixed needs the widths of the fields",
+            ))
+        }
+        (CsvFormat::Fixed, Some(_)) if options.delimiter.is_some() => {
+            return Err(invalid_query_response(
+                "delimiter can't be used with format=fixed",
+            ))
+        }
+        (format, Some(_)) if format != CsvFormat::Fixed => {
+            return Err(invalid_query_response(
+                "widths can only be used with format=fixed",
+            ))
+        }
+        _ => {}
+    }
+    if options.headers.is_some() && options.has_headers {
+        return Err(invalid_query_response(
+            "headers can only be used with has-headers=false",
+        ));
+    }
+    Ok(CsvParseOptions {
+        max_columns: config.max_columns,
+        max_cell_bytes: config.max_cell_bytes,
+        ..options
+    })
+}
+
+/// Checks that the CSV parse options can be used with the output format.
+fn check_output_format(
+    csv_parse_options: &CsvParseOptions,
+    output_options: &OutputOptions,
+) -> Result<(), &'static str> {
+    if csv_parse_options.key_column.is_some() && output_options.output != OutputFormat::Json {
+        return Err("key-column can only be used with JSON output");
+    }
+    if output_options.unwrap_single {
+        if output_options.output != OutputFormat::Json {
+            return Err("unwrap-single can only be used with JSON output");
+        }
+        if output_options.envelope || csv_parse_options.key_column.is_some() {
+            return Err("unwrap-single can't be used with envelope or key-column");
+        }
+    }
+    if output_options.meta {
+        if output_options.output != OutputFormat::Json {
+            return Err("meta can only be used with JSON output");
+        }
+        if output_options.envelope || output_options.unwrap_single {
+            return Err("meta can't be used with envelope or unwrap-single");
+        }
+    }
+    if output_options.bom && output_options.output == OutputFormat::Msgpack {
+        return Err("bom can't be used with MessagePack output");
+    }
+    // NOTE: MessagePack integers are at most 64 bits, so wider integers would end up as binary data.
+    if csv_parse_options.big_number == BigNumbers::I128
+        && output_options.output == OutputFormat::Msgpack
+    {
+        return Err("big-number=i128 can't be used with MessagePack output");
+    }
+    Ok(())
+}
+
+/// Parses the OutputOptions from the request's URL query string, also rejecting options that can't
+/// be used with the CsvParseOptions. The chunk sizes and buffer threshold are taken from the server config.
+#[allow(clippy::result_large_err)]
+fn parse_output_options(
+    config: &Config,
+    csv_parse_options: &CsvParseOptions,
+    req: &Request<Body>,
+) -> Result<OutputOptions, Response<Body>> {
+    let mut options = parse_query::<OutputOptions>(req)?;
+    // The Accept header only chooses the format when the query string doesn't.
+    if req.headers().contains_key(ACCEPT) && !has_query_param(req, "output") {
+        options.output = accepted_output_format(req.headers()).ok_or_else(|| {
+            let media_types: Vec<_> = OutputFormat::ALL
+                .iter()
+                .map(|format| format.media_types()[0])
+                .collect();
+            ApiError::new(
+                ErrorCode::NotAcceptable,
+                format!(
+                    "the Accept header doesn't accept any of the output formats: {}",
+                    media_types.join(", ")
+                ),
+            )
+            .into_response()
+        })?;
+    }
+    check_output_format(csv_parse_options, &options).map_err(invalid_query_response)?;
+    Ok(OutputOptions {
+        flush_bytes: config.flush_bytes,
+        buffer_threshold: config.buffer_threshold,
+        max_chunk_bytes: config.max_chunk_bytes,
+        ..options
+    })
+}
+
+/// Returns true if the request's query string gives the query param, whether or not it's valid.
+fn has_query_param(req: &Request<Body>, name: &str) -> bool {
+    let query = req.uri().query().unwrap_or_default();
+    url::form_urlencoded::parse(query.as_bytes()).any(|(param, _)| param == name)
+}
+
+/// Returns the items listed in a header like Accept or Accept-Encoding, along with their quality
+/// values, which default to 1.
+fn quality_values(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = (&str, f32)> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name, quality)
+        })
+}
+
+/// Returns true if the client accepts a gzip content encoding according to its Accept-Encoding
+/// header, i.e. it lists `gzip` without a quality value of zero.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    quality_values(headers, ACCEPT_ENCODING)
+        .any(|(coding, quality)| coding.eq_ignore_ascii_case("gzip") && quality > 0.0)
+}
+
+/// Returns the output format that the client prefers according to its Accept header, or None if it
+/// doesn't accept any of them. A media type gets the quality value of the most specific media range
+/// that matches it, so e.g. `*/*` accepts every format that isn't listed with a quality value of zero.
+/// Formats with the same quality value are preferred in the order of OutputFormat::ALL, so that JSON
+/// is used for clients like browsers that accept anything.
+fn accepted_output_format(headers: &HeaderMap) -> Option<OutputFormat> {
+    let ranges: Vec<_> = quality_values(headers, ACCEPT).collect();
+    let media_type_quality = |media_type: &str| {
+        let (type_, _) = media_type.split_once('/').unwrap_or_default();
+        ranges
+            .iter()
+            .filter_map(|&(range, quality)| {
+                let specificity = if range.eq_ignore_ascii_case(media_type) {
+                    2
+                } else if range
+                    .strip_suffix("/*")
+                    .is_some_and(|range_type| range_type.eq_ignore_ascii_case(type_))
+                {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, quality))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+    let mut preferred = None;
+    let mut preferred_quality = 0.0;
+    for format in OutputFormat::ALL {
+        let quality = format
+            .media_types()
+            .iter()
+            .map(|media_type| media_type_quality(media_type))
+            .fold(0.0, f32::max);
+        if quality > preferred_quality {
+            preferred = Some(format);
+            preferred_quality = quality;
+        }
+    }
+    preferred
+}
+
+/// Returns true if the client accepts trailers after the response body according to its TE header.
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// A single range of bytes requested with a Range header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ByteRange {
+    /// `bytes=start-end` or `bytes=start-`, where the end is inclusive.
+    FromTo(usize, Option<usize>),
+    /// `bytes=-length`, for the last `length` bytes.
+    Suffix(usize),
+}
+
+impl ByteRange {
+    /// Returns the inclusive start and end of the range within `len` bytes, or None if the range isn't
+    /// satisfiable.
+    fn resolve(self, len: usize) -> Option<(usize, usize)> {
+        match self {
+            ByteRange::FromTo(start, _) if start >= len => None,
+            ByteRange::FromTo(start, end) => {
+                Some((start, end.map_or(len - 1, |end| end.min(len - 1))))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if len == 0 => None,
+            ByteRange::Suffix(length) => Some((len.saturating_sub(length), len - 1)),
+        }
+    }
+}
+
+/// Parses the request's Range header, if it requests a single range of bytes. Any other Range header,
+/// including one with multiple ranges, is ignored, so that the whole output is responded with.
+fn parse_range(headers: &HeaderMap) -> Option<ByteRange> {
+    let range = headers
+        .get(RANGE)?
+        .to_str()
+        .ok()?
+        .trim()
+        .strip_prefix("bytes=")?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        return end.parse().ok().map(ByteRange::Suffix);
+    }
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().ok().filter(|&end| end >= start)?),
+    };
+    Some(ByteRange::FromTo(start, end))
+}
+
+const X_RECORD_COUNT: &str = "x-record-count";
+const X_CONVERSION_STATUS: &str = "x-conversion-status";
+
+/// Progress of a single conversion, which is reported in the trailers of its response if the client
+/// accepts them.
+#[derive(Debug, Default)]
+struct ConversionProgress {
+    records: AtomicU64,
+    failed: AtomicBool,
+}
+
+impl ConversionProgress {
+    fn count_record(&self) {
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn fail(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+    }
+
+    fn trailers(&self) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert(
+            X_RECORD_COUNT,
+            HeaderValue::from(self.records.load(Ordering::Relaxed)),
+        );
+        let status = if self.failed.load(Ordering::Relaxed) {
+            "failure"
+        } else {
+            "success"
+        };
+        trailers.insert(X_CONVERSION_STATUS, HeaderValue::from_static(status));
+        trailers
+    }
+}
+
+/// Resolves once the body that the sender sends to has been dropped, e.g. because the client went away.
+async fn body_closed(sender: &mut hyper::body::Sender) {
+    futures::future::poll_fn(|cx| match sender.poll_ready(cx) {
+        std::task::Poll::Ready(Err(_)) => std::task::Poll::Ready(()),
+        _ => std::task::Poll::Pending,
+    })
+    .await
+}
+
+/// Builds a response body that streams the chunks, followed by trailers that report the progress of
+/// the conversion. An error ends the body early rather than aborting the response, since the
+/// trailers report the failure.
+// NOTE: hyper only sends trailers over HTTP/2, over HTTP/1.1 they're silently dropped.
+fn body_with_trailers<S, E>(chunks: S, progress: Arc<ConversionProgress>) -> Body
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send,
+{
+    let (mut sender, body) = Body::channel();
+    // The chunks are produced in a task of their own, which isn't tied to the response like a wrapped
+    // stream would be. So it has to stop by itself once the body is dropped, even while it's still
+    // producing the next chunk, since parsing can go on for a long time without any output.
+    tokio::spawn(async move {
+        pin_mut!(chunks);
+        loop {
+            let chunk = tokio::select! {
+                _ = body_closed(&mut sender) => return,
+                chunk = chunks.next() => chunk,
+            };
+            let Some(chunk) = chunk else { break };
+            match chunk {
+                Ok(chunk) => {
+                    // The client has gone away, so there's nobody to send the rest to.
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    progress.fail();
+                    break;
+                }
+            }
         }
+        let _ = sender.send_trailers(progress.trailers()).await;
+    });
+    body
+}
+
+/// Stream adapter that gzip compresses a stream of bytes on the fly. The compressor only holds on
+/// to as much data as it needs to compress efficiently, so the output keeps streaming.
+fn gzip_stream<S>(input: S) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let reader = StreamReader::new(input.map_err(std::io::Error::other));
+    ReaderStream::new(GzipEncoder::new(reader))
+}
+
+/// Stream producer that converts a stream of CSV file contents to serialized JSON, counting the bytes
+/// read and records converted in the metrics and the progress of the conversion.
+fn convert_csv_stream<S, E>(
+    metrics: Arc<Metrics>,
+    progress: Arc<ConversionProgress>,
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    csv_file: S,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let byte_metrics = metrics.clone();
+    let csv_file = csv_file.inspect_ok(move |chunk| byte_metrics.count_bytes_received(chunk.len()));
+    let csv_file = decode_csv_input(&csv_parse_options, csv_file);
+    let csv_records =
+        parse_csv_records(csv_parse_options.clone(), csv_file).inspect(move |record| {
+            if record.is_ok() {
+                metrics.count_record();
+                progress.count_record();
+            } else {
+                // Errors may be reported in the envelope and not end the response with an error, so the
+                // failure has to be recorded here.
+                progress.fail();
+            }
+        });
+    let json = records_to_json(&csv_parse_options, output_options, csv_records);
+    match output_options.max_chunk_bytes {
+        Some(max_chunk_bytes) => Either::Left(split_large_chunks(json, max_chunk_bytes)),
+        None => Either::Right(json),
     }
 }
 
-/// Stream producer that takes a stream of serde::Serialize values and serializes them to
-/// JSON array in a UTF-8-encoed, binary chunked format.
-fn serialize_json_seq<S, T, E>(values: S) -> impl Stream<Item = Result<Bytes>>
+/// Stream adapter that splits chunks that are larger than `max_chunk_bytes` into several chunks.
+/// Other tasks get to run in between them, so that sending a very large chunk doesn't hold up the
+/// rest of the server for as long.
+fn split_large_chunks<S>(input: S, max_chunk_bytes: usize) -> impl Stream<Item = Result<Bytes>>
 where
-    S: Stream<Item = Result<T, E>>,
-    T: Serialize,
-    E: std::error::Error + Send + Sync + 'static,
+    S: Stream<Item = Result<Bytes>>,
 {
+    let max_chunk_bytes = max_chunk_bytes.max(1);
     try_stream! {
-        // To give downstream consumers the most opportunity for optimization we'll have a single bytes buffer
-        // and periodically flush that buffer and yield it's contents to the stream. This is *probably* much
-        // better than yielding individual , and [ characters.
-        let mut buffer = Vec::with_capacity(1024);
+        pin_mut!(input);
+        while let Some(mut chunk) = input.try_next().await? {
+            while chunk.len() > max_chunk_bytes {
+                yield chunk.split_to(max_chunk_bytes);
+                tokio::task::yield_now().await;
+            }
+            yield chunk;
+        }
+    }
+}
 
-        buffer.push(b'[');
-        // The first value won't need a leading array element separator "," so we treat it specially.
-        pin_mut!(values);
-        if let Some(first_value) = values.try_next().await.context("failed to read from input stream")? {
-            serde_json::to_writer(&mut buffer, &first_value).context("failed to serialize value")?;
+/// Reads a stream of bytes into memory in full.
+async fn collect_bytes<S, E>(input: S) -> Result<Bytes, E>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let bytes = input
+        .try_fold(BytesMut::new(), |mut bytes, chunk| async move {
+            bytes.extend_from_slice(&chunk);
+            Ok(bytes)
+        })
+        .await?;
+    Ok(bytes.freeze())
+}
+
+/// The start of a stream of bytes that was read into memory, see read_up_to.
+enum BufferedBytes {
+    /// The stream ended within the threshold, so this is all of it.
+    Complete(Bytes),
+    /// The stream exceeded the threshold, so this is only what has been read so far.
+    Partial(Bytes),
+}
+
+/// Reads a stream of bytes into memory until it ends or more than the threshold has been read.
+async fn read_up_to<S, E>(input: &mut S, threshold: usize) -> Result<BufferedBytes, E>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = input.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > threshold {
+            return Ok(BufferedBytes::Partial(bytes.freeze()));
+        }
+    }
+    Ok(BufferedBytes::Complete(bytes.freeze()))
+}
+
+/// Builds a response that streams the converted JSON to the client as a download. With the
+/// `buffered` option the JSON is instead converted in full before responding, so that the response
+/// has a Content-Length, and errors can still be reported with an error status. With a buffer
+/// threshold the same goes for output up to the threshold, and only larger output is streamed.
+/// Output that's converted in full is responded with only the requested `range` of it, if any.
+// NOTE: the response stream is boxed since otherwise it's stored inline in this function's future, which
+//       makes the request handling futures big enough to overflow the stack in debug builds.
+async fn json_response(
+    metrics: Arc<Metrics>,
+    output_options: OutputOptions,
+    gzip: bool,
+    range: Option<ByteRange>,
+    trailers: Option<Arc<ConversionProgress>>,
+    download_file_name: &str,
+    response: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    // The response is streamed after the request has been handled, so the stream has to be explicitly
+    // instrumented for errors to be logged with the request's span.
+    let response = response
+        .inspect(move |result| match result {
+            Ok(chunk) => metrics.count_bytes_sent(chunk.len()),
+            Err(error) => {
+                metrics.count_conversion_error();
+                error!("error during CSV conversion: {:?}", error);
+            }
+        })
+        .instrument(Span::current());
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, output_options.output.content_type())
+        .header(CONTENT_DISPOSITION, content_disposition(download_file_name))
+        .header(VARY, ACCEPT)
+        .header(VARY, ACCEPT_ENCODING);
+    let builder = if gzip {
+        builder.header(CONTENT_ENCODING, "gzip")
+    } else {
+        builder
+    };
+    let threshold = if output_options.buffered {
+        Some(usize::MAX)
+    } else {
+        output_options.buffer_threshold
+    };
+    let mut response = response;
+    let start = match threshold {
+        Some(threshold) => match read_up_to(&mut response, threshold).await {
+            Ok(BufferedBytes::Complete(json)) => {
+                return buffered_response(builder, gzip, range, json).await
+            }
+            Ok(BufferedBytes::Partial(start)) => Some(start),
+            Err(error) => {
+                return Ok(
+                    ApiError::new(ErrorCode::ConversionFailed, format!("{:#}", error))
+                        .into_response(),
+                );
+            }
+        },
+        None => None,
+    };
+    // Output over the threshold is streamed after all, starting with what has already been read.
+    let response = futures::stream::iter(start.map(Ok)).chain(response);
+    let response = if gzip {
+        Either::Left(gzip_stream(response))
+    } else {
+        Either::Right(response.map_err(std::io::Error::other))
+    };
+    match trailers {
+        // NOTE: hyper currently strips the Trailer header from HTTP/2 responses as if it were a
+        //       connection header, so clients can't rely on it to announce the trailers.
+        Some(progress) => builder
+            .header(TRAILER, "X-Record-Count, X-Conversion-Status")
+            .body(body_with_trailers(response, progress)),
+        None => builder.body(Body::wrap_stream(response)),
+    }
+}
+
+/// Builds a response with JSON that was converted in full, so that it has a Content-Length. With a
+/// `range`, responds with `206 Partial Content` and only that range of the (compressed) JSON, so that
+/// an interrupted download can be resumed, or with `416 Range Not Satisfiable` if it's out of bounds.
+async fn buffered_response(
+    builder: hyper::http::response::Builder,
+    gzip: bool,
+    range: Option<ByteRange>,
+    json: Bytes,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let body = if gzip {
+        // NOTE: compressing bytes that are already in memory can only fail if we run out of memory.
+        collect_bytes(gzip_stream(futures::stream::once(async { Ok(json) })))
+            .await
+            .expect("failed to compress in-memory JSON")
+    } else {
+        json
+    };
+    let builder = builder.header(ACCEPT_RANGES, "bytes");
+    match range.map(|range| range.resolve(body.len())) {
+        None => builder
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body)),
+        Some(Some((start, end))) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, body.len()),
+            )
+            .header(CONTENT_LENGTH, end - start + 1)
+            .body(Body::from(body.slice(start..=end))),
+        Some(None) => {
+            let mut res = ApiError::new(
+                ErrorCode::RangeNotSatisfiable,
+                format!("range is outside of the {} bytes of output", body.len()),
+            )
+            .into_response();
+            res.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", body.len()))
+                    .expect("content range is a valid header value"),
+            );
+            Ok(res)
+        }
+    }
+}
+
+/// Builds the response for converting a stream of CSV file contents to JSON. With the `meta` option or
+/// a `data_key` the records are wrapped in an object, see wrap_records.
+#[allow(clippy::too_many_arguments)]
+async fn csv_conversion_response<S, E>(
+    metrics: &Arc<Metrics>,
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    gzip: bool,
+    range: Option<ByteRange>,
+    trailers: bool,
+    file_name: &str,
+    data_key: Option<&str>,
+    csv_file: S,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+{
+    // NOTE: when the header row is limited, it's read before responding so that a header row over the
+    //       limits gets an error response, rather than failing the response after it has started.
+    let csv_file = if csv_parse_options.has_headers
+        && (csv_parse_options.max_columns.is_some() || csv_parse_options.max_cell_bytes.is_some())
+    {
+        match read_schema_ahead(&csv_parse_options, 0, csv_file).await {
+            (Ok(_), csv_file) => Either::Left(csv_file),
+            (Err(error), _) => {
+                return Ok(ApiError::new(
+                    ErrorCode::ConversionFailed,
+                    format!("failed to read the CSV's header row: {}", error),
+                )
+                .into_response())
+            }
+        }
+    } else {
+        Either::Right(csv_file)
+    };
+    let output = output_options.output;
+    let download_file_name = replace_file_extension(file_name, output.file_extension());
+    let progress = Arc::new(ConversionProgress::default());
+    let response = if output_options.meta || data_key.is_some() {
+        let records = convert_csv_stream(
+            metrics.clone(),
+            progress.clone(),
+            csv_parse_options,
+            OutputOptions {
+                depth: output_options.depth + 1,
+                ..output_options
+            },
+            csv_file,
+        );
+        let meta = output_options.meta.then(|| {
+            let converted_at = chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::now())
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            serde_json::json!({
+                "filename": file_name,
+                "converted_at": converted_at,
+            })
+        });
+        wrap_records(
+            output_options,
+            meta,
+            data_key.unwrap_or(DEFAULT_DATA_KEY),
+            progress.clone(),
+            records,
+        )
+        .boxed()
+    } else {
+        convert_csv_stream(
+            metrics.clone(),
+            progress.clone(),
+            csv_parse_options,
+            output_options,
+            csv_file,
+        )
+        .boxed()
+    };
+    json_response(
+        metrics.clone(),
+        output_options,
+        gzip,
+        range,
+        trailers.then_some(progress),
+        &download_file_name,
+        response,
+    )
+    .await
+}
+
+/// Key of the converted records in the objects that they're wrapped in, unless a `data-key=` is given.
+const DEFAULT_DATA_KEY: &str = "data";
+
+/// Writes the value at the given depth of pretty-printed JSON, or compactly if the newline is empty.
+fn write_nested_json(buffer: &mut Vec<u8>, value: &serde_json::Value, newline: &[u8]) {
+    if newline.is_empty() {
+        serde_json::to_writer(buffer, value).expect("JSON values are serializable");
+    } else {
+        // NOTE: newlines within JSON strings are escaped, so every newline is between tokens and can
+        //       be indented to the depth of the value.
+        let pretty = serde_json::to_string_pretty(value).expect("JSON values are serializable");
+        buffer.extend_from_slice(
+            pretty
+                .replace(
+                    '\n',
+                    std::str::from_utf8(newline).expect("newline is ASCII"),
+                )
+                .as_bytes(),
+        );
+    }
+}
+
+/// Stream adapter that wraps the converted JSON records in an object under the data key, for
+/// `data-key=` and `meta=true`. With metadata about the conversion that comes first, and the number of
+/// records comes after them, since it's only known once they've all been converted:
+/// `{"meta":{"filename":...,"converted_at":...},"data":[...],"records":...}`. The records have to be
+/// converted one level deeper, and without the BOM, which is added to the object.
+fn wrap_records<S>(
+    output_options: OutputOptions,
+    meta: Option<serde_json::Value>,
+    data_key: &str,
+    progress: Arc<ConversionProgress>,
+    records: S,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let newline = output_options.newline(1);
+    let key_separator = output_options.key_separator();
+    let mut head = Vec::new();
+    if output_options.bom {
+        head.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    head.push(b'{');
+    head.extend_from_slice(&newline);
+    let counted = meta.is_some();
+    if let Some(meta) = meta {
+        head.extend_from_slice(br#""meta""#);
+        head.extend_from_slice(key_separator);
+        write_nested_json(&mut head, &meta, &newline);
+        head.push(b',');
+        head.extend_from_slice(&newline);
+    }
+    serde_json::to_writer(&mut head, data_key).expect("data key is serializable");
+    head.extend_from_slice(key_separator);
+    // The tail is only built once the records have all been converted, since that's when they've all
+    // been counted.
+    let tail = futures::stream::once(async move {
+        let mut tail = Vec::new();
+        if counted {
+            tail.push(b',');
+            tail.extend_from_slice(&newline);
+            tail.extend_from_slice(br#""records""#);
+            tail.extend_from_slice(key_separator);
+            let records = progress.records.load(Ordering::Relaxed);
+            tail.extend_from_slice(records.to_string().as_bytes());
+        }
+        tail.extend_from_slice(&output_options.newline(0));
+        tail.push(b'}');
+        Ok(Bytes::from(tail))
+    });
+    futures::stream::once(async { Ok(Bytes::from(head)) })
+        .chain(records)
+        .chain(tail)
+}
+
+/// Options taken from the URL query string to wrap the converted records in an object.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct WrapOptions {
+    /// Key of the records in the object, which wraps them in an object if they aren't already, e.g.
+    /// with `meta=true` or `with-schema=true`.
+    #[serde(default)]
+    data_key: Option<String>,
+}
+
+/// Maximum length in characters of a `data-key=`.
+const MAX_DATA_KEY_LENGTH: usize = 128;
+
+impl WrapOptions {
+    /// Checks that the data key is a reasonable name for a JSON key, and that the records can be
+    /// wrapped in an object with it.
+    fn check(&self, output_options: &OutputOptions, with_schema: bool) -> Result<(), &'static str> {
+        let Some(data_key) = &self.data_key else {
+            return Ok(());
+        };
+        if data_key.is_empty()
+            || data_key.chars().count() > MAX_DATA_KEY_LENGTH
+            || data_key.chars().any(char::is_control)
+        {
+            return Err("data-key must be 1 to 128 characters without control characters");
+        }
+        if output_options.output != OutputFormat::Json {
+            return Err("data-key can only be used with JSON output");
+        }
+        if output_options.envelope || output_options.unwrap_single {
+            return Err("data-key can't be used with envelope or unwrap-single");
+        }
+        if output_options.meta && (data_key == "meta" || data_key == "records") {
+            return Err("data-key can't be meta or records when used with meta");
+        }
+        if with_schema && data_key == "schema" {
+            return Err("data-key can't be schema when used with with-schema");
+        }
+        Ok(())
+    }
+}
+
+/// Options taken from the URL query string to customize the downloaded file.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DownloadOptions {
+    /// Name to download the converted file as, instead of the name of the converted file. Its extension
+    /// is replaced to match the output format.
+    #[serde(default)]
+    filename: Option<String>,
+}
+
+impl DownloadOptions {
+    /// Returns the sanitized `filename` option if given, falling back to the given file name otherwise,
+    /// or if nothing is left of the option after sanitizing.
+    fn file_name(&self, file_name: String) -> String {
+        self.filename
+            .as_deref()
+            .and_then(sanitize_file_name)
+            .unwrap_or(file_name)
+    }
+}
+
+/// Options taken from the URL query string to upload the converted output instead of responding with it.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DestinationOptions {
+    /// `s3://bucket/key` URL of the S3 object to upload the output to.
+    #[serde(default)]
+    destination: Option<String>,
+}
+
+impl DestinationOptions {
+    /// Returns the S3 client and the location to upload to, if there's a destination. If the destination
+    /// is invalid, or the server wasn't started with S3 credentials, an error response is returned instead.
+    #[allow(clippy::result_large_err)]
+    fn s3_destination<'a>(
+        &self,
+        config: &'a Config,
+    ) -> Result<Option<(&'a aws_sdk_s3::Client, S3Location)>, Response<Body>> {
+        let Some(destination) = &self.destination else {
+            return Ok(None);
+        };
+        let invalid_destination =
+            |message: String| ApiError::new(ErrorCode::InvalidDestination, message).into_response();
+        let location = destination
+            .parse()
+            .map_err(|error| invalid_destination(format!("invalid destination: {}", error)))?;
+        let client = config.s3_client.as_ref().ok_or_else(|| {
+            invalid_destination(
+                "S3 destinations aren't enabled, start the server with --s3-region".to_string(),
+            )
+        })?;
+        Ok(Some((client, location)))
+    }
+}
+
+/// Converts a CSV and uploads the output to S3 rather than responding with it, see s3::upload_stream.
+/// Responds with the location of the output once it has been uploaded in full.
+async fn destination_response<S, E>(
+    metrics: &Arc<Metrics>,
+    client: &aws_sdk_s3::Client,
+    location: &S3Location,
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    csv_file: S,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let output = convert_csv_stream(
+        metrics.clone(),
+        Arc::default(),
+        csv_parse_options,
+        output_options,
+        csv_file,
+    );
+    // NOTE: the upload is boxed since the S3 client's futures are big enough to overflow the stack in
+    //       debug builds when they're stored inline in the request handling futures.
+    let upload = Box::pin(s3::upload_stream(
+        client,
+        location,
+        output_options.output.content_type(),
+        output,
+    ));
+    match upload.await {
+        Ok(()) => {
+            info!(%location, "uploaded converted output");
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "location": location.to_string() }).to_string(),
+                ))
+        }
+        Err(UploadError::Output(error)) => {
+            metrics.count_conversion_error();
+            error!("error during CSV conversion: {:?}", error);
+            Ok(ApiError::new(ErrorCode::ConversionFailed, format!("{:#}", error)).into_response())
+        }
+        Err(UploadError::S3(error)) => {
+            error!("failed to upload converted output: {:#}", error);
+            Ok(ApiError::new(ErrorCode::DestinationFailed, format!("{:#}", error)).into_response())
+        }
+    }
+}
+
+/// Default number of records that the column types are inferred from with `schema-only=true`.
+const DEFAULT_SCHEMA_SAMPLE_ROWS: usize = 100;
+
+const fn default_schema_sample_rows() -> usize {
+    DEFAULT_SCHEMA_SAMPLE_ROWS
+}
+
+/// Options taken from the URL query string to respond with the schema of a CSV instead of converting it.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SchemaOptions {
+    /// Respond with the names and inferred types of the columns, rather than the converted records.
+    #[serde(default)]
+    schema_only: bool,
+    /// Number of records to infer the column types from.
+    #[serde(default = "default_schema_sample_rows")]
+    schema_sample_rows: usize,
+    /// Respond with an object of the records' JSON Schema and the converted records.
+    #[serde(default)]
+    with_schema: bool,
+}
+
+impl SchemaOptions {
+    /// Checks that `with-schema` is only used for a plain JSON array of records, since that's what the
+    /// schema describes.
+    fn check(
+        &self,
+        csv_parse_options: &CsvParseOptions,
+        output_options: &OutputOptions,
+    ) -> Result<(), &'static str> {
+        if !self.with_schema {
+            return Ok(());
+        }
+        if self.schema_only {
+            return Err("with-schema can't be used with schema-only");
+        }
+        if output_options.output != OutputFormat::Json
+            || output_options.envelope
+            || output_options.meta
+        {
+            return Err(
+                "with-schema can only be used with JSON output without an envelope or meta",
+            );
+        }
+        if csv_parse_options.key_column.is_some()
+            || csv_parse_options.raw_rows
+            || csv_parse_options.nest
+        {
+            return Err("with-schema can't be used with key-column, raw-rows or nest");
+        }
+        Ok(())
+    }
+}
+
+/// Responds with the schema of a CSV, which is read up to the end of the sampled records.
+async fn schema_response<S, E>(
+    metrics: &Arc<Metrics>,
+    csv_parse_options: CsvParseOptions,
+    sample_rows: usize,
+    csv_file: S,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let metrics = metrics.clone();
+    let csv_file = csv_file.inspect_ok(move |chunk| metrics.count_bytes_received(chunk.len()));
+    let csv_file = decode_csv_input(&csv_parse_options, csv_file);
+    match read_csv_schema(csv_parse_options, csv_file, sample_rows).await {
+        Ok(schema) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&schema).expect("schema is serializable"),
+            )),
+        Err(error) => Ok(ApiError::new(
+            ErrorCode::ConversionFailed,
+            format!("failed to read the CSV's schema: {}", error),
+        )
+        .into_response()),
+    }
+}
+
+/// Reads the schema of a CSV from its first `sample_rows` records before converting it, returning the
+/// schema along with the whole CSV. The input that was read for the schema is put back in front of
+/// the rest of it.
+async fn read_schema_ahead<S, E>(
+    csv_parse_options: &CsvParseOptions,
+    sample_rows: usize,
+    csv_file: S,
+) -> (
+    Result<CsvSchema, CsvError>,
+    impl Stream<Item = Result<Bytes, E>>,
+)
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut csv_file = Box::pin(csv_file);
+    let mut sampled = Vec::new();
+    let sample = csv_file
+        .as_mut()
+        .inspect_ok(|chunk: &Bytes| sampled.push(chunk.clone()));
+    let sample = decode_csv_input(csv_parse_options, sample);
+    let schema = read_csv_schema(csv_parse_options.clone(), sample, sample_rows).await;
+    let csv_file = futures::stream::iter(sampled.into_iter().map(Ok)).chain(csv_file);
+    (schema, csv_file)
+}
+
+/// Responds with the converted records along with their JSON Schema, as `{"schema":{...},"data":[...]}`,
+/// or with the records under the `data_key` if there is one. The schema has to come first, so it's
+/// inferred from the sampled records before the conversion starts, and the input that was read for it
+/// is kept to be converted again.
+#[allow(clippy::too_many_arguments)]
+async fn with_schema_response<S, E>(
+    metrics: &Arc<Metrics>,
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    sample_rows: usize,
+    gzip: bool,
+    range: Option<ByteRange>,
+    trailers: bool,
+    file_name: &str,
+    data_key: Option<&str>,
+    csv_file: S,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+{
+    let (schema, csv_file) = read_schema_ahead(&csv_parse_options, sample_rows, csv_file).await;
+    let schema = match schema {
+        Ok(schema) => schema.to_json_schema(&csv_parse_options),
+        Err(error) => {
+            return Ok(ApiError::new(
+                ErrorCode::ConversionFailed,
+                format!("failed to read the CSV's schema: {}", error),
+            )
+            .into_response())
+        }
+    };
+
+    let download_file_name = replace_file_extension(file_name, "json");
+    let progress = Arc::new(ConversionProgress::default());
+    // The records are nested one level deep in the object, so the BOM has to be added to the object.
+    let records = convert_csv_stream(
+        metrics.clone(),
+        progress.clone(),
+        csv_parse_options,
+        OutputOptions {
+            depth: output_options.depth + 1,
+            ..output_options
+        },
+        csv_file,
+    );
+    let mut head = Vec::new();
+    if output_options.bom {
+        head.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    head.push(b'{');
+    head.extend_from_slice(&output_options.newline(1));
+    head.extend_from_slice(br#""schema""#);
+    head.extend_from_slice(output_options.key_separator());
+    let newline = output_options.newline(1);
+    write_nested_json(&mut head, &schema, &newline);
+    head.push(b',');
+    head.extend_from_slice(&newline);
+    serde_json::to_writer(&mut head, data_key.unwrap_or(DEFAULT_DATA_KEY))
+        .expect("data key is serializable");
+    head.extend_from_slice(output_options.key_separator());
+    let mut tail = output_options.newline(0);
+    tail.push(b'}');
+    let response = futures::stream::once(async { Ok(Bytes::from(head)) })
+        .chain(records)
+        .chain(futures::stream::once(async { Ok(Bytes::from(tail)) }));
+    json_response(
+        metrics.clone(),
+        output_options,
+        gzip,
+        range,
+        trailers.then_some(progress),
+        &download_file_name,
+        response.boxed(),
+    )
+    .await
+}
+
+/// Options taken from the URL query string to customize how uploads are read.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct UploadOptions {
+    /// Convert every field of a multipart upload rather than just the first one, responding with a JSON
+    /// object of the converted records keyed by file name.
+    #[serde(default)]
+    multiple_files: bool,
+    /// How the contents of uploaded files are encoded.
+    #[serde(default)]
+    field_encoding: FieldEncoding,
+    /// Reject uploads without any bytes at all, rather than converting them to an empty array like
+    /// uploads with only a header row.
+    #[serde(default)]
+    strict_empty: bool,
+    /// Reject uploads that have a header row but no records, rather than converting them to an empty
+    /// array.
+    #[serde(default)]
+    require_rows: bool,
+}
+
+/// Determines the key of an uploaded file in the combined response of a multiple file conversion.
+/// Files are keyed by their file name, falling back to their field name, and keys are made unique by
+/// appending a number.
+fn file_key(field: &Field, number: usize, keys: &mut HashSet<String>) -> String {
+    let key = field
+        .file_name()
+        .and_then(sanitize_file_name)
+        .or_else(|| field.name().map(str::to_string))
+        .unwrap_or_else(|| format!("file{}", number));
+    let mut unique_key = key.clone();
+    let mut suffix = 1;
+    while !keys.insert(unique_key.clone()) {
+        suffix += 1;
+        unique_key = format!("{} ({})", key, suffix);
+    }
+    unique_key
+}
+
+/// Converts every field of a multipart/form-data upload, responding with a single JSON object that
+/// has each file's converted records keyed by its file name. The files are converted one after the
+/// other as they are read from the request.
+#[allow(clippy::too_many_arguments)]
+async fn convert_csv_files(
+    config: &Config,
+    csv_parse_options: CsvParseOptions,
+    output_options: OutputOptions,
+    gzip: bool,
+    trailers: bool,
+    field_encoding: FieldEncoding,
+    file_name: &str,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if output_options.output != OutputFormat::Json {
+        return Ok(invalid_query_response(
+            "multiple-files can only be used with JSON output",
+        ));
+    }
+    if req.headers().contains_key(X_CONTENT_SHA256) {
+        return Ok(invalid_query_response(
+            "multiple-files can't be used with X-Content-SHA256",
+        ));
+    }
+    let range = parse_range(req.headers());
+    let mut multipart = match open_upload(config, req, &[]) {
+        Ok(UploadBody::Multipart(multipart)) => multipart,
+        // NOTE: no raw content types are accepted since a raw body can only contain a single file.
+        Ok(UploadBody::Raw(_)) => unreachable!("raw bodies are not accepted"),
+        Err(response) => return Ok(response),
+    };
+    // Read the first field up front so that we can still respond with an error if there isn't one.
+    let field_name = config.file_field_name.clone();
+    let first_field = match next_file_field(&mut multipart, field_name.as_deref()).await {
+        Ok(Some(field)) => field,
+        Ok(None) => return Ok(missing_file_field_response(field_name.as_deref())),
+        Err(error) => return Ok(multipart_error_response(error)),
+    };
+    let download_file_name = replace_file_extension(file_name, "json");
+    let request_timeout = config.request_timeout;
+    let metrics = config.metrics.clone();
+    let progress = Arc::new(ConversionProgress::default());
+    let file_progress = progress.clone();
+    // Each file's records are nested one level deep in the combined object.
+    let file_output_options = OutputOptions {
+        depth: output_options.depth + 1,
+        ..output_options
+    };
+    let response = try_stream! {
+        let mut keys = HashSet::new();
+        let mut number = 1;
+        let mut next_field = Some(first_field);
+        while let Some(field) = next_field {
+            let mut buffer = Vec::new();
+            // The files' records are nested, so the BOM has to be added to the combined object instead.
+            if number == 1 && output_options.bom {
+                buffer.extend_from_slice(b"\xEF\xBB\xBF");
+            }
+            buffer.push(if number == 1 { b'{' } else { b',' });
+            buffer.extend_from_slice(&output_options.newline(1));
+            serde_json::to_writer(&mut buffer, &file_key(&field, number, &mut keys))?;
+            buffer.extend_from_slice(output_options.key_separator());
+            yield Bytes::from(buffer);
+            // The field has to be dropped before the next one can be read, so it's converted in its own scope.
+            {
+                let file = idle_timeout_stream(field_stream(field), request_timeout);
+                let file = match field_encoding {
+                    FieldEncoding::Binary => Either::Left(file),
+                    FieldEncoding::Base64 => Either::Right(decode_base64_stream(file)),
+                };
+                let records = convert_csv_stream(
+                    metrics.clone(),
+                    file_progress.clone(),
+                    csv_parse_options.clone(),
+                    file_output_options,
+                    file,
+                );
+                for await chunk in records {
+                    yield chunk?;
+                }
+            }
+            next_field = next_file_field(&mut multipart, field_name.as_deref())
+                .await
+                .context("failed to read multipart field")?;
+            number += 1;
+        }
+        let mut buffer = output_options.newline(0);
+        buffer.push(b'}');
+        yield Bytes::from(buffer);
+    };
+    json_response(
+        config.metrics.clone(),
+        output_options,
+        gzip,
+        range,
+        trailers.then_some(progress),
+        &download_file_name,
+        response.boxed(),
+    )
+    .await
+}
+
+async fn convert_csv(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let csv_parse_options = match parse_csv_parse_options(config, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let output_options = match parse_output_options(config, &csv_parse_options, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let upload_options = match parse_query::<UploadOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let download_options = match parse_query::<DownloadOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let schema_options = match parse_query::<SchemaOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let destination_options = match parse_query::<DestinationOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let destination = match destination_options.s3_destination(config) {
+        Ok(destination) => destination,
+        Err(response) => return Ok(response),
+    };
+    let gzip = accepts_gzip(req.headers());
+    let range = parse_range(req.headers());
+    let trailers = accepts_trailers(req.headers());
+    if destination.is_some()
+        && (upload_options.multiple_files
+            || schema_options.schema_only
+            || schema_options.with_schema)
+    {
+        return Ok(invalid_query_response(
+            "destination can't be used with multiple-files, schema-only or with-schema",
+        ));
+    }
+    if (schema_options.schema_only || schema_options.with_schema) && upload_options.multiple_files {
+        return Ok(invalid_query_response(
+            "schema-only and with-schema can't be used with multiple-files",
+        ));
+    }
+    if let Err(message) = schema_options.check(&csv_parse_options, &output_options) {
+        return Ok(invalid_query_response(message));
+    }
+    let wrap_options = match parse_query::<WrapOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    if let Err(message) = wrap_options.check(&output_options, schema_options.with_schema) {
+        return Ok(invalid_query_response(message));
+    }
+    if (output_options.meta || wrap_options.data_key.is_some())
+        && (upload_options.multiple_files || destination.is_some())
+    {
+        return Ok(invalid_query_response(
+            "meta and data-key can't be used with multiple-files or destination",
+        ));
+    }
+    if upload_options.strict_empty && upload_options.multiple_files {
+        return Ok(invalid_query_response(
+            "strict-empty can't be used with multiple-files",
+        ));
+    }
+    if upload_options.require_rows && upload_options.multiple_files {
+        return Ok(invalid_query_response(
+            "require-rows can't be used with multiple-files",
+        ));
+    }
+    if upload_options.multiple_files {
+        let file_name = download_options.file_name(config.default_file_name.clone());
+        return convert_csv_files(
+            config,
+            csv_parse_options,
+            output_options,
+            gzip,
+            trailers,
+            upload_options.field_encoding,
+            &file_name,
+            req,
+        )
+        .await;
+    }
+    let (file_name, csv_file) = match read_upload(
+        config,
+        req,
+        &["text/csv", "application/csv", "application/zip"],
+    )
+    .await
+    {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    let csv_file = match upload_options.field_encoding {
+        FieldEncoding::Binary => Either::Left(csv_file),
+        FieldEncoding::Base64 => match decode_base64_upload(csv_file).await {
+            Ok(csv_file) => Either::Right(csv_file),
+            Err(response) => return Ok(response),
+        },
+    };
+    let (file_name, csv_file) = match unzip_upload(config, file_name, csv_file).await {
+        Ok((file_name, csv_file)) => (download_options.file_name(file_name), csv_file),
+        Err(response) => return Ok(response),
+    };
+    let csv_file = if upload_options.strict_empty {
+        match reject_empty_upload(csv_file).await {
+            Ok(csv_file) => Either::Left(csv_file),
+            Err(response) => return Ok(response),
+        }
+    } else {
+        Either::Right(csv_file)
+    };
+    let csv_file = if upload_options.require_rows {
+        match reject_headers_only(&csv_parse_options, csv_file).await {
+            Ok(csv_file) => Either::Left(csv_file),
+            Err(response) => return Ok(response),
+        }
+    } else {
+        Either::Right(csv_file)
+    };
+    if let Some((client, location)) = destination {
+        return destination_response(
+            &config.metrics,
+            client,
+            &location,
+            csv_parse_options,
+            output_options,
+            csv_file,
+        )
+        .await;
+    }
+    if schema_options.schema_only {
+        return schema_response(
+            &config.metrics,
+            csv_parse_options,
+            schema_options.schema_sample_rows,
+            csv_file,
+        )
+        .await;
+    }
+    if schema_options.with_schema {
+        return with_schema_response(
+            &config.metrics,
+            csv_parse_options,
+            output_options,
+            schema_options.schema_sample_rows,
+            gzip,
+            range,
+            trailers,
+            &file_name,
+            wrap_options.data_key.as_deref(),
+            csv_file,
+        )
+        .await;
+    }
+    csv_conversion_response(
+        &config.metrics,
+        csv_parse_options,
+        output_options,
+        gzip,
+        range,
+        trailers,
+        &file_name,
+        wrap_options.data_key.as_deref(),
+        csv_file,
+    )
+    .await
+}
+
+/// Checks an uploaded CSV without converting it, responding with a summary of its records and
+/// columns, and the first error in it if there is one. All the options for parsing the CSV apply,
+/// except for `limit`, since the whole CSV is checked.
+async fn validate_csv(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let csv_parse_options = match parse_csv_parse_options(config, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let csv_file = match read_upload(config, req, &["text/csv", "application/csv"]).await {
+        Ok((_, csv_file)) => csv_file,
+        Err(response) => return Ok(response),
+    };
+    let metrics = config.metrics.clone();
+    let csv_file = csv_file.inspect_ok(move |chunk| metrics.count_bytes_received(chunk.len()));
+    let csv_file = decode_csv_input(&csv_parse_options, csv_file);
+    let summary = validate_csv_records(csv_parse_options, csv_file).await;
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_string(&summary).expect("summary is serializable"),
+        ))
+}
+
+/// Options taken from the URL query string to convert a remotely hosted CSV.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RemoteSourceOptions {
+    source_url: String,
+}
+
+fn remote_source_error(code: ErrorCode, message: String) -> Response<Body> {
+    ApiError::new(code, message).into_response()
+}
+
+/// Stream producer that passes through chunks of bytes until more than `max_bytes` have been read,
+/// at which point an error is produced instead.
+fn limit_stream_size<S, E>(input: S, max_bytes: u64) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<anyhow::Error>,
+{
+    try_stream! {
+        let mut bytes_read = 0;
+        for await chunk in input {
+            let chunk = chunk
+                .map_err(Into::<anyhow::Error>::into)
+                .context("failed to read from input stream")?;
+            bytes_read += chunk.len() as u64;
+            if bytes_read > max_bytes {
+                Err(anyhow!("input exceeded the size limit of {} bytes", max_bytes))?;
+            }
+            yield chunk;
+        }
+    }
+}
+
+/// Stream producer that passes through chunks of bytes, producing an error instead if the next chunk
+/// takes longer than `timeout` to arrive. This limits how long a stalled client can hold on to a
+/// connection, without limiting the total time taken by large but steady streams.
+fn idle_timeout_stream<S, E>(
+    input: S,
+    timeout: Option<Duration>,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<anyhow::Error>,
+{
+    try_stream! {
+        pin_mut!(input);
+        loop {
+            let next = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, input.next())
+                    .await
+                    .map_err(|_| anyhow!("timed out after {:?} waiting for more input", timeout))?,
+                None => input.next().await,
+            };
+            match next {
+                Some(chunk) => yield chunk.map_err(Into::<anyhow::Error>::into)?,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Fetches a remotely hosted CSV file, returning its file name and a stream of its contents. If the
+/// URL isn't allowed or the remote server doesn't respond successfully, an error response is
+/// returned instead.
+async fn fetch_remote_csv(
+    config: &Config,
+    source_url: &str,
+) -> Result<(String, impl Stream<Item = Result<Bytes>>), Response<Body>> {
+    let url = Url::parse(source_url).map_err(|error| {
+        remote_source_error(
+            ErrorCode::InvalidSourceUrl,
+            format!("invalid source-url: {}", error),
+        )
+    })?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(remote_source_error(
+            ErrorCode::InvalidSourceUrl,
+            "source-url must be an http or https URL".to_string(),
+        ));
+    }
+    // To protect against server-side request forgery, only fetch from the allowed hosts if any have been
+    // configured.
+    let host = url.host_str().unwrap_or_default();
+    if !config.allowed_source_hosts.is_empty()
+        && !config
+            .allowed_source_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Err(remote_source_error(
+            ErrorCode::SourceHostNotAllowed,
+            format!("fetching from host {:?} is not allowed", host),
+        ));
+    }
+
+    let uri: hyper::Uri = url.as_str().parse().map_err(|error| {
+        remote_source_error(
+            ErrorCode::InvalidSourceUrl,
+            format!("invalid source-url: {}", error),
+        )
+    })?;
+    let res = config.http_client.get(uri).await.map_err(|error| {
+        remote_source_error(
+            ErrorCode::SourceUnavailable,
+            format!("failed to fetch source-url: {}", error),
+        )
+    })?;
+    if res.status() != StatusCode::OK {
+        return Err(remote_source_error(
+            ErrorCode::SourceUnavailable,
+            format!("source-url responded with status {}", res.status()),
+        ));
+    }
+    let content_length = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|cl| cl.to_str().ok())
+        .and_then(|cl| cl.parse::<u64>().ok());
+    if matches!(content_length, Some(length) if length > config.max_upload_bytes) {
+        return Err(remote_source_error(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "source-url exceeds the maximum upload size of {} bytes",
+                config.max_upload_bytes
+            ),
+        ));
+    }
+
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .and_then(|segment| {
+            percent_decode_str(segment)
+                .decode_utf8()
+                .ok()
+                .and_then(|segment| sanitize_file_name(&segment))
+        })
+        .unwrap_or_else(|| config.default_file_name.clone());
+    Ok((
+        file_name,
+        limit_stream_size(
+            idle_timeout_stream(res.into_body(), config.request_timeout),
+            config.max_upload_bytes,
+        ),
+    ))
+}
+
+async fn convert_remote_csv(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let RemoteSourceOptions { source_url } = match parse_query::<RemoteSourceOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let csv_parse_options = match parse_csv_parse_options(config, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let output_options = match parse_output_options(config, &csv_parse_options, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let download_options = match parse_query::<DownloadOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let schema_options = match parse_query::<SchemaOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    if let Err(message) = schema_options.check(&csv_parse_options, &output_options) {
+        return Ok(invalid_query_response(message));
+    }
+    let wrap_options = match parse_query::<WrapOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    if let Err(message) = wrap_options.check(&output_options, schema_options.with_schema) {
+        return Ok(invalid_query_response(message));
+    }
+    let gzip = accepts_gzip(req.headers());
+    let range = parse_range(req.headers());
+    let trailers = accepts_trailers(req.headers());
+    let (file_name, csv_file) = match fetch_remote_csv(config, &source_url).await {
+        Ok((file_name, csv_file)) => (download_options.file_name(file_name), csv_file),
+        Err(response) => return Ok(response),
+    };
+    if schema_options.schema_only {
+        return schema_response(
+            &config.metrics,
+            csv_parse_options,
+            schema_options.schema_sample_rows,
+            csv_file,
+        )
+        .await;
+    }
+    if schema_options.with_schema {
+        return with_schema_response(
+            &config.metrics,
+            csv_parse_options,
+            output_options,
+            schema_options.schema_sample_rows,
+            gzip,
+            range,
+            trailers,
+            &file_name,
+            wrap_options.data_key.as_deref(),
+            csv_file,
+        )
+        .await;
+    }
+    csv_conversion_response(
+        &config.metrics,
+        csv_parse_options,
+        output_options,
+        gzip,
+        range,
+        trailers,
+        &file_name,
+        wrap_options.data_key.as_deref(),
+        csv_file,
+    )
+    .await
+}
+
+/// Supported formats for the JSON input to convert to CSV.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum JsonInputFormat {
+    /// A single JSON array of objects.
+    #[default]
+    Json,
+    /// JSON Lines, one object per line.
+    Jsonl,
+}
+
+/// Options taken from the URL query string to customize how JSON input is read.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonInputOptions {
+    #[serde(default)]
+    input: JsonInputFormat,
+}
+
+async fn convert_json(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let json_input_options = match parse_query::<JsonInputOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let download_options = match parse_query::<DownloadOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let (file_name, json_file) =
+        match read_upload(config, req, &["application/json", "application/x-ndjson"]).await {
+            Ok((file_name, json_file)) => (download_options.file_name(file_name), json_file),
+            Err(response) => return Ok(response),
+        };
+    let json_file = json_file.inspect_ok({
+        let metrics = config.metrics.clone();
+        move |chunk| metrics.count_bytes_received(chunk.len())
+    });
+    let json_objects = match json_input_options.input {
+        JsonInputFormat::Json => Either::Left(to_csv::parse_json_objects(json_file)),
+        JsonInputFormat::Jsonl => Either::Right(to_csv::parse_json_lines(json_file)),
+    };
+    let json_objects = json_objects.inspect_ok({
+        let metrics = config.metrics.clone();
+        move |_| metrics.count_record()
+    });
+    let metrics = config.metrics.clone();
+    let response = to_csv::serialize_csv_records(json_objects)
+        .inspect(move |result| match result {
+            Ok(chunk) => metrics.count_bytes_sent(chunk.len()),
+            Err(error) => {
+                metrics.count_conversion_error();
+                error!("error during JSON conversion: {:?}", error);
+            }
+        })
+        .instrument(Span::current());
+    let download_file_name = replace_file_extension(&file_name, "csv");
+    Response::builder()
+        // NOTE: see OutputFormat::content_type for why octet-stream is used over the actual mime type.
+        .header(CONTENT_TYPE, "application/octet-stream; charset=utf-8")
+        .header(
+            CONTENT_DISPOSITION,
+            content_disposition(&download_file_name),
+        )
+        .body(Body::wrap_stream(response))
+}
+
+/// Options taken from the URL query string to customize the Parquet output.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ParquetOptions {
+    /// Number of records to infer the column types from.
+    #[serde(default = "default_schema_sample_rows")]
+    schema_sample_rows: usize,
+}
+
+/// Returns the type of a Parquet column, which is the one given with the `types` option, or otherwise
+/// the one inferred from the sampled records.
+fn parquet_column(csv_parse_options: &CsvParseOptions, column: ColumnSchema) -> ColumnSchema {
+    let column_type = match csv_parse_options.column_type(&column.name) {
+        Some(FieldType::Int) => ColumnType::Integer,
+        Some(FieldType::Float) => ColumnType::Number,
+        Some(FieldType::Bool) => ColumnType::Boolean,
+        Some(FieldType::String) => ColumnType::String,
+        None => column.column_type,
+    };
+    ColumnSchema {
+        column_type,
+        ..column
+    }
+}
+
+/// Converts an uploaded CSV to an Apache Parquet file. A Parquet file's schema has to be known before
+/// any records are written, so the column types are inferred from the first records like with
+/// `schema-only`, and the input that was read for them is converted again. Its metadata comes after the
+/// records, so the file is written to memory in full before responding, up to `--max-parquet-bytes`,
+/// and failures are responded with an error status.
+async fn convert_csv_to_parquet(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let csv_parse_options = match parse_csv_parse_options(config, &req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    if csv_parse_options.key_column.is_some()
+        || csv_parse_options.raw_rows
+        || csv_parse_options.nest
+        || csv_parse_options.add_row_number.is_some()
+    {
+        return Ok(invalid_query_response(
+            "key-column, raw-rows, nest and add-row-number can't be used with Parquet output",
+        ));
+    }
+    let parquet_options = match parse_query::<ParquetOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let download_options = match parse_query::<DownloadOptions>(&req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let range = parse_range(req.headers());
+    let (file_name, csv_file) = match read_upload(
+        config,
+        req,
+        &["text/csv", "application/csv", "application/zip"],
+    )
+    .await
+    {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    let (file_name, csv_file) = match unzip_upload(config, file_name, csv_file).await {
+        Ok((file_name, csv_file)) => (download_options.file_name(file_name), csv_file),
+        Err(response) => return Ok(response),
+    };
+    let csv_file = csv_file.inspect_ok({
+        let metrics = config.metrics.clone();
+        move |chunk| metrics.count_bytes_received(chunk.len())
+    });
+    let (schema, csv_file) = read_schema_ahead(
+        &csv_parse_options,
+        parquet_options.schema_sample_rows,
+        csv_file,
+    )
+    .await;
+    let columns: Vec<_> = match schema {
+        Ok(schema) => schema
+            .columns
+            .into_iter()
+            .map(|column| parquet_column(&csv_parse_options, column))
+            .collect(),
+        Err(error) => {
+            return Ok(ApiError::new(
+                ErrorCode::ConversionFailed,
+                format!("failed to read the CSV's schema: {}", error),
+            )
+            .into_response())
+        }
+    };
+
+    let csv_file = decode_csv_input(&csv_parse_options, csv_file);
+    let records = parse_csv_records(csv_parse_options.clone(), csv_file);
+    let records = match csv_parse_options.limit {
+        Some(limit) => Either::Left(records.take(limit)),
+        None => Either::Right(records),
+    };
+    let records = records.map({
+        let metrics = config.metrics.clone();
+        move |record| {
+            let record = record?;
+            metrics.count_record();
+            match serde_json::to_value(&record)? {
+                serde_json::Value::Object(fields) => Ok(fields),
+                _ => Err(anyhow!("records are objects without raw-rows")),
+            }
+        }
+    });
+    match to_parquet::write_parquet(&columns, config.max_parquet_bytes, records).await {
+        Ok(parquet) => {
+            config.metrics.count_bytes_sent(parquet.len());
+            let builder = Response::builder()
+                .header(CONTENT_TYPE, "application/vnd.apache.parquet")
+                .header(
+                    CONTENT_DISPOSITION,
+                    content_disposition(&replace_file_extension(&file_name, "parquet")),
+                );
+            buffered_response(builder, false, range, parquet).await
+        }
+        Err(error) => {
+            config.metrics.count_conversion_error();
+            error!("error during Parquet conversion: {:?}", error);
+            let code = if error.is::<ParquetTooLarge>() {
+                ErrorCode::ParquetTooLarge
+            } else {
+                ErrorCode::ConversionFailed
+            };
+            Ok(ApiError::new(code, format!("{:#}", error)).into_response())
+        }
+    }
+}
+
+const X_REQUEST_ID: &str = "x-request-id";
+const TRACEPARENT: &str = "traceparent";
+
+/// Maximum length of a client-provided request ID that we're willing to log and echo back.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Determines the ID used to correlate a request with its log lines. Clients can provide their own
+/// ID with the `X-Request-Id` header, or as part of a W3C `traceparent` header, in which case its
+/// trace ID is used. Otherwise a random UUID is generated.
+fn request_id(headers: &HeaderMap) -> String {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    if let Some(request_id) =
+        header(X_REQUEST_ID).filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+    {
+        return request_id.to_string();
+    }
+    // A traceparent looks like `00-<32 hex digit trace ID>-<16 hex digit parent ID>-<2 hex digit flags>`.
+    let trace_id = header(TRACEPARENT)
+        .and_then(|traceparent| traceparent.split('-').nth(1))
+        .filter(|id| id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()));
+    match trace_id {
+        Some(trace_id) => trace_id.to_ascii_lowercase(),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+/// Returns the value of the Access-Control-Allow-Origin header for a request from the given origin, or
+/// None if cross-origin requests from it aren't allowed.
+fn cors_allowed_origin(config: &Config, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+    let origin = origin?;
+    if config.cors_origins.iter().any(|allowed| allowed == "*") {
+        Some(HeaderValue::from_static("*"))
+    } else if config
+        .cors_origins
+        .iter()
+        .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+/// Adds the CORS headers that allow the response to be read by a browser on another origin.
+fn add_cors_headers(res: &mut Response<Body>, allowed_origin: HeaderValue) {
+    let headers = res.headers_mut();
+    // The allowed origin depends on the request's origin, unless any origin is allowed.
+    if allowed_origin != "*" {
+        headers.append(VARY, HeaderValue::from_static("origin"));
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+    // NOTE: browsers hide response headers from scripts unless they're exposed, and clients need the
+    //       Content-Disposition to know the name of the download.
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("content-disposition, x-request-id"),
+    );
+}
+
+/// Responds to a CORS preflight request, which browsers send before cross-origin requests that can't
+/// be sent without asking first, e.g. ones with custom headers. The Access-Control-Allow-Origin header
+/// is added by add_cors_headers like for any other response.
+fn preflight_response(req: &Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+    let mut builder = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS")
+        .header(ACCESS_CONTROL_MAX_AGE, "86400");
+    if let Some(headers) = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+        builder = builder.header(ACCESS_CONTROL_ALLOW_HEADERS, headers);
+    }
+    builder.body(Body::empty())
+}
+
+async fn route_request(
+    config: Arc<Config>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let request_id = request_id(req.headers());
+    let cors_allowed_origin = cors_allowed_origin(&config, req.headers().get(ORIGIN));
+    // NOTE: only selected parts of the request are logged, since logging all of its headers could leak
+    //       credentials into the logs.
+    let span = tracing::info_span!(
+        "request",
+        id = %request_id,
+        method = %req.method(),
+        path = req.uri().path(),
+        content_length = tracing::field::Empty,
+    );
+    if let Some(content_length) = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|cl| cl.to_str().ok())
+        .and_then(|cl| cl.parse::<u64>().ok())
+    {
+        span.record("content_length", content_length);
+    }
+    async move {
+        info!("received request");
+        let started = Instant::now();
+        config.metrics.count_request();
+        // The timeout only applies until the response starts. Once it has started, request bodies are instead
+        // limited by an idle timeout between chunks (see idle_timeout_stream) so that large uploads aren't cut off.
+        // NOTE: the request's future is boxed since it holds the state of whichever route handles it,
+        //       which is too large to keep on the stack in debug builds.
+        let dispatch = Box::pin(dispatch_request(&config, req));
+        let mut res = match config.request_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, dispatch).await {
+                Ok(res) => res,
+                Err(_) => Ok(ApiError::new(
+                    ErrorCode::RequestTimeout,
+                    format!("timed out after {:?} waiting for the request", timeout),
+                )
+                .into_response()),
+            },
+            None => dispatch.await,
+        };
+        config.metrics.observe_request_duration(started.elapsed());
+        match &mut res {
+            Ok(res) => {
+                info!(status = res.status().as_u16(), "responding");
+                // The request ID only contains visible ASCII characters since it either came from a header or
+                // was generated, so it's always a valid header value.
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(X_REQUEST_ID, value);
+                }
+                if let Some(allowed_origin) = cors_allowed_origin {
+                    add_cors_headers(res, allowed_origin);
+                }
+            }
+            Err(error) => error!("failed to build response: {}", error),
+        }
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+/// Runs a conversion while holding one of the permits that limit the number of concurrent
+/// conversions, if the number is limited. The permit is held until the response has been streamed
+/// in full, since that's when the conversion actually happens. Responds with `503 Service
+/// Unavailable` if there are no permits left.
+async fn with_conversion_permit(
+    config: &Config,
+    conversion: impl Future<Output = Result<Response<Body>, hyper::http::Error>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let permit = match &config.conversion_permits {
+        Some(permits) => match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("rejecting conversion since the concurrent conversion limit was reached");
+                let mut res = ApiError::new(
+                    ErrorCode::TooManyConversions,
+                    "too many concurrent conversions, try again later",
+                )
+                .into_response();
+                res.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                return Ok(res);
+            }
+        },
+        None => return conversion.await,
+    };
+    let res = conversion.await?;
+    Ok(res.map(|body| {
+        Body::wrap_stream(body.inspect(move |_| {
+            // NOTE: the permit is only referenced so that it's moved into the stream, and released when the
+            //       stream is dropped.
+            let _ = &permit;
+        }))
+    }))
+}
+
+/// Counts the request towards the rate limits of the client's IP address, and the bytes of its body as
+/// they're read. Responds with `429 Too Many Requests` if the client has already reached a limit.
+#[allow(clippy::result_large_err)]
+fn check_rate_limit(config: &Config, req: Request<Body>) -> Result<Request<Body>, Response<Body>> {
+    let (Some(rate_limiter), Some(&RemoteAddr(remote_addr))) =
+        (&config.rate_limiter, req.extensions().get::<RemoteAddr>())
+    else {
+        return Ok(req);
+    };
+    let client = remote_addr.ip();
+    if let Err(retry_after) = rate_limiter.check(client) {
+        warn!(%client, "rejecting request since the client reached its rate limit");
+        let mut res = ApiError::new(ErrorCode::RateLimited, "too many requests, try again later")
+            .into_response();
+        // Retry-After is in whole seconds, so it's rounded up to not retry before the window ends.
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        return Err(res);
+    }
+    let rate_limiter = rate_limiter.clone();
+    Ok(req.map(|body| {
+        Body::wrap_stream(
+            body.inspect_ok(move |chunk| rate_limiter.count_bytes(client, chunk.len())),
+        )
+    }))
+}
+
+/// Adds the query param defaults from the config file to the request's URL, except for those that the
+/// request gives itself, so that they're used when the options are parsed from the query string.
+fn with_query_defaults(defaults: &[(String, String)], mut req: Request<Body>) -> Request<Body> {
+    if defaults.is_empty() {
+        return req;
+    }
+    let query = req.uri().query().unwrap_or_default();
+    let given: HashSet<String> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, _)| name.into_owned())
+        .collect();
+    let mut serializer = url::form_urlencoded::Serializer::for_suffix(query.to_string(), 0);
+    for (name, value) in defaults.iter().filter(|(name, _)| !given.contains(name)) {
+        serializer.append_pair(name, value);
+    }
+    let path_and_query = format!("{}?{}", req.uri().path(), serializer.finish());
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().expect("the query is URL-encoded"));
+    *req.uri_mut() = hyper::Uri::from_parts(parts).expect("only the query has changed");
+    req
+}
+
+async fn dispatch_request(
+    config: &Config,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if !route_enabled(config, req.method(), req.uri().path()) {
+        return Ok(unrouted_response(config, &req));
+    }
+    let req = with_query_defaults(&config.query_defaults, req);
+    // Health checks and metrics are never limited, so that they keep working for monitoring.
+    let req = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health" | "/metrics") => req,
+        _ => match check_rate_limit(config, req) {
+            Ok(req) => req,
+            Err(res) => return Ok(res),
+        },
+    };
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/") => with_conversion_permit(config, convert_csv(config, req)).await,
+        (&Method::GET, "/") => {
+            with_conversion_permit(config, convert_remote_csv(config, req)).await
+        }
+        (&Method::POST, "/to-csv") => {
+            with_conversion_permit(config, convert_json(config, req)).await
+        }
+        (&Method::POST, "/to-parquet") => {
+            with_conversion_permit(config, convert_csv_to_parquet(config, req)).await
+        }
+        (&Method::POST, "/validate") => {
+            with_conversion_permit(config, validate_csv(config, req)).await
+        }
+        (&Method::HEAD, "/") => head_response(config, &req),
+        (&Method::OPTIONS, path @ ("/" | "/to-csv" | "/to-parquet" | "/validate"))
+            if !config.cors_origins.is_empty() && !allowed_methods(config, path).is_empty() =>
+        {
+            preflight_response(&req)
+        }
+        (&Method::GET, "/health") => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"status":"ok"}"#)),
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(config.metrics.render())),
+        _ => Ok(unrouted_response(config, &req)),
+    }
+}
+
+/// Method and path of each route that dispatch_request handles, apart from CORS preflight requests.
+const ROUTES: [(Method, &str); 8] = [
+    (Method::POST, "/"),
+    (Method::GET, "/"),
+    (Method::HEAD, "/"),
+    (Method::POST, "/to-csv"),
+    (Method::POST, "/to-parquet"),
+    (Method::POST, "/validate"),
+    (Method::GET, "/health"),
+    (Method::GET, "/metrics"),
+];
+
+/// Returns whether the route hasn't been disabled with `--enable` or `--disable`. Requests that
+/// aren't for one of the ROUTES are always let through, to be responded to by dispatch_request.
+fn route_enabled(config: &Config, method: &Method, path: &str) -> bool {
+    !config
+        .disabled_routes
+        .iter()
+        .any(|(disabled_method, disabled_path)| disabled_method == method && *disabled_path == path)
+}
+
+/// Responds to a request that has no route, or whose route is disabled, listing the routes that are
+/// enabled. If there are enabled routes for the path with other methods, responds with `405 Method Not
+/// Allowed` and an Allow header listing them, and with `404 Not Found` otherwise, including for
+/// disabled routes.
+fn unrouted_response(config: &Config, req: &Request<Body>) -> Response<Body> {
+    let available = ROUTES
+        .iter()
+        .filter(|(method, path)| route_enabled(config, method, path))
+        .map(|(method, path)| format!("{} {}", method, path))
+        .collect();
+    let allowed = allowed_methods(config, req.uri().path());
+    let disabled = !route_enabled(config, req.method(), req.uri().path());
+    if allowed.is_empty() || disabled {
+        return ApiError::new(ErrorCode::NotFound, "not found")
+            .with_available(available)
+            .into_response();
+    }
+    let mut res = ApiError::new(
+        ErrorCode::MethodNotAllowed,
+        format!("method {} not allowed", req.method()),
+    )
+    .with_available(available)
+    .into_response();
+    res.headers_mut().insert(ALLOW, allowed);
+    res
+}
+
+/// Returns the methods that there are enabled routes for with the path, as the value of an Allow
+/// header. It's empty if there are none.
+fn allowed_methods(config: &Config, path: &str) -> HeaderValue {
+    let mut allowed: Vec<&str> = ROUTES
+        .iter()
+        .filter(|(method, route_path)| *route_path == path && route_enabled(config, method, path))
+        .map(|(method, _)| method.as_str())
+        .collect();
+    if !allowed.is_empty()
+        && !config.cors_origins.is_empty()
+        && matches!(path, "/" | "/to-csv" | "/to-parquet" | "/validate")
+    {
+        allowed.push(Method::OPTIONS.as_str());
+    }
+    // The methods are all valid header values, and so is a list of them.
+    HeaderValue::from_str(&allowed.join(", ")).expect("methods are valid header values")
+}
+
+/// Responds to a HEAD request for the conversion path with the headers of a conversion with the same
+/// query string, so that clients can check the options and the output's content type before uploading.
+/// Nothing is converted, since that requires a POST, or a GET with a `source-url`.
+fn head_response(
+    config: &Config,
+    req: &Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let csv_parse_options = match parse_csv_parse_options(config, req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    let output_options = match parse_output_options(config, &csv_parse_options, req) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, output_options.output.content_type())
+        .header(ALLOW, allowed_methods(config, req.uri().path()))
+        .body(Body::empty())
+}
+
+/// Default maximum size of a request body, 50 MiB.
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Default maximum size of a converted Parquet file, 50 MiB.
+const DEFAULT_MAX_PARQUET_BYTES: usize = 50 * 1024 * 1024;
+
+/// Default name of the download for uploads without a file name, before its extension is set to match
+/// the output format.
+const DEFAULT_FILE_NAME: &str = "download";
+
+/// Default time to wait for in-flight requests to finish when shutting down.
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// Window over which the requests and uploaded bytes of each client are rate limited.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Formats that log lines can be written in.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LogFormat {
+    /// Human-readable lines of text.
+    Pretty,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Read defaults for these flags, and for query params, from this TOML file, or JSON file with a
+    /// `.json` extension. Flags that are given take precedence over the file
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Query params from the `defaults` of the config file, used for requests that don't give them.
+    #[clap(skip)]
+    query_defaults: Vec<(String, String)>,
+    /// IP address to listen on
+    #[clap(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    host: IpAddr,
+    #[clap(short, long, default_value_t = 8000)]
+    port: u16,
+    /// Listen on a Unix domain socket at this path instead of on the host and port. A stale socket
+    /// file left behind at the path is replaced
+    #[clap(long, value_name = "PATH", conflicts_with = "tls-cert")]
+    unix_socket: Option<PathBuf>,
+    /// Maximum size in bytes of an uploaded request body
+    #[clap(long, default_value_t = DEFAULT_MAX_UPLOAD_BYTES)]
+    max_upload_bytes: u64,
+    /// Maximum size in bytes of a Parquet file converted with `/to-parquet`, which is held in memory in
+    /// full before it's sent
+    #[clap(long, default_value_t = DEFAULT_MAX_PARQUET_BYTES)]
+    max_parquet_bytes: usize,
+    /// Only allow converting remote CSVs hosted on this host. May be given multiple times. If not
+    /// given, remote CSVs may be fetched from any host
+    #[clap(long = "allow-source-host", value_name = "HOST")]
+    allowed_source_hosts: Vec<String>,
+    /// Maximum number of fields in a CSV record. Unlimited by default
+    #[clap(long, value_name = "COUNT")]
+    max_columns: Option<usize>,
+    /// Maximum size in bytes of a single CSV field. Unlimited by default
+    #[clap(long, value_name = "BYTES")]
+    max_cell_bytes: Option<usize>,
+    /// Maximum number of conversions to run at the same time. Further conversion requests are rejected
+    /// with 503 Service Unavailable until one finishes. Unlimited by default
+    #[clap(long, value_name = "COUNT")]
+    max_concurrent_conversions: Option<usize>,
+    /// Maximum number of requests per minute from a single IP address. Further requests are rejected
+    /// with 429 Too Many Requests until the minute is up. Unlimited by default
+    #[clap(long, value_name = "COUNT")]
+    max_requests_per_minute_per_ip: Option<u64>,
+    /// Maximum number of bytes per second, averaged over a minute, that a single IP address may
+    /// upload. Once exceeded, further requests are rejected with 429 Too Many Requests until the minute
+    /// is up. Unlimited by default
+    #[clap(long, value_name = "BYTES")]
+    rate_limit_bytes_per_sec: Option<u64>,
+    /// Number of bytes of converted output to accumulate before sending it, rather than sending
+    /// after every record. Larger values mean fewer, larger chunks
+    #[clap(long, value_name = "BYTES", default_value_t = 0)]
+    flush_bytes: usize,
+    /// Convert output up to this many bytes in full before responding, so that small responses have
+    /// a Content-Length and report errors with their status, and stream larger output. By default
+    /// output is always streamed unless requested otherwise
+    #[clap(long, value_name = "BYTES")]
+    buffer_threshold: Option<usize>,
+    /// Maximum number of bytes of converted output to send at once. The output of a record that's
+    /// larger than this is split into several chunks, so that converting it doesn't hold up other
+    /// requests for as long. Unlimited by default
+    #[clap(long, value_name = "BYTES")]
+    max_chunk_bytes: Option<usize>,
+    /// Only read uploaded files from multipart fields with this name, skipping any other fields. By
+    /// default the first field is read
+    #[clap(long, value_name = "NAME")]
+    file_field_name: Option<String>,
+    /// Name of the download when the upload doesn't have a file name, e.g. with a raw request body or
+    /// a multipart field without one. Its extension is replaced to match the output format
+    #[clap(
+        long,
+        value_name = "NAME",
+        default_value = DEFAULT_FILE_NAME,
+        parse(try_from_str = parse_default_filename)
+    )]
+    default_filename: String,
+    /// Maximum number of seconds to wait for a request before responding, and between chunks of
+    /// the request body afterwards. Disabled by default
+    #[clap(long, value_name = "SECS")]
+    request_timeout_secs: Option<u64>,
+    /// Allow browsers to make cross-origin requests from this origin, e.g. `https://example.com`.
+    /// May be given multiple times, or as `*` to allow any origin. Disabled by default
+    #[clap(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+    /// Only enable this route, given as `METHOD /path`, or as `/path` for all methods of the path. May
+    /// be given multiple times. All routes are enabled by default
+    #[clap(long, value_name = "ROUTE", parse(try_from_str = parse_route_selector))]
+    enable: Vec<RouteSelector>,
+    /// Disable this route, given like for `--enable`, so that requests for it are responded to with
+    /// 404 Not Found. May be given multiple times
+    #[clap(long, value_name = "ROUTE", parse(try_from_str = parse_route_selector))]
+    disable: Vec<RouteSelector>,
+    /// Maximum number of seconds to wait for in-flight requests to finish when shutting down
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_SHUTDOWN_TIMEOUT_SECS)]
+    shutdown_timeout_secs: u64,
+    /// Serve HTTPS using the certificate chain in this PEM file. Requires `--tls-key`
+    #[clap(long, value_name = "PATH", requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// Serve HTTPS using the private key in this PEM file. Requires `--tls-cert`
+    #[clap(long, value_name = "PATH", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Allow uploading conversions to S3 objects with `destination=s3://bucket/key`, in this AWS
+    /// region. Requires `--s3-access-key-id` and `--s3-secret-access-key`
+    #[clap(
+        long,
+        value_name = "REGION",
+        requires_all = &["s3-access-key-id", "s3-secret-access-key"]
+    )]
+    s3_region: Option<String>,
+    /// Upload to the S3-compatible store at this URL instead of AWS, e.g. `http://localhost:9000`
+    #[clap(long, value_name = "URL", requires = "s3-region")]
+    s3_endpoint: Option<String>,
+    /// Access key ID of the credentials to upload to S3 with
+    #[clap(long, value_name = "ID", env = "AWS_ACCESS_KEY_ID")]
+    s3_access_key_id: Option<String>,
+    /// Secret access key of the credentials to upload to S3 with
+    #[clap(
+        long,
+        value_name = "KEY",
+        env = "AWS_SECRET_ACCESS_KEY",
+        hide_env_values = true
+    )]
+    s3_secret_access_key: Option<String>,
+    /// Format of the log lines written to stdout. Which log lines are written can be configured with
+    /// the RUST_LOG environment variable
+    #[clap(long, arg_enum, default_value = "pretty")]
+    log_format: LogFormat,
+    /// Write an access log line to stdout for each request once its response has been sent, in the
+    /// Combined Log Format or as JSON
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    access_log_format: Option<AccessLogFormat>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Parses the command line, filling in the flags that weren't given from the `--config` file if
+/// there is one.
+fn parse_args<I, T>(args: I) -> Result<Args>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Args::command().get_matches_from(args);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    if let Some(path) = args.config.clone() {
+        apply_config_file(&mut args, &matches, ConfigFile::load(&path)?)?;
+    }
+    Ok(args)
+}
+
+/// Sets the flags that weren't given on the command line to their values in the config file. The
+/// query param defaults are checked up front, so that invalid ones don't fail every request.
+fn apply_config_file(args: &mut Args, matches: &ArgMatches, file: ConfigFile) -> Result<()> {
+    macro_rules! apply {
+        ($($flag:literal => $field:ident $(as $wrap:path)?),* $(,)?) => {
+            $(
+                if let Some(value) = file.$field.clone() {
+                    if matches.occurrences_of($flag) == 0 {
+                        args.$field = $($wrap)?(value);
+                    }
+                }
+            )*
+        };
+    }
+    apply! {
+        "host" => host,
+        "port" => port,
+        "unix-socket" => unix_socket as Some,
+        "max-upload-bytes" => max_upload_bytes,
+        "max-parquet-bytes" => max_parquet_bytes,
+        "max-columns" => max_columns as Some,
+        "max-cell-bytes" => max_cell_bytes as Some,
+        "max-concurrent-conversions" => max_concurrent_conversions as Some,
+        "max-requests-per-minute-per-ip" => max_requests_per_minute_per_ip as Some,
+        "rate-limit-bytes-per-sec" => rate_limit_bytes_per_sec as Some,
+        "flush-bytes" => flush_bytes,
+        "buffer-threshold" => buffer_threshold as Some,
+        "max-chunk-bytes" => max_chunk_bytes as Some,
+        "file-field-name" => file_field_name as Some,
+        "default-filename" => default_filename,
+        "request-timeout-secs" => request_timeout_secs as Some,
+        "shutdown-timeout-secs" => shutdown_timeout_secs,
+        "tls-cert" => tls_cert as Some,
+        "tls-key" => tls_key as Some,
+        "log-format" => log_format,
+        "access-log-format" => access_log_format as Some,
+    }
+    // NOTE: the flag is checked when it's parsed, but values from the config file aren't.
+    parse_default_filename(&args.default_filename)
+        .map_err(|error| anyhow!("invalid default-filename in config file: {}", error))?;
+    if let Some(hosts) = file.allow_source_hosts.clone() {
+        if matches.occurrences_of("allowed-source-hosts") == 0 {
+            args.allowed_source_hosts = hosts;
+        }
+    }
+    if let Some(origins) = file.cors_origins.clone() {
+        if matches.occurrences_of("cors-origins") == 0 {
+            args.cors_origins = origins;
+        }
+    }
+    let query_defaults = file.query_defaults()?;
+    let query = serde_urlencoded::to_string(&query_defaults)?;
+    serde_urlencoded::from_str::<CsvParseOptions>(&query)
+        .context("invalid query param defaults in config file")?;
+    serde_urlencoded::from_str::<OutputOptions>(&query)
+        .context("invalid query param defaults in config file")?;
+    args.query_defaults = query_defaults;
+    Ok(())
+}
+
+/// Selects the ROUTES with a path, and optionally a method, for `--enable` and `--disable`.
+#[derive(Debug, Clone)]
+struct RouteSelector {
+    method: Option<Method>,
+    path: String,
+}
+
+impl RouteSelector {
+    fn matches(&self, (method, path): &(Method, &str)) -> bool {
+        self.path == *path && self.method.as_ref().is_none_or(|m| m == method)
+    }
+}
+
+/// Checks that the `--default-filename` can be used as is, i.e. that there's nothing to sanitize.
+fn parse_default_filename(value: &str) -> Result<String, String> {
+    match sanitize_file_name(value) {
+        Some(file_name) if file_name == value => Ok(file_name),
+        _ => Err(format!(
+            "{:?} isn't a file name without directories, quotes or control characters",
+            value
+        )),
+    }
+}
+
+fn parse_route_selector(value: &str) -> Result<RouteSelector, String> {
+    let selector = match value.trim().split_once(' ') {
+        Some((method, path)) => RouteSelector {
+            method: Some(
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("invalid method {:?}", method))?,
+            ),
+            path: path.trim().to_string(),
+        },
+        None => RouteSelector {
+            method: None,
+            path: value.trim().to_string(),
+        },
+    };
+    if !ROUTES.iter().any(|route| selector.matches(route)) {
+        return Err(format!("there's no route {:?}", value));
+    }
+    Ok(selector)
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a single CSV file to JSON without running the server
+    #[clap(long_flag = "convert")]
+    Convert(ConvertArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    /// CSV file to convert. Reads from stdin if not given or `-`
+    input: Option<PathBuf>,
+    /// File to write the JSON to. Writes to stdout if not given
+    #[clap(short = 'o', long)]
+    output_file: Option<PathBuf>,
+    #[clap(flatten)]
+    csv_parse_options: CsvParseOptions,
+    #[clap(flatten)]
+    output_options: OutputOptions,
+}
+
+/// Converts a CSV file, or stdin, to JSON and writes it to a file, or stdout.
+async fn convert_file(args: ConvertArgs) -> Result<()> {
+    check_output_format(&args.csv_parse_options, &args.output_options)
+        .map_err(|error| anyhow!(error))?;
+    let input: Pin<Box<dyn AsyncRead + Send>> = match &args.input {
+        Some(path) if path != Path::new("-") => Box::pin(
+            tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("failed to open {}", path.display()))?,
+        ),
+        _ => Box::pin(tokio::io::stdin()),
+    };
+    let mut output: Pin<Box<dyn AsyncWrite + Send>> = match &args.output_file {
+        Some(path) => Box::pin(
+            tokio::fs::File::create(path)
+                .await
+                .with_context(|| format!("failed to create {}", path.display()))?,
+        ),
+        None => Box::pin(tokio::io::stdout()),
+    };
+    let json = convert_csv_stream(
+        Arc::default(),
+        Arc::default(),
+        args.csv_parse_options,
+        args.output_options,
+        ReaderStream::new(input),
+    );
+    pin_mut!(json);
+    while let Some(chunk) = json.try_next().await? {
+        output
+            .write_all(&chunk)
+            .await
+            .context("failed to write output")?;
+    }
+    output.flush().await.context("failed to write output")?;
+    Ok(())
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+fn build_http_client() -> HttpClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// Server-wide configuration shared between all requests.
+#[derive(Debug)]
+struct Config {
+    max_upload_bytes: u64,
+    max_parquet_bytes: usize,
+    max_columns: Option<usize>,
+    max_cell_bytes: Option<usize>,
+    allowed_source_hosts: Vec<String>,
+    request_timeout: Option<Duration>,
+    cors_origins: Vec<String>,
+    conversion_permits: Option<Arc<Semaphore>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    disabled_routes: Vec<(Method, &'static str)>,
+    flush_bytes: usize,
+    buffer_threshold: Option<usize>,
+    max_chunk_bytes: Option<usize>,
+    file_field_name: Option<String>,
+    default_file_name: String,
+    http_client: HttpClient,
+    s3_client: Option<aws_sdk_s3::Client>,
+    query_defaults: Vec<(String, String)>,
+    metrics: Arc<Metrics>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            max_parquet_bytes: DEFAULT_MAX_PARQUET_BYTES,
+            max_columns: None,
+            max_cell_bytes: None,
+            allowed_source_hosts: Vec::new(),
+            request_timeout: None,
+            cors_origins: Vec::new(),
+            conversion_permits: None,
+            rate_limiter: None,
+            disabled_routes: Vec::new(),
+            flush_bytes: 0,
+            buffer_threshold: None,
+            max_chunk_bytes: None,
+            file_field_name: None,
+            default_file_name: DEFAULT_FILE_NAME.to_string(),
+            http_client: build_http_client(),
+            s3_client: None,
+            query_defaults: Vec::new(),
+            metrics: Arc::default(),
+            access_log: None,
+        }
+    }
+}
+
+impl From<&Args> for Config {
+    fn from(args: &Args) -> Self {
+        Config {
+            max_upload_bytes: args.max_upload_bytes,
+            max_parquet_bytes: args.max_parquet_bytes,
+            max_columns: args.max_columns,
+            max_cell_bytes: args.max_cell_bytes,
+            allowed_source_hosts: args.allowed_source_hosts.clone(),
+            request_timeout: args.request_timeout_secs.map(Duration::from_secs),
+            cors_origins: args.cors_origins.clone(),
+            conversion_permits: args
+                .max_concurrent_conversions
+                .map(|max| Arc::new(Semaphore::new(max))),
+            rate_limiter: match (
+                args.max_requests_per_minute_per_ip,
+                args.rate_limit_bytes_per_sec,
+            ) {
+                (None, None) => None,
+                (max_requests, bytes_per_sec) => Some(Arc::new(RateLimiter::new(
+                    RATE_LIMIT_WINDOW,
+                    max_requests,
+                    bytes_per_sec.map(|bytes_per_sec| bytes_per_sec * RATE_LIMIT_WINDOW.as_secs()),
+                ))),
+            },
+            disabled_routes: ROUTES
+                .iter()
+                .filter(|route| {
+                    let enabled = args.enable.is_empty()
+                        || args.enable.iter().any(|selector| selector.matches(route));
+                    !enabled || args.disable.iter().any(|selector| selector.matches(route))
+                })
+                .cloned()
+                .collect(),
+            flush_bytes: args.flush_bytes,
+            buffer_threshold: args.buffer_threshold,
+            max_chunk_bytes: args.max_chunk_bytes,
+            file_field_name: args.file_field_name.clone(),
+            default_file_name: args.default_filename.clone(),
+            http_client: build_http_client(),
+            s3_client: match (
+                &args.s3_region,
+                &args.s3_access_key_id,
+                &args.s3_secret_access_key,
+            ) {
+                (Some(region), Some(access_key_id), Some(secret_access_key)) => {
+                    Some(s3::build_client(
+                        region,
+                        args.s3_endpoint.as_deref(),
+                        access_key_id,
+                        secret_access_key,
+                    ))
+                }
+                _ => None,
+            },
+            query_defaults: args.query_defaults.clone(),
+            metrics: Arc::default(),
+            access_log: args
+                .access_log_format
+                .map(|format| Arc::new(AccessLog::stdout(format))),
+        }
+    }
+}
+
+/// Sets up logging to stdout in the given format. Defaults to logging at the `info` level if the
+/// `RUST_LOG` environment variable isn't set.
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// Resolves once the process is asked to stop, with Ctrl-C (SIGINT) or, on Unix, SIGTERM as sent by
+/// container runtimes.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for Ctrl-C: {}", error);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                error!("failed to listen for SIGTERM: {}", error);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Loads the certificate chain and private key for serving HTTPS from PEM files.
+fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<rustls::ServerConfig> {
+    let read_pem = |path: &Path| {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        rustls_pemfile::read_all(&mut std::io::BufReader::new(file))
+            .with_context(|| format!("failed to read PEM file {}", path.display()))
+    };
+    let certs: Vec<rustls::Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(rustls::Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {}", cert_path.display()));
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {}", key_path.display()))?;
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or private key")?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(tls_config)
+}
+
+/// Routes the request like `route_request`, and writes an access log line once its response has been
+/// sent if access logging is enabled.
+async fn serve_request(
+    config: Arc<Config>,
+    req: Request<Body>,
+) -> Result<Response<AccessLogBody>, hyper::http::Error> {
+    let log = config.access_log.clone().map(|access_log| {
+        let remote_addr = req.extensions().get::<RemoteAddr>().map(|addr| addr.0);
+        (access_log, access_log::Entry::new(&req, remote_addr))
+    });
+    let response = route_request(config, req).await?;
+    let (parts, body) = response.into_parts();
+    let log = log.map(|(access_log, mut entry)| {
+        entry.set_response(parts.status, &parts.headers, X_REQUEST_ID);
+        (access_log, entry)
+    });
+    Ok(Response::from_parts(parts, AccessLogBody::new(body, log)))
+}
+
+/// Address of the client that made a request, which is added to the request's extensions.
+#[derive(Clone, Copy, Debug)]
+struct RemoteAddr(SocketAddr);
+
+/// A connection to a client, whose address is used to rate limit its requests.
+trait Connection {
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    // NOTE: clients on a Unix domain socket don't have an IP address, so they aren't rate limited.
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Listens on a Unix domain socket at the path. A socket file that's already there is removed first
+/// if no server is listening on it anymore, e.g. because the server crashed before it could clean up.
+/// Anything else at the path is left alone, which fails binding.
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    let is_socket =
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Accepts connections on the Unix domain socket listener.
+#[cfg(unix)]
+fn unix_incoming(listener: UnixListener) -> impl Accept<Conn = UnixStream, Error = std::io::Error> {
+    hyper::server::accept::from_stream(async_stream::stream! {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => yield Ok(stream),
+                Err(error) => {
+                    // NOTE: we back off after errors for the same reason as in tls_incoming.
+                    warn!("failed to accept connection: {}", error);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    })
+}
+
+/// Serves requests on the Unix domain socket listener like `serve`, and removes the socket file at
+/// the path once the server has shut down.
+#[cfg(unix)]
+async fn serve_unix_socket(
+    listener: UnixListener,
+    path: &Path,
+    config: Arc<Config>,
+    shutdown: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+) {
+    serve(unix_incoming(listener), config, shutdown, shutdown_timeout).await;
+    if let Err(error) = std::fs::remove_file(path) {
+        warn!("failed to remove socket file {}: {}", path.display(), error);
+    }
+}
+
+/// Accepts TLS connections on the listener. Each handshake happens in its own task so that slow
+/// clients can't hold up accepting other connections. Connections that fail their handshake are
+/// dropped.
+fn tls_incoming(
+    listener: TcpListener,
+    tls_config: Arc<rustls::ServerConfig>,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error> {
+    let acceptor = TlsAcceptor::from(tls_config);
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                // The receiver is dropped along with the server, after which we stop listening.
+                _ = sender.closed() => break,
+                result = listener.accept() => match result {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        // NOTE: like hyper's own listener we back off after errors, since errors such as
+                        //       running out of file descriptors would otherwise immediately happen again.
+                        warn!("failed to accept connection: {}", error);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = sender.send(stream).await;
+                    }
+                    Err(error) => warn!(%remote_addr, "TLS handshake failed: {}", error),
+                }
+            });
+        }
+    });
+    hyper::server::accept::from_stream(async_stream::stream! {
+        while let Some(stream) = receiver.recv().await {
+            yield Ok(stream);
+        }
+    })
+}
+
+/// Serves requests until the shutdown future resolves. After that no new connections are accepted,
+/// but in-flight requests are given up to the shutdown timeout to finish, so that streaming
+/// responses aren't cut off during deploys.
+async fn serve<I>(
+    incoming: I,
+    config: Arc<Config>,
+    shutdown: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+) where
+    I: Accept,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let csv_service = make_service_fn(move |conn: &I::Conn| {
+        let config = config.clone();
+        let remote_addr = conn.remote_addr().map(RemoteAddr);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req| {
+                if let Some(remote_addr) = remote_addr {
+                    req.extensions_mut().insert(remote_addr);
+                }
+                serve_request(config.clone(), req)
+            }))
+        }
+    });
+
+    let server = Server::builder(incoming).serve(csv_service);
+
+    // The server only tells us when it has finished, so we're notified separately when the shutdown
+    // begins to know when to start the timeout.
+    let shutdown_started = Arc::new(tokio::sync::Notify::new());
+    let server = server.with_graceful_shutdown({
+        let shutdown_started = shutdown_started.clone();
+        async move {
+            shutdown.await;
+            info!("shutting down, waiting for in-flight requests to finish");
+            shutdown_started.notify_one();
+        }
+    });
+    let timeout = async {
+        shutdown_started.notified().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        result = server => match result {
+            Ok(()) => info!("shut down"),
+            Err(e) => error!("server error: {}", e),
+        },
+        _ = timeout => warn!(
+            "timed out after {:?} waiting for in-flight requests, shutting down anyway",
+            shutdown_timeout
+        ),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match parse_args(std::env::args_os()) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("error: {:#}", error);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Convert(convert_args)) = args.command {
+        // NOTE: logging isn't set up here since it would be mixed in with the JSON written to stdout.
+        if let Err(error) = convert_file(convert_args).await {
+            eprintln!("error: {:#}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+    init_logging(args.log_format);
+
+    let addr = SocketAddr::from((args.host, args.port));
+
+    let config = Arc::new(Config::from(&args));
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout_secs);
+
+    // The TLS files are loaded before listening so that the server fails fast if they're invalid.
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => match load_tls_config(cert_path, key_path) {
+            Ok(tls_config) => Some(Arc::new(tls_config)),
+            Err(error) => {
+                error!("failed to load TLS certificate: {:#}", error);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    if let Some(path) = &args.unix_socket {
+        #[cfg(unix)]
+        {
+            let listener = match bind_unix_socket(path) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("failed to listen on {}: {}", path.display(), error);
+                    std::process::exit(1);
+                }
+            };
+            info!("listening on {}", path.display());
+            serve_unix_socket(listener, path, config, shutdown_signal(), shutdown_timeout).await;
+            return;
+        }
+        #[cfg(not(unix))]
+        {
+            error!(
+                "can't listen on {}, since Unix domain sockets aren't supported on this platform",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+    }
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("failed to listen on {}: {}", addr, error);
+            std::process::exit(1);
+        }
+    };
+    let local_addr = listener.local_addr().unwrap_or(addr);
+    match tls_config {
+        Some(tls_config) => {
+            info!("listening on {} with TLS", local_addr);
+            serve(
+                tls_incoming(listener, tls_config),
+                config,
+                shutdown_signal(),
+                shutdown_timeout,
+            )
+            .await
+        }
+        None => {
+            info!("listening on {}", local_addr);
+            let incoming =
+                AddrIncoming::from_listener(listener).expect("listener is already bound");
+            serve(incoming, config, shutdown_signal(), shutdown_timeout).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type};
+    use arrow_schema::DataType;
+    use futures::{StreamExt, TryStreamExt};
+    use hyper::body::HttpBody;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use tokio::io::AsyncReadExt;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn build_multipart_request(
+        request: hyper::http::request::Builder,
+        data: impl AsRef<[u8]>,
+    ) -> Request<Body> {
+        build_multipart_request_with_file_name(request, "example.csv", data)
+    }
+
+    fn build_multipart_request_with_file_name(
+        request: hyper::http::request::Builder,
+        file_name: &str,
+        data: impl AsRef<[u8]>,
+    ) -> Request<Body> {
+        let mut body = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"field\"; filename=\"{1}\"\r\n\r\n",
+            BOUNDARY, file_name
+        )
+        .into_bytes();
+        body.extend_from_slice(data.as_ref());
+        body.extend_from_slice(format!("\r\n--{}\r\n", BOUNDARY).as_bytes());
+        request
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Builds a multipart request with a field for each of the given file names and contents, and
+    /// a properly terminated body so that every field can be read.
+    fn build_multipart_request_with_files(
+        request: hyper::http::request::Builder,
+        files: &[(&str, &str)],
+    ) -> Request<Body> {
+        let mut body = String::new();
+        for (index, (file_name, data)) in files.iter().enumerate() {
+            body.push_str(&format!(
+                "--{0}\r\nContent-Disposition: form-data; name=\"field{1}\"; filename=\"{2}\"\r\n\r\n{3}\r\n",
+                BOUNDARY, index, file_name, data
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        request
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        GzipEncoder::new(data).read_to_end(&mut compressed).await?;
+        Ok(compressed)
+    }
+
+    /// Builds a zip archive with an entry for each of the given names and contents.
+    fn build_zip_archive(entries: &[(&str, &str)]) -> Result<Vec<u8>> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            writer.start_file(*name, zip::write::SimpleFileOptions::default())?;
+            std::io::Write::write_all(&mut writer, contents.as_bytes())?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    async fn read_to_string(body: Body) -> String {
+        body.try_fold(String::new(), |output, bytes| async move {
+            let parsed = std::str::from_utf8(&bytes).unwrap();
+            Ok(output + parsed)
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_csv() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "[]");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_upload_with_strict_empty() -> Result<()> {
+        let req = build_multipart_request(Request::builder().uri("/?strict-empty=true"), "");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(
+            res_body,
+            serde_json::json!({"error": "empty upload", "code": "EMPTY_UPLOAD"})
+        );
+
+        // A raw body made up of empty chunks is empty too.
+        let req = Request::builder()
+            .uri("/?strict-empty=true")
+            .header(CONTENT_TYPE, "text/csv")
+            .body(Body::wrap_stream(futures::stream::iter([
+                Ok::<_, std::io::Error>(""),
+                Ok(""),
+            ])))?;
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_nothing_when_only_headers_with_strict_empty() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?strict-empty=true"),
+            "field1,field2,field3",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "[]");
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?strict-empty=true&multiple-files=true"),
+            "field1",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_only_headers_with_require_rows() -> Result<()> {
+        for csv in ["field1,field2,field3", "field1,field2,field3\n\n\n", ""] {
+            let req = build_multipart_request(Request::builder().uri("/?require-rows=true"), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{:?}", csv);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(
+                res_body,
+                serde_json::json!({"error": "no data rows", "code": "NO_DATA_ROWS"})
+            );
+        }
+
+        // The start of the CSV that was read to find a record is still converted.
+        let req = Request::builder()
+            .uri("/?require-rows=true")
+            .header(CONTENT_TYPE, "text/csv")
+            .body(Body::wrap_stream(futures::stream::iter([
+                Ok::<_, std::io::Error>("field1,fie"),
+                Ok("ld2\n1,"),
+                Ok("2\n3,4"),
+            ])))?;
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"}]"#
+        );
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?require-rows=true&multiple-files=true"),
+            "field1\n1",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_nothing_when_only_headers() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "field1,field2,field3");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "[]");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_single_record_for_single_line() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_multiple_records_for_multiple_lines() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3\n4,5,6");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2","field3":"3"},{"field1":"4","field2":"5","field3":"6"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preserves_column_order() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder(),
+            "id,name,email\n1,alice,alice@example.com",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"id":"1","name":"alice","email":"alice@example.com"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_parse_quoted_fields() -> Result<()> {
+        let req =
+            build_multipart_request(Request::builder(), "\"field1\",field2,field3\n1,\"2\",3");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_parse_newslines_in_quoted_fields() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder(),
+            "\"field1\",field2,field3\n1,\"2 &\n 3\",4",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2 &\n 3","field3":"4"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+
+    async fn can_change_delimiter_with_query_param() -> Result<()> {
+        for query in ["delimiter=%09", "delimiter=tab"] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?{}", query)),
+                "field1\tfield2\tfield3\n1\t2\t3",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(
+                &res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#,
+                "{}",
+                query
+            );
+        }
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?delimiter=pipe"),
+            "field1|field2\n1|2",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2"}]"#);
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?delimiter=tabs"),
+            "field1\tfield2\n1\t2",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_detect_delimiter_with_query_param() -> Result<()> {
+        for (csv, expected) in [
+            (
+                "date;price;note\n2022-04-06;1,50;a,b",
+                r#"[{"date":"2022-04-06","price":"1,50","note":"a,b"}]"#,
+            ),
+            (
+                "name\tcity\nalice\tParis; France",
+                r#"[{"name":"alice","city":"Paris; France"}]"#,
+            ),
+            // Delimiters inside quoted headers don't count.
+            ("\"a;b;c\",d,e\n1,2,3", r#"[{"a;b;c":"1","d":"2","e":"3"}]"#),
+            // Ties and single-column files fall back to commas.
+            ("a;b,c\n1;2,3", r#"[{"a;b":"1;2","c":"3"}]"#),
+            ("a\n1", r#"[{"a":"1"}]"#),
+        ] {
+            let req = build_multipart_request(Request::builder().uri("/?delimiter=auto"), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{:?}", csv);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_choose_format_preset_with_query_param() -> Result<()> {
+        for (query, csv, expected) in [
+            (
+                "format=tsv",
+                "field1\tfield2\n1,5\t2;5",
+                r#"[{"field1":"1,5","field2":"2;5"}]"#,
+            ),
+            (
+                "format=ssv",
+                "field1;field2\n1,5;2\t5",
+                r#"[{"field1":"1,5","field2":"2\t5"}]"#,
+            ),
+            (
+                "format=csv",
+                "field1,field2\n\"1;5\",2\t5",
+                r#"[{"field1":"1;5","field2":"2\t5"}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+
+        // An explicit delimiter takes precedence over the preset.
+        let req = build_multipart_request(
+            Request::builder().uri("/?format=tsv&delimiter=%7C"),
+            "field1|field2\n1\t2|3",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1\t2","field2":"3"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+
+    async fn can_change_quote_char_with_query_param() -> Result<()> {
+        for query in ["quote=%27", "quote=single"] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?{}", query)),
+                "field1,'field2','field3'\n1,'2',3",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(
+                &res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#,
+                "{}",
+                query
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+
+    async fn responds_with_content_disposition_header() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3");
+        let res = convert_csv(&Config::default(), req).await?;
+
+        assert_eq!(
+            res.headers().get("content-disposition"),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="example.json"; filename*=UTF-8''example.json"#
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_ndjson_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?output=ndjson"),
+            "field1,field2,field3\n1,2,3\n4,5,6",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static(
+                "application/x-ndjson; charset=utf-8"
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            "{\"field1\":\"1\",\"field2\":\"2\",\"field3\":\"3\"}\n{\"field1\":\"4\",\"field2\":\"5\",\"field3\":\"6\"}\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_choose_output_format_with_accept_header() -> Result<()> {
+        let csv = "a,b\n1,2";
+        let json = r#"[{"a":"1","b":"2"}]"#;
+        let ndjson = "{\"a\":\"1\",\"b\":\"2\"}\n";
+        for (uri, accept, expected_content_type, expected) in [
+            (
+                "/",
+                "application/json",
+                "application/octet-stream; charset=utf-8",
+                json,
+            ),
+            (
+                "/",
+                "application/x-ndjson",
+                "application/x-ndjson; charset=utf-8",
+                ndjson,
+            ),
+            (
+                "/",
+                "application/ndjson; q=0.9, application/json; q=0.5",
+                "application/x-ndjson; charset=utf-8",
+                ndjson,
+            ),
+            (
+                "/",
+                "text/html, application/xhtml+xml, */*; q=0.8",
+                "application/octet-stream; charset=utf-8",
+                json,
+            ),
+            (
+                "/",
+                "application/*; q=0.5, application/x-ndjson",
+                "application/x-ndjson; charset=utf-8",
+                ndjson,
+            ),
+            // The query param overrides the Accept header.
+            (
+                "/?output=json",
+                "application/x-ndjson",
+                "application/octet-stream; charset=utf-8",
+                json,
+            ),
+            (
+                "/?output=ndjson",
+                "text/csv",
+                "application/x-ndjson; charset=utf-8",
+                ndjson,
+            ),
+        ] {
+            let req =
+                build_multipart_request(Request::builder().uri(uri).header(ACCEPT, accept), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", accept);
+            assert_eq!(
+                res.headers().get(CONTENT_TYPE),
+                Some(&HeaderValue::from_static(expected_content_type)),
+                "{}",
+                accept
+            );
+            assert_eq!(
+                read_to_string(res.into_body()).await,
+                expected,
+                "{}",
+                accept
+            );
+        }
+
+        // Without a query param, an Accept header that doesn't accept any of the formats is rejected.
+        for accept in ["text/csv", "application/json; q=0, text/html"] {
+            let req =
+                build_multipart_request(Request::builder().uri("/").header(ACCEPT, accept), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE, "{}", accept);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(res_body["code"], "NOT_ACCEPTABLE");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_unwrap_single_records_with_query_param() -> Result<()> {
+        for (uri, csv, expected) in [
+            ("/?unwrap-single=true", "a,b", "[]"),
+            ("/?unwrap-single=true", "a,b\n1,2", r#"{"a":"1","b":"2"}"#),
+            (
+                "/?unwrap-single=true",
+                "a,b\n1,2\n3,4",
+                r#"[{"a":"1","b":"2"},{"a":"3","b":"4"}]"#,
+            ),
+            (
+                "/?unwrap-single=true",
+                "a,b\n1,2\n3,4\n5,6",
+                r#"[{"a":"1","b":"2"},{"a":"3","b":"4"},{"a":"5","b":"6"}]"#,
+            ),
+            (
+                "/?unwrap-single=true&pretty=true",
+                "a,b\n1,2",
+                "{\n  \"a\": \"1\",\n  \"b\": \"2\"\n}",
+            ),
+            (
+                "/?unwrap-single=true&limit=1",
+                "a,b\n1,2\n3,4",
+                r#"{"a":"1","b":"2"}"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{} {:?}", uri, csv);
+        }
+
+        for uri in [
+            "/?unwrap-single=true&output=ndjson",
+            "/?unwrap-single=true&envelope=true",
+            "/?unwrap-single=true&key-column=a",
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), "a,b\n1,2");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_wrap_records_with_meta_with_query_param() -> Result<()> {
+        for (uri, csv, expected_data, expected_records) in [
+            (
+                "/?meta=true",
+                "a,b\n1,2\n3,4",
+                serde_json::json!([{"a":"1","b":"2"},{"a":"3","b":"4"}]),
+                2,
+            ),
+            ("/?meta=true", "a,b", serde_json::json!([]), 0),
+            (
+                "/?meta=true&limit=1&pretty=true",
+                "a,b\n1,2\n3,4",
+                serde_json::json!([{"a":"1","b":"2"}]),
+                1,
+            ),
+            (
+                "/?meta=true&key-column=a",
+                "a,b\n1,2",
+                serde_json::json!({"1":{"b":"2"}}),
+                1,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+            let res_body = read_to_string(res.into_body()).await;
+            let wrapped: Value = serde_json::from_str(&res_body)?;
+            let keys: Vec<_> = wrapped.as_object().unwrap().keys().collect();
+            assert_eq!(keys, ["meta", "data", "records"], "{}", uri);
+            assert_eq!(wrapped["meta"]["filename"], "example.csv", "{}", uri);
+            let converted_at = wrapped["meta"]["converted_at"].as_str().unwrap();
+            chrono::DateTime::parse_from_rfc3339(converted_at)?;
+            assert_eq!(wrapped["data"], expected_data, "{}", uri);
+            assert_eq!(wrapped["records"], expected_records, "{}", uri);
+        }
+
+        let req =
+            build_multipart_request(Request::builder().uri("/?meta=true&pretty=true"), "a\n1");
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert!(
+            res_body.ends_with(
+                "\"data\": [\n    {\n      \"a\": \"1\"\n    }\n  ],\n  \"records\": 1\n}"
+            ),
+            "{}",
+            res_body
+        );
+
+        for uri in [
+            "/?meta=true&output=ndjson",
+            "/?meta=true&envelope=true",
+            "/?meta=true&unwrap-single=true",
+            "/?meta=true&with-schema=true",
+            "/?meta=true&multiple-files=true",
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), "a,b\n1,2");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_choose_data_key_with_query_param() -> Result<()> {
+        for (uri, expected) in [
+            (
+                "/?data-key=results",
+                r#"{"results":[{"a":"1","b":"2"}]}"#.to_string(),
+            ),
+            (
+                "/?data-key=r%C3%A9sultats%20%22x%22",
+                r#"{"résultats \"x\"":[{"a":"1","b":"2"}]}"#.to_string(),
+            ),
+            (
+                "/?data-key=results&key-column=a",
+                r#"{"results":{"1":{"b":"2"}}}"#.to_string(),
+            ),
+            (
+                "/?data-key=results&pretty=true",
+                "{\n  \"results\": [\n    {\n      \"a\": \"1\",\n      \"b\": \"2\"\n    }\n  ]\n}"
+                    .to_string(),
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), "a,b\n1,2");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(read_to_string(res.into_body()).await, expected, "{}", uri);
+        }
+
+        for (uri, expected_keys) in [
+            (
+                "/?data-key=results&meta=true",
+                ["meta", "results", "records"],
+            ),
+            (
+                "/?data-key=results&with-schema=true",
+                ["schema", "results", ""],
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), "a,b\n1,2");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+            let wrapped: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            let keys: Vec<_> = wrapped.as_object().unwrap().keys().cloned().collect();
+            let expected_keys: Vec<_> = expected_keys
+                .into_iter()
+                .filter(|key| !key.is_empty())
+                .collect();
+            assert_eq!(keys, expected_keys, "{}", uri);
+            assert_eq!(
+                wrapped["results"],
+                serde_json::json!([{"a":"1","b":"2"}]),
+                "{}",
+                uri
+            );
+        }
+
+        let long_key = "k".repeat(MAX_DATA_KEY_LENGTH + 1);
+        for uri in [
+            "/?data-key=".to_string(),
+            format!("/?data-key={}", long_key),
+            "/?data-key=a%0Ab".to_string(),
+            "/?data-key=results&output=ndjson".to_string(),
+            "/?data-key=results&envelope=true".to_string(),
+            "/?data-key=results&unwrap-single=true".to_string(),
+            "/?data-key=results&multiple-files=true".to_string(),
+            "/?data-key=records&meta=true".to_string(),
+            "/?data-key=schema&with-schema=true".to_string(),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(&uri), "a,b\n1,2");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_start_output_with_bom_with_query_param() -> Result<()> {
+        const BOM: &[u8] = b"\xEF\xBB\xBF";
+        let csv = "a,b\n1,2\n3,4";
+        let config = Config {
+            flush_bytes: 1,
+            ..Config::default()
+        };
+        for (uri, expected) in [
+            ("/?bom=true", r#"[{"a":"1","b":"2"},{"a":"3","b":"4"}]"#),
+            (
+                "/?bom=true&output=ndjson",
+                "{\"a\":\"1\",\"b\":\"2\"}\n{\"a\":\"3\",\"b\":\"4\"}\n",
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), csv);
+            let res = convert_csv(&config, req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+            let res_body = hyper::body::to_bytes(res.into_body()).await?;
+            assert_eq!(&res_body[..3], BOM, "{}", uri);
+            assert_eq!(std::str::from_utf8(&res_body[3..])?, expected);
+        }
+
+        // The BOM is compressed along with the rest, and only starts the combined object of multiple files.
+        let req = build_multipart_request_with_files(
+            Request::builder()
+                .uri("/?bom=true&multiple-files=true")
+                .header(ACCEPT_ENCODING, "gzip"),
+            &[("a.csv", "a\n1"), ("b.csv", "b\n2")],
+        );
+        let res = convert_csv(&config, req).await?;
+        let compressed = hyper::body::to_bytes(res.into_body()).await?;
+        let mut res_body = Vec::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_end(&mut res_body)
+            .await?;
+        assert_eq!(&res_body[..3], BOM);
+        assert_eq!(
+            std::str::from_utf8(&res_body[3..])?,
+            r#"{"a.csv":[{"a":"1"}],"b.csv":[{"b":"2"}]}"#
+        );
+
+        let req = build_multipart_request(Request::builder().uri("/?bom=true&output=msgpack"), csv);
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_msgpack_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?output=msgpack&infer-types=true"),
+            "name,age\nalice,30\nbob,",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("application/msgpack"))
+        );
+        let res_body = hyper::body::to_bytes(res.into_body()).await?;
+        let mut deserializer = rmp_serde::Deserializer::new(&res_body[..]);
+        let mut records = Vec::new();
+        while !deserializer.get_ref().is_empty() {
+            records.push(Value::deserialize(&mut deserializer)?);
+        }
+        assert_eq!(
+            records,
+            vec![
+                serde_json::json!({"name": "alice", "age": 30}),
+                serde_json::json!({"name": "bob", "age": null}),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_server_sent_events_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?output=sse"),
+            "field1,field2\n1,2\n3,4",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static(
+                "text/event-stream; charset=utf-8"
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            concat!(
+                "event: record\ndata: {\"field1\":\"1\",\"field2\":\"2\"}\n\n",
+                "event: record\ndata: {\"field1\":\"3\",\"field2\":\"4\"}\n\n",
+                "event: done\ndata: {\"records\":2}\n\n",
+            )
+        );
+
+        // Errors are reported in an event instead of the done event.
+        let req = build_multipart_request(Request::builder().uri("/?output=sse"), "a\n1\n2,3");
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            concat!(
+                "event: record\ndata: {\"a\":\"1\"}\n\n",
+                "event: error\ndata: {\"error\":\"failed to read from input stream: line 3: record has 2 fields, but there are 1 columns\"}\n\n",
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accumulates_records_until_flush_bytes() -> Result<()> {
+        let config = Config {
+            flush_bytes: 20,
+            ..Config::default()
+        };
+        let req = build_multipart_request(Request::builder(), "a\n1\n2\n3");
+        let res = convert_csv(&config, req).await?;
+        let chunks = res.into_body().try_collect::<Vec<_>>().await?;
+        assert_eq!(chunks, [r#"[{"a":"1"},{"a":"2"}"#, r#",{"a":"3"}]"#]);
+
+        // Records that were accumulated before an error are still sent.
+        let req =
+            build_multipart_request(Request::builder().uri("/?output=ndjson"), "a\n1\n2\n3,4");
+        let res = convert_csv(&config, req).await?;
+        let mut body = res.into_body();
+        assert_eq!(
+            body.try_next().await?,
+            Some(Bytes::from("{\"a\":\"1\"}\n{\"a\":\"2\"}\n"))
+        );
+        assert!(body.try_next().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn splits_large_records_into_chunks_of_max_chunk_bytes() -> Result<()> {
+        let config = Config {
+            max_chunk_bytes: Some(4096),
+            ..Config::default()
+        };
+        let large_cell = "x".repeat(100_000);
+        let req =
+            build_multipart_request(Request::builder(), format!("a,b\n1,{}\n2,3", large_cell));
+        let res = convert_csv(&config, req).await?;
+        let chunks = res.into_body().try_collect::<Vec<_>>().await?;
+        assert!(chunks.len() > 100_000 / 4096, "{}", chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 4096));
+        let res_body = chunks.concat();
+        assert_eq!(
+            std::str::from_utf8(&res_body)?,
+            format!(r#"[{{"a":"1","b":"{}"}},{{"a":"2","b":"3"}}]"#, large_cell)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_csv_as_ndjson_is_empty() -> Result<()> {
+        let req = build_multipart_request(Request::builder().uri("/?output=ndjson"), "");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_infer_types_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?infer-types=true"),
+            "int,neg,float,exp,bool,empty,zero,padded,plus,text\n42,-7,1.5,1e10,TRUE,,0,007,+1,hello",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"int":42,"neg":-7,"float":1.5,"exp":10000000000.0,"bool":true,"empty":null,"zero":0,"padded":"007","plus":"+1","text":"hello"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_choose_big_number_representation_with_query_param() -> Result<()> {
+        let csv = "big,precise,small\n-9999999999999999999,3.14159265358979323846,1.5";
+        for (query, expected) in [
+            (
+                "",
+                r#"[{"big":-1e+19,"precise":3.141592653589793,"small":1.5}]"#,
+            ),
+            (
+                "big-number=float",
+                r#"[{"big":-1e+19,"precise":3.141592653589793,"small":1.5}]"#,
+            ),
+            (
+                "big-number=string",
+                r#"[{"big":"-9999999999999999999","precise":"3.14159265358979323846","small":1.5}]"#,
+            ),
+            (
+                "big-number=i128",
+                r#"[{"big":-9999999999999999999,"precise":3.141592653589793,"small":1.5}]"#,
+            ),
+            (
+                "big-number=i128&nest=true",
+                r#"[{"big":-9999999999999999999,"precise":3.141592653589793,"small":1.5}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?infer-types=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?infer-types=true&big-number=i128&output=msgpack"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn does_not_infer_types_by_default() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "int,bool,empty\n42,true,");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"int":"42","bool":"true","empty":""}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_empty_fields_as_null_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?empty-as-null=true"),
+            "field1,field2,field3\n1,,\"\"\n,,",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":null,"field3":null},{"field1":null,"field2":null,"field3":null}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_normalize_newlines_with_query_param() -> Result<()> {
+        let csv = "id,note\r\n1,\"first\r\nsecond\rthird\nfourth\"\r\n";
+        let req = build_multipart_request(Request::builder(), csv);
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"id":"1","note":"first\r\nsecond\rthird\nfourth"}]"#
+        );
+
+        let req = build_multipart_request(Request::builder().uri("/?normalize-newlines=true"), csv);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"id":"1","note":"first\nsecond\nthird\nfourth"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_null_tokens_as_null_with_query_param() -> Result<()> {
+        let csv = "a,b,c,d\nNA,NULL,-,na\n1,null,x,-";
+        for (query, expected) in [
+            (
+                "null-tokens=NA,NULL,-",
+                r#"[{"a":null,"b":null,"c":null,"d":"na"},{"a":"1","b":"null","c":"x","d":null}]"#,
+            ),
+            (
+                "null-tokens=NA,NULL&null-tokens-ignore-case=true",
+                r#"[{"a":null,"b":null,"c":"-","d":null},{"a":"1","b":null,"c":"x","d":"-"}]"#,
+            ),
+            (
+                "null-tokens=NA,-&infer-types=true",
+                r#"[{"a":null,"b":"NULL","c":null,"d":"na"},{"a":1,"b":"null","c":"x","d":null}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_set_escape_char_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?escape=%5C"),
+            r#"a,b
+1,"he said \"hi\"""#,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"a":"1","b":"he said \"hi\""}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_disable_headers_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?has-headers=false"),
+            "1,2,3\n4,5,6",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"0":"1","1":"2","2":"3"},{"0":"4","1":"5","2":"6"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_name_columns_with_query_param() -> Result<()> {
+        for (query, csv, expected) in [
+            (
+                "has-headers=false&headers=id,name,email",
+                "1,Ann,ann@example.com\n2,Bob,bob@example.com",
+                r#"[{"id":"1","name":"Ann","email":"ann@example.com"},{"id":"2","name":"Bob","email":"bob@example.com"}]"#,
+            ),
+            // Columns without a name are named by their index.
+            (
+                "has-headers=false&headers=id,name",
+                "1,Ann,ann@example.com",
+                r#"[{"id":"1","name":"Ann","2":"ann@example.com"}]"#,
+            ),
+            (
+                "has-headers=false&headers=id,name,email&ragged=pad",
+                "1,Ann",
+                r#"[{"id":"1","name":"Ann","email":""}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+
+        let req = build_multipart_request(Request::builder().uri("/?headers=id,name"), "1,Ann");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_raw_rows_with_query_param() -> Result<()> {
+        for (query, expected) in [
+            (
+                "raw-rows=true&has-headers=false",
+                r#"[["a","b","c"],["1","2","3"],["4","5","6"]]"#,
+            ),
+            ("raw-rows=true", r#"[["1","2","3"],["4","5","6"]]"#),
+            (
+                "raw-rows=true&exclude=b&infer-types=true",
+                r#"[[1,3],[4,6]]"#,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?{}", query)),
+                "a,b,c\n1,2,3\n4,5,6",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?raw-rows=true&key-column=a"),
+            "a,b,c\n1,2,3",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_add_row_numbers_with_query_param() -> Result<()> {
+        let csv = "a,b.c\n1,2\n3,4\n5,6";
+        for (query, expected) in [
+            (
+                "add-row-number=row",
+                r#"[{"row":1,"a":"1","b.c":"2"},{"row":2,"a":"3","b.c":"4"},{"row":3,"a":"5","b.c":"6"}]"#,
+            ),
+            (
+                "add-row-number=row&nest=true&exclude=a",
+                r#"[{"row":1,"b":{"c":"2"}},{"row":2,"b":{"c":"4"}},{"row":3,"b":{"c":"6"}}]"#,
+            ),
+            (
+                "add-row-number=a&exclude=a&key-column=a",
+                r#"{"1":{"a":1,"b.c":"2"},"3":{"a":2,"b.c":"4"},"5":{"a":3,"b.c":"6"}}"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+
+        for query in ["add-row-number=a", "add-row-number=b&nest=true"] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?{}&envelope=true", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let envelope: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(envelope["records"], serde_json::json!([]), "{}", query);
+            assert!(
+                envelope["error"]
+                    .as_str()
+                    .unwrap()
+                    .contains("since the CSV has a column of that name"),
+                "{}: {}",
+                query,
+                envelope["error"]
+            );
+        }
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?add-row-number=row&raw-rows=true"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validates_csv_without_converting_it() -> Result<()> {
+        let validate = |query: &str, csv: &str| {
+            let req = build_multipart_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/validate?{}", query)),
+                csv,
+            );
+            async move {
+                let res = route_request(Arc::new(Config::default()), req).await?;
+                assert_eq!(res.status(), StatusCode::OK);
+                Ok::<Value, anyhow::Error>(serde_json::from_str(
+                    &read_to_string(res.into_body()).await,
+                )?)
+            }
+        };
+
+        assert_eq!(
+            validate("", "a,b\n1,2\n3,4\n5,6").await?,
+            serde_json::json!({"records": 3, "columns": ["a", "b"], "delimiter": ",", "ragged_rows": 0, "error": null})
+        );
+        assert_eq!(
+            validate("ragged=pad", "a,b\n1,2\n3\n5\n8,9").await?,
+            serde_json::json!({"records": 4, "columns": ["a", "b"], "delimiter": ",", "ragged_rows": 2, "error": null})
+        );
+        assert_eq!(
+            validate("has-headers=false", "1,2,3").await?,
+            serde_json::json!({"records": 1, "columns": ["0", "1", "2"], "delimiter": ",", "ragged_rows": 0, "error": null})
+        );
+        assert_eq!(
+            validate("", "a,b\n1,2\n3\n5,6").await?,
+            serde_json::json!({
+                "records": 1,
+                "columns": ["a", "b"],
+                "delimiter": ",",
+                "ragged_rows": 0,
+                "error": "line 3: record has 1 fields, but there are 2 columns",
+            })
+        );
+        assert_eq!(
+            validate("delimiter=auto", "a|b\n1|2").await?["delimiter"],
+            "|"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_schema_only_with_query_param() -> Result<()> {
+        let schema = |query: &str, csv: &str| {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?schema-only=true&{}", query)),
+                csv,
+            );
+            async move {
+                let res = convert_csv(&Config::default(), req).await?;
+                let status = res.status();
+                let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+                Ok::<_, anyhow::Error>((status, res_body))
+            }
+        };
+
+        let csv =
+            "id,price,active,name,note,mixed\n1,9.5,true,alice,,1\n2,10,FALSE,bob,,x\n3,,,carol,,2";
+        assert_eq!(
+            schema("", csv).await?,
+            (
+                StatusCode::OK,
+                serde_json::json!({"columns": [
+                    {"name": "id", "type": "integer"},
+                    {"name": "price", "type": "number"},
+                    {"name": "active", "type": "boolean"},
+                    {"name": "name", "type": "string"},
+                    {"name": "note", "type": "string"},
+                    {"name": "mixed", "type": "string"},
+                ]})
+            )
+        );
+        // Only the sampled records are read, so later records can't change the types or fail.
+        assert_eq!(
+            schema(
+                "schema-sample-rows=2&include=id,mixed",
+                "id,mixed\n1,1\n2,2\n3,x\n4"
+            )
+            .await?,
+            (
+                StatusCode::OK,
+                serde_json::json!({"columns": [
+                    {"name": "id", "type": "integer"},
+                    {"name": "mixed", "type": "integer"},
+                ]})
+            )
+        );
+        assert_eq!(
+            schema("has-headers=false&schema-sample-rows=0", "1,2").await?,
+            (StatusCode::OK, serde_json::json!({"columns": []}))
+        );
+        let (status, res_body) = schema("", "a,b\n1").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res_body["error"],
+            "failed to read the CSV's schema: line 2: record has 1 fields, but there are 2 columns"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_output_json_schema_with_records_with_query_param() -> Result<()> {
+        let csv = "id,price,active,name\n1,9.5,true,alice\n2,10,,bob\n3,11,false,carol";
+        let req = build_multipart_request(
+            Request::builder().uri("/?with-schema=true&infer-types=true&schema-sample-rows=2"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        let items = &body["schema"]["items"];
+        assert_eq!(
+            items["properties"],
+            serde_json::json!({
+                "id": {"type": ["integer", "null"]},
+                "price": {"type": ["number", "null"]},
+                "active": {"type": ["boolean", "null"]},
+                "name": {"type": ["string", "null"]},
+            })
+        );
+        assert_eq!(
+            items["required"],
+            serde_json::json!(["id", "price", "active", "name"])
+        );
+        // Every record is converted, including the ones that were read for the schema.
+        assert_eq!(
+            body["data"],
+            serde_json::json!([
+                {"id": 1, "price": 9.5, "active": true, "name": "alice"},
+                {"id": 2, "price": 10, "active": null, "name": "bob"},
+                {"id": 3, "price": 11, "active": false, "name": "carol"},
+            ])
+        );
+
+        // Without type inference every field is a string, and pretty printing indents the schema.
+        let req = build_multipart_request(
+            Request::builder().uri("/?with-schema=true&pretty=true&include=id"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(
+            read_to_string(res.into_body()).await,
+            r#"{
+  "schema": {
+    "$schema": "https://json-schema.org/draft/2020-12/schema",
+    "type": "array",
+    "items": {
+      "type": "object",
+      "properties": {
+        "id": {
+          "type": "string"
+        }
+      },
+      "required": [
+        "id"
+      ],
+      "additionalProperties": false
+    }
+  },
+  "data": [
+    {
+      "id": "1"
+    },
+    {
+      "id": "2"
+    },
+    {
+      "id": "3"
+    }
+  ]
+}"#
+        );
+
+        for query in [
+            "with-schema=true&schema-only=true",
+            "with-schema=true&output=ndjson",
+            "with-schema=true&envelope=true",
+            "with-schema=true&raw-rows=true",
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_json_to_csv() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().method(Method::POST).uri("/to-csv"),
+            r#"[{"a":"1","b":2,"c":true},{"a":"x,y","c":null}]"#,
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get("content-disposition"),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="example.csv"; filename*=UTF-8''example.csv"#
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "a,b,c\n1,2,true\n\"x,y\",,\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converting_json_to_csv_preserves_key_order() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().method(Method::POST).uri("/to-csv"),
+            r#"[{"id":1,"name":"alice","email":"alice@example.com"}]"#,
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "id,name,email\n1,alice,alice@example.com\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converting_empty_json_array_to_csv_is_empty() -> Result<()> {
+        let req =
+            build_multipart_request(Request::builder().method(Method::POST).uri("/to-csv"), "[]");
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converting_json_to_csv_stops_at_unknown_field() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().method(Method::POST).uri("/to-csv"),
+            r#"[{"a":"1"},{"a":"2","b":"3"}]"#,
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let chunks: Vec<_> = StreamExt::collect(res.into_body()).await;
+        assert_eq!(std::str::from_utf8(chunks[0].as_ref().unwrap())?, "a\n");
+        assert!(chunks.last().unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_json_lines_to_csv() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/to-csv?input=jsonl"),
+            "{\"a\":\"1\",\"b\":2}\n\n{\"a\":\"x,y\"}\n",
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "a,b\n1,2\n\"x,y\",\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converting_json_lines_to_csv_stops_at_non_object_line() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/to-csv?input=jsonl"),
+            "{\"a\":\"1\"}\n\"2\"\n",
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let chunks: Vec<_> = StreamExt::collect(res.into_body()).await;
+        assert_eq!(std::str::from_utf8(chunks[0].as_ref().unwrap())?, "a\n");
+        assert!(chunks.last().unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_csv_to_parquet() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().method(Method::POST).uri("/to-parquet"),
+            "id,price,active,name\n1,9.5,true,apple\n2,,false,\n3,12,TRUE,cherry",
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE).unwrap(),
+            "application/vnd.apache.parquet"
+        );
+        assert_eq!(
+            res.headers().get("content-disposition"),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="example.parquet"; filename*=UTF-8''example.parquet"#
+            ))
+        );
+        let parquet = hyper::body::to_bytes(res.into_body()).await?;
+        let batches = ParquetRecordBatchReaderBuilder::try_new(parquet)?
+            .build()?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let types: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| (field.name().clone(), field.data_type().clone()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("id".to_string(), DataType::Int64),
+                ("price".to_string(), DataType::Float64),
+                ("active".to_string(), DataType::Boolean),
+                ("name".to_string(), DataType::Utf8),
+            ]
+        );
+        assert_eq!(
+            batch
+                .column(0)
+                .as_primitive::<Int64Type>()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(3)]
+        );
+        assert_eq!(
+            batch
+                .column(1)
+                .as_primitive::<Float64Type>()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![Some(9.5), None, Some(12.0)]
+        );
+        assert_eq!(
+            batch.column(2).as_boolean().iter().collect::<Vec<_>>(),
+            vec![Some(true), Some(false), Some(true)]
+        );
+        assert_eq!(
+            batch
+                .column(3)
+                .as_string::<i32>()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![Some("apple"), Some(""), Some("cherry")]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converting_csv_to_parquet_fails_at_field_of_another_type() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/to-parquet?schema-sample-rows=1"),
+            "a,b\n1,x\n2.5,y",
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(res_body["code"], "CONVERSION_FAILED");
+        assert_eq!(
+            res_body["error"],
+            "field \"2.5\" of column \"a\" in record 2 doesn't fit the column's integer type"
+        );
+
+        // With types the column has that type, whatever the sampled records are.
+        let req = build_multipart_request(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/to-parquet?schema-sample-rows=1&types=a:float"),
+            "a,b\n1,x\n2.5,y",
+        );
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_raw_csv_body() -> Result<()> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+            .body(Body::from("field1,field2\n1,2"))?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="download.json"; filename*=UTF-8''download.json"#
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn names_raw_csv_body_after_file_name_headers() -> Result<()> {
+        for (content_type, headers, expected_file_name) in [
+            ("application/csv", vec![], "download.json"),
+            (
+                "application/csv; charset=utf-8",
+                vec![(CONTENT_DISPOSITION, "attachment; filename=\"birds.csv\"")],
+                "birds.json",
+            ),
+            (
+                "text/csv",
+                vec![(CONTENT_DISPOSITION, "inline; filename=birds.csv; size=17")],
+                "birds.json",
+            ),
+            (
+                "text/csv",
+                vec![(
+                    CONTENT_DISPOSITION,
+                    "attachment; filename=\"b;i\\\"rds.csv\"",
+                )],
+                "b;irds.json",
+            ),
+            (
+                "text/csv",
+                vec![(
+                    CONTENT_DISPOSITION,
+                    "attachment; filename=\"oiseaux.csv\"; filename*=UTF-8''%C3%A9t%C3%A9.csv",
+                )],
+                "été.json",
+            ),
+            (
+                "text/csv",
+                vec![(
+                    CONTENT_DISPOSITION,
+                    "attachment; filename=\"../../etc/passwd\"",
+                )],
+                "passwd.json",
+            ),
+            (
+                "text/csv",
+                vec![(HeaderName::from_static(X_FILENAME), "birds.csv")],
+                "birds.json",
+            ),
+            (
+                "text/csv",
+                vec![
+                    (HeaderName::from_static(X_FILENAME), "other.csv"),
+                    (CONTENT_DISPOSITION, "attachment; filename=birds.csv"),
+                ],
+                "birds.json",
+            ),
+            (
+                "text/csv",
+                vec![(CONTENT_DISPOSITION, "attachment")],
+                "download.json",
+            ),
+        ] {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .header(CONTENT_TYPE, content_type);
+            for (name, value) in &headers {
+                builder = builder.header(name, *value);
+            }
+            let req = builder.body(Body::from("field1,field2\n1,2"))?;
+            let res = route_request(Arc::new(Config::default()), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{:?}", headers);
+            assert_eq!(
+                res.headers().get(CONTENT_DISPOSITION).unwrap(),
+                &content_disposition(expected_file_name),
+                "{:?}",
+                headers
+            );
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, r#"[{"field1":"1","field2":"2"}]"#);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_raw_json_body_to_csv() -> Result<()> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/to-csv")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"[{"a":"1"}]"#))?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "a\n1\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_content_types() -> Result<()> {
+        for content_type in [Some("application/json"), Some("text/plain"), None] {
+            let mut builder = Request::builder().method(Method::POST);
+            if let Some(content_type) = content_type {
+                builder = builder.header(CONTENT_TYPE, content_type);
+            }
+            let req = builder.body(Body::from("field1,field2\n1,2"))?;
+            let res = route_request(Arc::new(Config::default()), req).await?;
+            assert_eq!(
+                res.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{:?}",
+                content_type
+            );
+            let res_body = read_to_string(res.into_body()).await;
+            assert!(res_body.contains("multipart/form-data"), "{}", res_body);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_size_of_raw_csv_body() -> Result<()> {
+        let config = Config {
+            max_upload_bytes: 16,
+            ..Config::default()
+        };
+        let (mut sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, "text/csv")
+            .body(body)?;
+        tokio::spawn(async move {
+            for _ in 0..8 {
+                if sender.send_data(Bytes::from("a,b\n1,2\n")).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let chunks: Vec<_> = StreamExt::collect(res.into_body()).await;
+        assert!(chunks.last().unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_to_cors_preflight_requests() -> Result<()> {
+        let config = Arc::new(Config {
+            cors_origins: vec!["https://app.example.com".to_string()],
+            ..Config::default()
+        });
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header("origin", "https://app.example.com")
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "x-request-id")
+            .body(Body::empty())?;
+        let res = route_request(config.clone(), req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers.get("access-control-allow-origin"),
+            Some(&HeaderValue::from_static("https://app.example.com"))
+        );
+        assert_eq!(
+            headers.get("access-control-allow-methods"),
+            Some(&HeaderValue::from_static("GET, POST, OPTIONS"))
+        );
+        assert_eq!(
+            headers.get("access-control-allow-headers"),
+            Some(&HeaderValue::from_static("x-request-id"))
+        );
+        assert_eq!(
+            headers.get("vary"),
+            Some(&HeaderValue::from_static("origin"))
+        );
+
+        // Preflight requests are only answered when CORS is enabled.
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header("origin", "https://app.example.com")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers().get("access-control-allow-origin"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adds_cors_headers_for_allowed_origins() -> Result<()> {
+        let config = Arc::new(Config {
+            cors_origins: vec!["https://app.example.com".to_string()],
+            ..Config::default()
+        });
+        for (origin, allowed) in [
+            ("https://app.example.com", true),
+            ("https://evil.example.com", false),
+        ] {
+            let req = build_multipart_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .header("origin", origin),
+                "a\n1",
+            );
+            let res = route_request(config.clone(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let headers = res.headers();
+            assert_eq!(
+                headers.get("access-control-allow-origin"),
+                allowed
+                    .then(|| HeaderValue::from_static("https://app.example.com"))
+                    .as_ref()
+            );
+            assert_eq!(
+                headers.get("access-control-expose-headers").is_some(),
+                allowed
+            );
+            assert_eq!(
+                headers.get_all("vary").iter().collect::<Vec<_>>(),
+                if allowed {
+                    vec!["accept", "accept-encoding", "origin"]
+                } else {
+                    vec!["accept", "accept-encoding"]
+                }
+            );
+        }
+
+        let config = Arc::new(Config {
+            cors_origins: vec!["*".to_string()],
+            ..Config::default()
+        });
+        let req = Request::builder()
+            .uri("/health")
+            .header("origin", "https://anywhere.example.com")
+            .body(Body::empty())?;
+        let res = route_request(config, req).await?;
+        assert_eq!(
+            res.headers().get("access-control-allow-origin"),
+            Some(&HeaderValue::from_static("*"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn echoes_request_id() -> Result<()> {
+        let req = Request::builder()
+            .uri("/health")
+            .header("x-request-id", "client-request-1")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(
+            res.headers().get("x-request-id"),
+            Some(&HeaderValue::from_static("client-request-1"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uses_trace_id_from_traceparent_as_request_id() -> Result<()> {
+        let req = Request::builder()
+            .uri("/health")
+            .header(
+                "traceparent",
+                "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(
+            res.headers().get("x-request-id"),
+            Some(&HeaderValue::from_static(
+                "4bf92f3577b34da6a3ce929d0e0e4736"
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_request_id() -> Result<()> {
+        for header in [None, Some(""), Some(&*"x".repeat(200))] {
+            let mut builder = Request::builder().uri("/does-not-exist");
+            if let Some(header) = header {
+                builder = builder.header("x-request-id", header);
+            }
+            let res =
+                route_request(Arc::new(Config::default()), builder.body(Body::empty())?).await?;
+            let request_id = res.headers().get("x-request-id").unwrap().to_str()?;
+            assert!(Uuid::parse_str(request_id).is_ok(), "{}", request_id);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decompresses_gzip_encoded_uploads() -> Result<()> {
+        let csv = "field1,field2\n1,2\n3,4";
+        let expected = r#"[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"}]"#;
+
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "text/csv")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzip(csv.as_bytes()).await?))?;
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(&read_to_string(res.into_body()).await, expected);
+
+        // The whole multipart body is compressed, not just the file.
+        let req = build_multipart_request(Request::builder(), csv);
+        let (parts, body) = req.into_parts();
+        let body = gzip(&hyper::body::to_bytes(body).await?).await?;
+        let mut req = Request::from_parts(parts, Body::from(body));
+        req.headers_mut().remove(CONTENT_LENGTH);
+        req.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(&read_to_string(res.into_body()).await, expected);
+
+        // The size limit applies to the decompressed upload.
+        let config = Config {
+            max_upload_bytes: 1000,
+            ..Config::default()
+        };
+        let large_csv = format!("a\n{}", "1\n".repeat(1000));
+        let req = Request::builder()
+            .uri("/?buffered=true")
+            .header(CONTENT_TYPE, "text/csv")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzip(large_csv.as_bytes()).await?))?;
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body = read_to_string(res.into_body()).await;
+        assert!(
+            res_body.contains("input exceeded the size limit of 1000 bytes"),
+            "{}",
+            res_body
+        );
+
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "text/csv")
+            .header(CONTENT_ENCODING, "br")
+            .body(Body::from(csv))?;
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn converts_first_csv_in_zip_upload() -> Result<()> {
+        let archive = build_zip_archive(&[
+            ("README.txt", "not a CSV"),
+            ("data/birds.csv", "name,wings\nsparrow,2"),
+            ("other.csv", "a\n1"),
+        ])?;
+        let expected = r#"[{"name":"sparrow","wings":"2"}]"#;
+        let req =
+            build_multipart_request_with_file_name(Request::builder(), "birds.csv.zip", &archive);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="birds.json"; filename*=UTF-8''birds.json"#
+            ))
+        );
+        assert_eq!(&read_to_string(res.into_body()).await, expected);
+
+        // Without a .zip file name the archive is recognized by its magic bytes.
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "application/zip")
+            .body(Body::from(archive))?;
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(&read_to_string(res.into_body()).await, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_zip_uploads() -> Result<()> {
+        let config = Config {
+            max_upload_bytes: 1000,
+            ..Config::default()
+        };
+        let large_csv = format!("a\n{}", "1\n".repeat(1000));
+        for (archive, expected_status, expected_error) in [
+            (
+                build_zip_archive(&[("README.txt", "not a CSV")])?,
+                StatusCode::BAD_REQUEST,
+                "zip archive has no .csv entry",
+            ),
+            (
+                b"PK\x03\x04 truncated".to_vec(),
+                StatusCode::BAD_REQUEST,
+                "invalid zip archive: ",
+            ),
+            // The archive is small enough, but the extracted CSV isn't.
+            (
+                build_zip_archive(&[("large.csv", &large_csv)])?,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "large.csv in zip archive exceeds the maximum upload size of 1000 bytes",
+            ),
+        ] {
+            let req =
+                build_multipart_request_with_file_name(Request::builder(), "upload.zip", &archive);
+            let res = convert_csv(&config, req).await?;
+            assert_eq!(res.status(), expected_status);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert!(
+                res_body["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with(expected_error),
+                "{}",
+                res_body
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_multiple_files_with_query_param() -> Result<()> {
+        let req = build_multipart_request_with_files(
+            Request::builder().uri("/?multiple-files=true"),
+            &[
+                ("birds.csv", "a,b\n1,2\n3,4"),
+                ("empty.csv", ""),
+                ("birds.csv", "c\n5"),
+            ],
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="download.json"; filename*=UTF-8''download.json"#
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"birds.csv":[{"a":"1","b":"2"},{"a":"3","b":"4"}],"empty.csv":[],"birds.csv (2)":[{"c":"5"}]}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_multiple_files_in_envelopes() -> Result<()> {
+        let req = build_multipart_request_with_files(
+            Request::builder().uri("/?multiple-files=true&envelope=true"),
+            &[("a.csv", "a\n1"), ("b.csv", "b\n2")],
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"a.csv":{"records":[{"a":"1"}],"error":null},"b.csv":{"records":[{"b":"2"}],"error":null}}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_multiple_files_without_file_fields() -> Result<()> {
+        let req = build_multipart_request_with_files(
+            Request::builder().uri("/?multiple-files=true"),
+            &[],
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"error":"missing required multipart file field","code":"MISSING_FILE_FIELD"}"#
+        );
+
+        let req = build_multipart_request_with_files(
+            Request::builder().uri("/?multiple-files=true&output=ndjson"),
+            &[("a.csv", "a\n1")],
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn distinguishes_missing_file_field_from_malformed_multipart_body() -> Result<()> {
+        let multipart_request = |body: String| {
+            Request::builder()
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", BOUNDARY),
+                )
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = multipart_request(format!("--{}--\r\n", BOUNDARY));
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            &read_to_string(res.into_body()).await,
+            r#"{"error":"missing required multipart file field","code":"MISSING_FILE_FIELD"}"#
+        );
+
+        // The field's headers are never terminated.
+        let req = multipart_request(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"",
+            BOUNDARY
+        ));
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert!(
+            res_body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("failed to read multipart body: "),
+            "{}",
+            res_body
+        );
+
+        // Without a Content-Length the size limit is only exceeded while reading the body.
+        let config = Config {
+            max_upload_bytes: 16,
+            ..Config::default()
+        };
+        let req = multipart_request(format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\na\n1\r\n--{0}--\r\n",
+            BOUNDARY
+        ));
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_available_routes_for_unrouted_requests() -> Result<()> {
+        let available = serde_json::json!([
+            "POST /",
+            "GET /",
+            "HEAD /",
+            "POST /to-csv",
+            "POST /to-parquet",
+            "POST /validate",
+            "GET /health",
+            "GET /metrics"
+        ]);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/nope")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(res.headers().get(ALLOW), None);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(
+            res_body,
+            serde_json::json!({"error": "not found", "code": "NOT_FOUND", "available": available})
+        );
+
+        for (method, path, expected_allow) in [
+            (Method::PUT, "/", "POST, GET, HEAD"),
+            (Method::GET, "/to-csv", "POST"),
+            (Method::POST, "/health", "GET"),
+        ] {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri(path)
+                .body(Body::empty())?;
+            let res = route_request(Arc::new(Config::default()), req).await?;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers().get(ALLOW).unwrap(), expected_allow);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(
+                res_body,
+                serde_json::json!({
+                    "error": format!("method {} not allowed", method),
+                    "code": "METHOD_NOT_ALLOWED",
+                    "available": available,
+                })
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_to_head_requests_with_conversion_headers() -> Result<()> {
+        for (uri, expected_content_type) in [
+            ("/", "application/octet-stream; charset=utf-8"),
+            ("/?output=ndjson", "application/x-ndjson; charset=utf-8"),
+        ] {
+            let req = Request::builder()
+                .method(Method::HEAD)
+                .uri(uri)
+                .body(Body::empty())?;
+            let res = route_request(Arc::new(Config::default()), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(CONTENT_TYPE).unwrap(),
+                expected_content_type
+            );
+            assert_eq!(res.headers().get(ALLOW).unwrap(), "POST, GET, HEAD");
+            assert_eq!(&read_to_string(res.into_body()).await, "");
+        }
+
+        // The query string is checked the same way as for a conversion.
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri("/?limit=x")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_with_error_codes() -> Result<()> {
+        let multipart = |body: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", BOUNDARY),
+                )
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let raw_csv = |uri: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(CONTENT_TYPE, "text/csv")
+        };
+        let (_sender, stalled_body) = Body::channel();
+        let cases = [
+            (
+                Config::default(),
+                build_multipart_request(
+                    Request::builder().method(Method::POST).uri("/?limit=x"),
+                    "a",
+                ),
+                "INVALID_QUERY",
+            ),
+            (
+                Config {
+                    max_upload_bytes: 4,
+                    ..Config::default()
+                },
+                build_multipart_request(Request::builder().method(Method::POST), "a\n1"),
+                "PAYLOAD_TOO_LARGE",
+            ),
+            (
+                Config::default(),
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body("a".into())?,
+                "UNSUPPORTED_MEDIA_TYPE",
+            ),
+            (
+                Config::default(),
+                Request::builder()
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, "multipart/form-data")
+                    .body("a".into())?,
+                "MISSING_BOUNDARY",
+            ),
+            (
+                Config::default(),
+                multipart(&format!("--{}--\r\n", BOUNDARY)),
+                "MISSING_FILE_FIELD",
+            ),
+            (
+                Config::default(),
+                multipart(&format!("--{}\r\nContent-Disposition", BOUNDARY)),
+                "MALFORMED_MULTIPART",
+            ),
+            (
+                Config::default(),
+                raw_csv("/")
+                    .header(X_CONTENT_SHA256, "abc")
+                    .body("a".into())?,
+                "INVALID_CHECKSUM_HEADER",
+            ),
+            (
+                Config::default(),
+                raw_csv("/")
+                    .header(X_CONTENT_SHA256, "0".repeat(64))
+                    .body("a".into())?,
+                "CHECKSUM_MISMATCH",
+            ),
+            (
+                Config {
+                    max_upload_bytes: 4,
+                    ..Config::default()
+                },
+                raw_csv("/")
+                    .header(X_CONTENT_SHA256, "0".repeat(64))
+                    .body(Body::wrap_stream(futures::stream::iter([
+                        Ok::<_, std::io::Error>("a\n1\n"),
+                        Ok("2\n"),
+                    ])))?,
+                "UPLOAD_FAILED",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?buffered=true").body("a,b\n1".into())?,
+                "CONVERSION_FAILED",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?field-encoding=base64").body("a,b\n1".into())?,
+                "INVALID_BASE64",
+            ),
+            (
+                Config::default(),
+                raw_csv("/").body(b"PK\x03\x04"[..].into())?,
+                "INVALID_ZIP",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?strict-empty=true").body(Body::empty())?,
+                "EMPTY_UPLOAD",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?require-rows=true").body("a,b".into())?,
+                "NO_DATA_ROWS",
+            ),
+            (
+                Config {
+                    max_parquet_bytes: 16,
+                    ..Config::default()
+                },
+                raw_csv("/to-parquet").body("a,b\n1,2".into())?,
+                "PARQUET_TOO_LARGE",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?destination=s3%3A%2F%2Fbucket%2Fkey").body("a\n1".into())?,
+                "INVALID_DESTINATION",
+            ),
+            (
+                fake_s3_config(serve_fake_s3(true).0),
+                raw_csv("/?destination=s3%3A%2F%2Fbucket%2Fkey").body("a\n1".into())?,
+                "DESTINATION_FAILED",
+            ),
+            (
+                Config::default(),
+                raw_csv("/?buffered=true")
+                    .header(RANGE, "bytes=100-")
+                    .body("a\n1".into())?,
+                "RANGE_NOT_SATISFIABLE",
+            ),
+            (
+                Config::default(),
+                Request::builder()
+                    .uri("/?source-url=ftp%3A%2F%2Fexample.com%2Fa.csv")
+                    .body(Body::empty())?,
+                "INVALID_SOURCE_URL",
+            ),
+            (
+                Config {
+                    allowed_source_hosts: vec!["example.com".to_string()],
+                    ..Config::default()
+                },
+                Request::builder()
+                    .uri("/?source-url=http%3A%2F%2Fexample.org%2Fa.csv")
+                    .body(Body::empty())?,
+                "SOURCE_HOST_NOT_ALLOWED",
+            ),
+            (
+                Config::default(),
+                Request::builder()
+                    .uri("/?source-url=http%3A%2F%2F127.0.0.1%3A1%2Fa.csv")
+                    .body(Body::empty())?,
+                "SOURCE_UNAVAILABLE",
+            ),
+            (
+                Config {
+                    request_timeout: Some(Duration::from_millis(10)),
+                    ..Config::default()
+                },
+                Request::builder()
+                    .method(Method::POST)
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={}", BOUNDARY),
+                    )
+                    .body(stalled_body)?,
+                "REQUEST_TIMEOUT",
+            ),
+            (
+                Config {
+                    conversion_permits: Some(Arc::new(Semaphore::new(0))),
+                    ..Config::default()
+                },
+                build_multipart_request(Request::builder().method(Method::POST), "a\n1"),
+                "TOO_MANY_CONVERSIONS",
+            ),
+            (
+                Config {
+                    rate_limiter: Some(Arc::new(RateLimiter::new(
+                        RATE_LIMIT_WINDOW,
+                        Some(0),
+                        None,
+                    ))),
+                    ..Config::default()
+                },
+                {
+                    let mut req =
+                        build_multipart_request(Request::builder().method(Method::POST), "a\n1");
+                    req.extensions_mut()
+                        .insert(RemoteAddr(SocketAddr::from(([127, 0, 0, 1], 1234))));
+                    req
+                },
+                "RATE_LIMITED",
+            ),
+            (
+                Config::default(),
+                Request::builder().uri("/nope").body(Body::empty())?,
+                "NOT_FOUND",
+            ),
+            (
+                Config::default(),
+                Request::builder()
+                    .method(Method::DELETE)
+                    .uri("/")
+                    .body(Body::empty())?,
+                "METHOD_NOT_ALLOWED",
+            ),
+            (
+                Config::default(),
+                build_multipart_request(
+                    Request::builder()
+                        .method(Method::POST)
+                        .header(ACCEPT, "text/csv"),
+                    "a",
+                ),
+                "NOT_ACCEPTABLE",
+            ),
+        ];
+        for (config, req, expected_code) in cases {
+            let res = route_request(Arc::new(config), req).await?;
+            let status = res.status();
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(res_body["code"], expected_code, "{}", res_body);
+            assert_eq!(
+                status.as_u16(),
+                match expected_code {
+                    "SOURCE_HOST_NOT_ALLOWED" => 403,
+                    "NOT_FOUND" => 404,
+                    "METHOD_NOT_ALLOWED" => 405,
+                    "NOT_ACCEPTABLE" => 406,
+                    "REQUEST_TIMEOUT" => 408,
+                    "PAYLOAD_TOO_LARGE" | "PARQUET_TOO_LARGE" => 413,
+                    "UNSUPPORTED_MEDIA_TYPE" => 415,
+                    "RANGE_NOT_SATISFIABLE" => 416,
+                    "CHECKSUM_MISMATCH" | "NO_DATA_ROWS" => 422,
+                    "RATE_LIMITED" => 429,
+                    "SOURCE_UNAVAILABLE" | "DESTINATION_FAILED" => 502,
+                    "TOO_MANY_CONVERSIONS" => 503,
+                    _ => 400,
+                },
+                "{}",
+                expected_code
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_read_file_from_configured_field_name() -> Result<()> {
+        let config = Config {
+            file_field_name: Some("file".to_string()),
+            ..Config::default()
+        };
+        let multipart_request = |fields: &[(&str, &str)]| {
+            let mut body = String::new();
+            for (name, data) in fields {
+                body.push_str(&format!(
+                    "--{0}\r\nContent-Disposition: form-data; name=\"{1}\"\r\n\r\n{2}\r\n",
+                    BOUNDARY, name, data
+                ));
+            }
+            body.push_str(&format!("--{}--\r\n", BOUNDARY));
+            Request::builder()
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", BOUNDARY),
+                )
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = multipart_request(&[("csrf-token", "decoy"), ("file", "a\n1")]);
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(read_to_string(res.into_body()).await, r#"[{"a":"1"}]"#);
+
+        let req = multipart_request(&[("csrf-token", "decoy"), ("upload", "a\n1")]);
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            read_to_string(res.into_body()).await,
+            r#"{"error":"missing required multipart file field \"file\"","code":"MISSING_FILE_FIELD"}"#
+        );
+
+        // Without the option the first field is read, whatever its name.
+        let req = multipart_request(&[("other", "b\n2"), ("file", "a\n1")]);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(read_to_string(res.into_body()).await, r#"[{"b":"2"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_uploads_larger_than_limit() -> Result<()> {
+        let config = Config {
+            max_upload_bytes: 256,
+            ..Config::default()
+        };
+        let req = build_multipart_request(Request::builder(), "a,b\n1,2\n".repeat(64));
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_streaming_uploads_larger_than_limit() -> Result<()> {
+        let config = Config {
+            max_upload_bytes: 256,
+            ..Config::default()
+        };
+        // Simulate a chunked request where the size of the body isn't known up front, and the bulk of
+        // the field only arrives after the response has started.
+        let (mut sender, body) = Body::channel();
+        sender
+            .send_data(Bytes::from(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"field\"; filename=\"example.csv\"\r\n\r\na,b\n",
+                BOUNDARY
+            )))
+            .await?;
+        tokio::spawn(async move {
+            sender.send_data("1,2\n".repeat(128).into()).await?;
+            sender
+                .send_data(format!("\r\n--{}\r\n", BOUNDARY).into())
+                .await
+        });
+        let req = Request::builder()
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)?;
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let chunks: Vec<_> = StreamExt::collect(res.into_body()).await;
+        assert!(chunks.last().unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strips_directories_from_file_name() -> Result<()> {
+        let req = build_multipart_request_with_file_name(
+            Request::builder(),
+            "../../etc/passwd.csv",
+            "field1\n1",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="passwd.json"; filename*=UTF-8''passwd.json"#
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_override_download_file_name_with_query_param() -> Result<()> {
+        for (uri, expected) in [
+            ("/?filename=report", "report.json"),
+            ("/?filename=report&output=ndjson", "report.ndjson"),
+            ("/?filename=report.csv", "report.json"),
+            ("/?filename=..%2F..%2Freport", "report.json"),
+            // Falls back to the uploaded file's name if nothing is left after sanitizing.
+            ("/?filename=..%2F", "example.json"),
+            ("/?filename=report&multiple-files=true", "report.json"),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), "field1\n1");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(
+                res.headers().get(CONTENT_DISPOSITION),
+                Some(&HeaderValue::from_str(&format!(
+                    "attachment; filename=\"{0}\"; filename*=UTF-8''{0}",
+                    expected
+                ))?),
+                "{}",
+                uri
+            );
+        }
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/to-csv?filename=report")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"[{"a":1}]"#))?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="report.csv"; filename*=UTF-8''report.csv"#
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encodes_non_ascii_file_name() -> Result<()> {
+        let req = build_multipart_request_with_file_name(
+            Request::builder(),
+            "données été.csv",
+            "field1\n1",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="donn_es _t_.json"; filename*=UTF-8''donn%C3%A9es%20%C3%A9t%C3%A9.json"#
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_file_name_sanitizes_to_nothing() -> Result<()> {
+        let req = build_multipart_request_with_file_name(Request::builder(), "../", "field1\n1");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="download.json"; filename*=UTF-8''download.json"#
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_default_file_name_without_one() -> Result<()> {
+        let without_file_name = |uri: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", BOUNDARY),
+                )
+                .body(Body::from(format!(
+                    "--{0}\r\nContent-Disposition: form-data; name=\"field\"\r\n\r\nfield1\n1\r\n--{0}--\r\n",
+                    BOUNDARY
+                )))
+        };
+        let args = Args::parse_from(["csv-to-json", "--default-filename", "export"]);
+        for (config, uri, expected) in [
+            (Config::default(), "/", "download.json"),
+            (Config::default(), "/?output=ndjson", "download.ndjson"),
+            (Config::from(&args), "/", "export.json"),
+            (Config::from(&args), "/?multiple-files=true", "export.json"),
+        ] {
+            let res = convert_csv(&config, without_file_name(uri)?).await?;
+            assert_eq!(
+                res.headers().get(CONTENT_DISPOSITION),
+                Some(&HeaderValue::from_str(&format!(
+                    "attachment; filename=\"{0}\"; filename*=UTF-8''{0}",
+                    expected
+                ))?),
+                "{}",
+                uri
+            );
+        }
+
+        for file_name in ["../export", "export\"", ""] {
+            assert!(
+                Args::try_parse_from(["csv-to-json", "--default-filename", file_name]).is_err(),
+                "{}",
+                file_name
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(
+            sanitize_file_name("../../evil.csv"),
+            Some("evil.csv".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("C:\\data\\report.csv"),
+            Some("report.csv".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("quo\"ted\r\n\u{7}.csv"),
+            Some("quoted.csv".to_string())
+        );
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("dir/"), None);
+        assert_eq!(sanitize_file_name("\"\""), None);
+    }
+
+    #[test]
+    fn replaces_file_extensions() {
+        for (file_name, expected) in [
+            ("birds.csv", "birds.json"),
+            ("birds", "birds.json"),
+            ("data.2024.csv", "data.2024.json"),
+            ("birds.", "birds.json"),
+            (".csv", ".csv.json"),
+        ] {
+            assert_eq!(replace_file_extension(file_name, "json"), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn replaces_extension_of_uploaded_file_name() -> Result<()> {
+        for (file_name, expected) in [("birds", "birds.json"), ("data.2024.csv", "data.2024.json")]
+        {
+            let req =
+                build_multipart_request_with_file_name(Request::builder(), file_name, "field1\n1");
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(
+                res.headers().get(CONTENT_DISPOSITION),
+                Some(&HeaderValue::from_str(&format!(
+                    "attachment; filename=\"{0}\"; filename*=UTF-8''{0}",
+                    expected
+                ))?),
+                "{}",
+                file_name
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_check_responds_ok() -> Result<()> {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/health")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE),
+            Some(&HeaderValue::from_static("application/json"))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"{"status":"ok"}"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_defaults_from_config_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("csv2json.toml");
+        std::fs::write(
+            &path,
+            r#"
+host = "0.0.0.0"
+port = 9000
+max-upload-bytes = 1024
+cors-origins = ["https://example.com"]
+log-format = "json"
+
+[defaults]
+delimiter = ";"
+infer-types = true
+include = ["a", "b"]
+"#,
+        )?;
+        let args = parse_args([
+            "csv-to-json",
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "9001",
+        ])?;
+        // Flags that are given take precedence over the file, which takes precedence over the defaults.
+        assert_eq!(args.host, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(args.port, 9001);
+        assert_eq!(args.log_format, LogFormat::Json);
+        assert_eq!(args.shutdown_timeout_secs, DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+        let config = Arc::new(Config::from(&args));
+        assert_eq!(config.max_upload_bytes, 1024);
+        assert_eq!(config.cors_origins, ["https://example.com"]);
+
+        // Query params that are given take precedence over the defaults.
+        for (query, csv, expected) in [
+            ("", "a;b;c\n1;x;2", r#"[{"a":1,"b":"x"}]"#),
+            (
+                "?delimiter=%2C&infer-types=false",
+                "a,b,c\n1,x,2",
+                r#"[{"a":"1","b":"x"}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/{}", query)),
+                csv,
+            );
+            let res = route_request(config.clone(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            assert_eq!(read_to_string(res.into_body()).await, expected, "{}", query);
+        }
+
+        let path = dir.path().join("csv2json.json");
+        std::fs::write(&path, r#"{"port": 9002, "defaults": {"output": "xml"}}"#)?;
+        let error = parse_args(["csv-to-json", "--config", path.to_str().unwrap()]).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("invalid query param defaults"),
+            "{:#}",
+            error
+        );
+        std::fs::write(&path, r#"{"port": 9002, "colour": "blue"}"#)?;
+        assert!(parse_args(["csv-to-json", "--config", path.to_str().unwrap()]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_with_not_found_for_disabled_routes() -> Result<()> {
+        let args = Args::parse_from([
+            "csv-to-json",
+            "--disable",
+            "GET /metrics",
+            "--disable",
+            "/to-csv",
+        ]);
+        let config = Arc::new(Config::from(&args));
+        for (method, path) in [(Method::GET, "/metrics"), (Method::POST, "/to-csv")] {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())?;
+            let res = route_request(config.clone(), req).await?;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(
+                res_body["available"],
+                serde_json::json!([
+                    "POST /",
+                    "GET /",
+                    "HEAD /",
+                    "POST /to-parquet",
+                    "POST /validate",
+                    "GET /health"
+                ])
+            );
+        }
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/health")
+            .body(Body::empty())?;
+        let res = route_request(config.clone(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Only POST / is enabled, so other methods for it aren't allowed.
+        let args = Args::parse_from(["csv-to-json", "--enable", "POST /"]);
+        let config = Arc::new(Config::from(&args));
+        let req = build_multipart_request(Request::builder().method(Method::POST).uri("/"), "a\n1");
+        let res = route_request(config.clone(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        for (method, path, status) in [
+            (Method::GET, "/", StatusCode::NOT_FOUND),
+            (Method::PUT, "/", StatusCode::METHOD_NOT_ALLOWED),
+            (Method::GET, "/health", StatusCode::NOT_FOUND),
+        ] {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())?;
+            let res = route_request(config.clone(), req).await?;
+            assert_eq!(res.status(), status, "{}", path);
+            if status == StatusCode::METHOD_NOT_ALLOWED {
+                assert_eq!(res.headers().get(ALLOW).unwrap(), "POST");
+            }
+        }
+
+        assert!(Args::try_parse_from(["csv-to-json", "--disable", "DELETE /"]).is_err());
+        assert!(Args::try_parse_from(["csv-to-json", "--enable", "/nope"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_count_conversions() -> Result<()> {
+        let config = Arc::new(Config::default());
+        let csv = "a,b\n1,2\n3,4";
+        let req = build_multipart_request(Request::builder().method(Method::POST).uri("/"), csv);
+        let res = route_request(config.clone(), req).await?;
+        let json = read_to_string(res.into_body()).await;
+        let req =
+            build_multipart_request(Request::builder().method(Method::POST).uri("/"), "a,b\n1");
+        let res = route_request(config.clone(), req).await?;
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/metrics")
+            .body(Body::empty())?;
+        let res = route_request(config.clone(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let metrics = read_to_string(res.into_body()).await;
+        for expected in [
+            "csv_to_json_requests_total 3".to_string(),
+            format!("csv_to_json_received_bytes_total {}", csv.len() + 5),
+            format!("csv_to_json_sent_bytes_total {}", json.len()),
+            "csv_to_json_records_converted_total 2".to_string(),
+            "csv_to_json_conversion_errors_total 1".to_string(),
+            "csv_to_json_request_duration_seconds_count 2".to_string(),
+        ] {
+            assert!(
+                metrics.lines().any(|line| line == expected),
+                "expected {:?} in metrics:\n{}",
+                expected,
+                metrics
+            );
+        }
+        Ok(())
+    }
+
+    /// Collects the lines written to an access log.
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_access_log_lines() -> Result<()> {
+        for format in [AccessLogFormat::Json, AccessLogFormat::Clf] {
+            let writer = SharedWriter::default();
+            let config = Arc::new(Config {
+                access_log: Some(Arc::new(AccessLog::new(format, Box::new(writer.clone())))),
+                ..Config::default()
+            });
+            let mut req = build_multipart_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/?infer-types=true")
+                    .header(X_REQUEST_ID, "my-request")
+                    .header(hyper::header::USER_AGENT, "curl/7.79.1"),
+                "a,b\n1,2",
+            );
+            req.extensions_mut()
+                .insert(RemoteAddr(SocketAddr::from(([127, 0, 0, 1], 1234))));
+            let res = serve_request(config, req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let bytes_sent = hyper::body::to_bytes(res.into_body()).await?.len();
+
+            let log = String::from_utf8(writer.0.lock().unwrap().clone())?;
+            let line = log.strip_suffix('\n').unwrap();
+            assert!(!line.contains('\n'));
+            match format {
+                AccessLogFormat::Json => {
+                    let entry: serde_json::Value = serde_json::from_str(line)?;
+                    assert_eq!(entry["remote_addr"], "127.0.0.1");
+                    assert_eq!(entry["method"], "POST");
+                    assert_eq!(entry["path"], "/?infer-types=true");
+                    assert_eq!(entry["protocol"], "HTTP/1.1");
+                    assert_eq!(entry["status"], 200);
+                    assert_eq!(entry["bytes_sent"], bytes_sent);
+                    assert_eq!(entry["user_agent"], "curl/7.79.1");
+                    assert_eq!(entry["referer"], serde_json::Value::Null);
+                    assert_eq!(entry["request_id"], "my-request");
+                    assert!(entry["duration_ms"].as_f64().unwrap() >= 0.0);
+                }
+                AccessLogFormat::Clf => {
+                    let (start, rest) = line.split_once(" [").unwrap();
+                    assert_eq!(start, "127.0.0.1 - -");
+                    let (_time, rest) = rest.split_once("] ").unwrap();
+                    let (rest, duration) = rest.rsplit_once(' ').unwrap();
+                    assert_eq!(
+                        rest,
+                        format!(
+                            "\"POST /?infer-types=true HTTP/1.1\" 200 {} \"-\" \"curl/7.79.1\"",
+                            bytes_sent
+                        )
+                    );
+                    duration.parse::<u64>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves `body` with the given status for any request on a random local port, returning the
+    /// address that the server is listening on.
+    fn serve_remote_file(status: StatusCode, body: &'static str) -> SocketAddr {
+        let service = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |_req| async move {
+                Response::builder().status(status).body(Body::from(body))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Parts and objects uploaded to a fake S3 server, see serve_fake_s3.
+    #[derive(Debug, Default)]
+    struct FakeS3 {
+        parts: Vec<Bytes>,
+        objects: HashMap<String, Bytes>,
+        aborted: bool,
+    }
+
+    /// Serves just enough of the S3 API for multipart uploads on a random local port, returning the
+    /// address that the server is listening on and what has been uploaded to it. Objects are stored by
+    /// their path, i.e. `/bucket/key`. With `fail_parts` uploading parts is denied.
+    fn serve_fake_s3(fail_parts: bool) -> (SocketAddr, Arc<std::sync::Mutex<FakeS3>>) {
+        let fake_s3 = Arc::new(std::sync::Mutex::new(FakeS3::default()));
+        let uploaded = fake_s3.clone();
+        let service = make_service_fn(move |_conn| {
+            let fake_s3 = fake_s3.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let fake_s3 = fake_s3.clone();
+                    async move {
+                        let method = req.method().clone();
+                        let path = req.uri().path().to_string();
+                        let query = req.uri().query().unwrap_or_default().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let mut fake_s3 = fake_s3.lock().unwrap();
+                        let res = match method {
+                            Method::POST if query.split('&').any(|param| param.starts_with("uploads")) => {
+                                Response::new(Body::from(
+                                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                                     <UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+                                ))
+                            }
+                            Method::PUT if fail_parts => Response::builder()
+                                .status(StatusCode::FORBIDDEN)
+                                .body(Body::from(
+                                    "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>",
+                                ))
+                                .unwrap(),
+                            Method::PUT => {
+                                fake_s3.parts.push(body);
+                                Response::builder()
+                                    .header("ETag", format!("\"etag-{}\"", fake_s3.parts.len()))
+                                    .body(Body::empty())
+                                    .unwrap()
+                            }
+                            Method::POST => {
+                                let object = fake_s3.parts.concat();
+                                fake_s3.objects.insert(path, Bytes::from(object));
+                                Response::new(Body::from(
+                                    "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                                     <ETag>\"etag\"</ETag></CompleteMultipartUploadResult>",
+                                ))
+                            }
+                            _ => {
+                                fake_s3.aborted = true;
+                                Response::builder()
+                                    .status(StatusCode::NO_CONTENT)
+                                    .body(Body::empty())
+                                    .unwrap()
+                            }
+                        };
+                        Ok::<_, hyper::Error>(res)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, uploaded)
+    }
+
+    fn fake_s3_config(addr: SocketAddr) -> Config {
+        Config {
+            s3_client: Some(s3::build_client(
+                "us-east-1",
+                Some(&format!("http://{}", addr)),
+                "access-key-id",
+                "secret-access-key",
+            )),
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_conversion_to_s3_destination() -> Result<()> {
+        let (addr, fake_s3) = serve_fake_s3(false);
+        let req = build_multipart_request(
+            Request::builder().uri("/?destination=s3%3A%2F%2Fbucket%2Fbirds.json"),
+            "name,wings\nsparrow,2",
+        );
+        let res = convert_csv(&fake_s3_config(addr), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(
+            res_body,
+            serde_json::json!({"location": "s3://bucket/birds.json"})
+        );
+        let fake_s3 = fake_s3.lock().unwrap();
+        assert_eq!(fake_s3.parts.len(), 1);
+        assert_eq!(
+            fake_s3.objects.get("/bucket/birds.json"),
+            Some(&Bytes::from(r#"[{"name":"sparrow","wings":"2"}]"#))
+        );
+        assert!(!fake_s3.aborted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aborts_failed_uploads_to_s3_destination() -> Result<()> {
+        // S3 rejecting the upload is reported as a bad gateway.
+        let (addr, fake_s3) = serve_fake_s3(true);
+        let req = build_multipart_request(
+            Request::builder().uri("/?destination=s3%3A%2F%2Fbucket%2Fbirds.json"),
+            "name,wings\nsparrow,2",
+        );
+        let res = convert_csv(&fake_s3_config(addr), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(res_body["code"], "DESTINATION_FAILED");
+        assert!(fake_s3.lock().unwrap().aborted);
+
+        // An error in the CSV is the client's fault though.
+        let (addr, fake_s3) = serve_fake_s3(false);
+        let req = build_multipart_request(
+            Request::builder().uri("/?destination=s3%3A%2F%2Fbucket%2Fbirds.json"),
+            "name,wings\nsparrow",
+        );
+        let res = convert_csv(&fake_s3_config(addr), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(res_body["code"], "CONVERSION_FAILED");
+        let fake_s3 = fake_s3.lock().unwrap();
+        assert!(fake_s3.aborted);
+        assert!(fake_s3.objects.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_s3_destinations() -> Result<()> {
+        let (addr, _) = serve_fake_s3(false);
+        for (config, destination) in [
+            (Config::default(), "s3%3A%2F%2Fbucket%2Fbirds.json"),
+            (
+                fake_s3_config(addr),
+                "https%3A%2F%2Fexample.com%2Fbirds.json",
+            ),
+            (fake_s3_config(addr), "s3%3A%2F%2Fbucket"),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?destination={}", destination)),
+                "a\n1",
+            );
+            let res = convert_csv(&config, req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", destination);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(res_body["code"], "INVALID_DESTINATION", "{}", destination);
+        }
+        Ok(())
+    }
+
+    /// Starts serving on a random port until the returned sender is used to shut down the server.
+    fn start_server(
+        config: Config,
+        shutdown_timeout: Duration,
+    ) -> Result<(
+        SocketAddr,
+        tokio::sync::oneshot::Sender<()>,
+        tokio::task::JoinHandle<()>,
+    )> {
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            AddrIncoming::from_listener(TcpListener::from_std(listener)?)?,
+            Arc::new(config),
+            async {
+                let _ = shutdown_rx.await;
+            },
+            shutdown_timeout,
+        ));
+        Ok((addr, shutdown_tx, server))
+    }
+
+    /// Starts a streaming multipart upload of a CSV file to the server, sending only the given start
+    /// of the file. Returns the response along with the sender for the rest of the body.
+    async fn start_upload(
+        addr: SocketAddr,
+        csv_start: &str,
+    ) -> Result<(Response<Body>, hyper::body::Sender)> {
+        let (mut sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/", addr))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)?;
+        let res = tokio::spawn(Client::new().request(req));
+        sender
+            .send_data(Bytes::from(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"field\"; filename=\"example.csv\"\r\n\r\n{}",
+                BOUNDARY, csv_start
+            )))
+            .await?;
+        Ok((res.await??, sender))
+    }
+
+    #[tokio::test]
+    async fn finishes_in_flight_requests_when_shutting_down() -> Result<()> {
+        let (addr, shutdown, server) = start_server(Config::default(), Duration::from_secs(10))?;
+        let (res, mut sender) = start_upload(addr, "a\n1\n").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        shutdown.send(()).unwrap();
+        // Give the server a chance to start shutting down before the upload finishes.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sender
+            .send_data(Bytes::from(format!("2\r\n--{}--\r\n", BOUNDARY)))
+            .await?;
+        drop(sender);
+
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"a":"1"},{"a":"2"}]"#);
+        tokio::time::timeout(Duration::from_secs(5), server).await??;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_requests_on_unix_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("csv-to-json.sock");
+        // A socket file left behind by a server that's no longer running is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let listener = bind_unix_socket(&path)?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                serve_unix_socket(
+                    listener,
+                    &path,
+                    Arc::new(Config::default()),
+                    async {
+                        let _ = shutdown_rx.await;
+                    },
+                    Duration::from_secs(1),
+                )
+                .await
+            }
+        });
+        // A socket that's in use isn't replaced though.
+        assert!(bind_unix_socket(&path).is_err());
+
+        let stream = UnixStream::connect(&path).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        let req =
+            build_multipart_request(Request::builder().method(Method::POST).uri("/"), "a,b\n1,2");
+        let res = sender.send_request(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"a":"1","b":"2"}]"#);
+        drop(sender);
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await??;
+        assert!(!path.exists());
+
+        // Nor is anything at the path that isn't a socket.
+        std::fs::write(&path, "not a socket")?;
+        assert!(bind_unix_socket(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "not a socket");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_conversions_over_concurrency_limit() -> Result<()> {
+        let config = Config {
+            conversion_permits: Some(Arc::new(Semaphore::new(2))),
+            ..Config::default()
+        };
+        let (addr, _shutdown, _server) = start_server(config, Duration::from_secs(1))?;
+        // The uploads are left unfinished so that their conversions are still in progress.
+        let (first_res, mut first_sender) = start_upload(addr, "a\n1\n").await?;
+        let (second_res, _second_sender) = start_upload(addr, "a\n1\n").await?;
+        let (third_res, _third_sender) = start_upload(addr, "a\n1\n").await?;
+        assert_eq!(first_res.status(), StatusCode::OK);
+        assert_eq!(second_res.status(), StatusCode::OK);
+        assert_eq!(third_res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            third_res.headers().get(RETRY_AFTER),
+            Some(&HeaderValue::from_static("1"))
+        );
+
+        // Once a conversion finishes, another one can start.
+        first_sender
+            .send_data(Bytes::from(format!("2\r\n--{}--\r\n", BOUNDARY)))
+            .await?;
+        drop(first_sender);
+        read_to_string(first_res.into_body()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (fourth_res, _fourth_sender) = start_upload(addr, "a\n1\n").await?;
+        assert_eq!(fourth_res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_over_rate_limit() -> Result<()> {
+        // Each upload is between 150 and 300 bytes, so the byte limit is reached after two of them.
+        for (max_requests, max_bytes) in [(Some(2), None), (None, Some(300))] {
+            let config = Config {
+                rate_limiter: Some(Arc::new(RateLimiter::new(
+                    RATE_LIMIT_WINDOW,
+                    max_requests,
+                    max_bytes,
+                ))),
+                ..Config::default()
+            };
+            let (addr, _shutdown, _server) = start_server(config, Duration::from_secs(1))?;
+            let convert = || async {
+                let req = build_multipart_request(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri(format!("http://{}/", addr)),
+                    format!("a\n{}", "1".repeat(150)),
+                );
+                let res = Client::new().request(req).await?;
+                let status = res.status();
+                let retry_after = res.headers().get(RETRY_AFTER).cloned();
+                read_to_string(res.into_body()).await;
+                Ok::<_, anyhow::Error>((status, retry_after))
+            };
+            assert_eq!(convert().await?, (StatusCode::OK, None));
+            assert_eq!(convert().await?, (StatusCode::OK, None));
+            let (status, retry_after) = convert().await?;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: u64 = retry_after.expect("has Retry-After").to_str()?.parse()?;
+            assert!((1..=60).contains(&retry_after), "{}", retry_after);
+
+            // Health checks aren't limited.
+            let res = Client::new()
+                .get(format!("http://{}/health", addr).parse()?)
+                .await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_record_count_and_status_in_trailers() -> Result<()> {
+        let (addr, _shutdown, _server) = start_server(Config::default(), Duration::from_secs(1))?;
+        // Trailers are only sent over HTTP/2.
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        for (query, csv, expected_body, expected_count, expected_status) in [
+            (
+                "",
+                &b"a\n1\n2\n"[..],
+                r#"[{"a":"1"},{"a":"2"}]"#,
+                "2",
+                "success",
+            ),
+            // The error is reported in the envelope, but the status still shows the conversion failed.
+            (
+                "?envelope=true",
+                &b"a\n1\n\xff\n"[..],
+                r#"{"records":[{"a":"1"}],"error":"#,
+                "1",
+                "failure",
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("http://{}/{}", addr, query))
+                    .header(TE, "trailers"),
+                csv,
+            );
+            let res = client.request(req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let mut body = res.into_body();
+            let mut res_body = Vec::new();
+            while let Some(chunk) = body.data().await {
+                res_body.extend_from_slice(&chunk?);
+            }
+            assert!(std::str::from_utf8(&res_body)?.starts_with(expected_body));
+            let trailers = body.trailers().await?.unwrap();
+            assert_eq!(trailers.get(X_RECORD_COUNT).unwrap(), expected_count);
+            assert_eq!(trailers.get(X_CONVERSION_STATUS).unwrap(), expected_status);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_converting_once_the_response_is_dropped() -> Result<()> {
+        // The input never ends and is all skipped, so the conversion never produces any output and
+        // would go on reading forever if it weren't cancelled.
+        let chunks_read = Arc::new(AtomicU64::new(0));
+        let endless_input = || {
+            let counter = chunks_read.clone();
+            futures::stream::repeat_with(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, std::io::Error>(Bytes::from_static(b"skipped\n"))
+            })
+            .then(|chunk| async {
+                tokio::task::yield_now().await;
+                chunk
+            })
+        };
+        // With trailers the output is produced by a task of its own, rather than by the body itself.
+        for trailers in [false, true] {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/?skip-rows=1000000000")
+                .header(CONTENT_TYPE, "text/csv");
+            if trailers {
+                req = req.header(TE, "trailers");
+            }
+            let req = req.body(Body::wrap_stream(endless_input()))?;
+            let res = convert_csv(&Config::default(), req).await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(res);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let read_after_drop = chunks_read.load(Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(
+                chunks_read.load(Ordering::SeqCst),
+                read_after_drop,
+                "trailers: {}",
+                trailers
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_for_in_flight_requests_after_shutdown_timeout() -> Result<()> {
+        let (addr, shutdown, server) = start_server(Config::default(), Duration::from_millis(100))?;
+        // The upload is never finished, so the request never finishes either.
+        let (res, _sender) = start_upload(addr, "a\n1\n").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await??;
+        Ok(())
+    }
+
+    /// Writes a self-signed certificate for localhost and its private key to PEM files in the
+    /// directory, returning the certificate.
+    fn write_self_signed_certificate(dir: &Path) -> Result<rcgen::Certificate> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem()?)?;
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+        Ok(cert)
+    }
+
+    #[tokio::test]
+    async fn can_serve_https() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cert = write_self_signed_certificate(dir.path())?;
+        let tls_config =
+            load_tls_config(&dir.path().join("cert.pem"), &dir.path().join("key.pem"))?;
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(serve(
+            tls_incoming(listener, Arc::new(tls_config)),
+            Arc::new(Config::default()),
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert.serialize_der()?))?;
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(client_config)
+            .https_only()
+            .enable_http1()
+            .enable_http2()
+            .build();
+        let client = Client::builder().build::<_, Body>(connector);
+        for (uri, expected) in [
+            (
+                format!("https://localhost:{}/health", port),
+                Some(StatusCode::OK),
+            ),
+            // Plain HTTP requests fail the TLS handshake.
+            (format!("http://localhost:{}/health", port), None),
+        ] {
+            let res = if uri.starts_with("https") {
+                client.get(uri.parse()?).await
+            } else {
+                Client::new().get(uri.parse()?).await
+            };
+            assert_eq!(res.ok().map(|res| res.status()), expected, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_to_load_invalid_tls_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_self_signed_certificate(dir.path())?;
+        std::fs::write(dir.path().join("empty.pem"), "")?;
+        for (cert, key, expected) in [
+            ("missing.pem", "key.pem", "failed to open"),
+            ("cert.pem", "missing.pem", "failed to open"),
+            ("empty.pem", "key.pem", "no certificates found in"),
+            ("key.pem", "key.pem", "no certificates found in"),
+            ("cert.pem", "cert.pem", "no private key found in"),
+        ] {
+            let error = load_tls_config(&dir.path().join(cert), &dir.path().join(key))
+                .expect_err("loading should fail");
+            assert!(
+                format!("{:#}", error).contains(expected),
+                "{} {}: {:#}",
+                cert,
+                key,
+                error
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_remote_csv() -> Result<()> {
+        let addr = serve_remote_file(StatusCode::OK, "field1;field2\n1;2");
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "/?delimiter=%3B&source-url=http%3A%2F%2F{}%2Fdata%2Fremote.csv",
+                addr
+            ))
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_DISPOSITION),
+            Some(&HeaderValue::from_static(
+                r#"attachment; filename="remote.json"; filename*=UTF-8''remote.json"#
+            ))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_bad_gateway_when_remote_csv_fails() -> Result<()> {
+        let addr = serve_remote_file(StatusCode::NOT_FOUND, "not found");
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("/?source-url=http%3A%2F%2F{}%2Fremote.csv", addr))
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(Config::default()), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"error":"source-url responded with status 404 Not Found","code":"SOURCE_UNAVAILABLE"}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_remote_csv_from_disallowed_host() -> Result<()> {
+        let config = Config {
+            allowed_source_hosts: vec!["example.com".to_string()],
+            ..Config::default()
+        };
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/?source-url=http%3A%2F%2F169.254.169.254%2Flatest%2Fmeta-data")
+            .body(Body::empty())?;
+        let res = route_request(Arc::new(config), req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compresses_response_when_gzip_is_accepted() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().header(ACCEPT_ENCODING, "deflate, gzip;q=0.8"),
+            "field1,field2,field3\n1,2,3\n4,5,6",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_ENCODING),
+            Some(&HeaderValue::from_static("gzip"))
+        );
+        let compressed = hyper::body::to_bytes(res.into_body()).await?;
+        let mut res_body = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut res_body)
+            .await?;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2","field3":"3"},{"field1":"4","field2":"5","field3":"6"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffers_response_with_content_length() -> Result<()> {
+        let csv = "field1,field2\n1,2";
+        let expected = r#"[{"field1":"1","field2":"2"}]"#;
+
+        let req = build_multipart_request(Request::builder(), csv);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.headers().get(CONTENT_LENGTH), None);
+
+        let req = build_multipart_request(Request::builder().uri("/?buffered=true"), csv);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_LENGTH),
+            Some(&HeaderValue::from(expected.len()))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, expected);
+
+        let req = build_multipart_request(
+            Request::builder()
+                .uri("/?buffered=true")
+                .header(ACCEPT_ENCODING, "gzip"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let content_length = res.headers().get(CONTENT_LENGTH).cloned();
+        let compressed = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(content_length, Some(HeaderValue::from(compressed.len())));
+        let mut res_body = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut res_body)
+            .await?;
+        assert_eq!(&res_body, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_with_range_of_buffered_response() -> Result<()> {
+        let csv = "field1,field2\n1,2";
+        let expected = r#"[{"field1":"1","field2":"2"}]"#;
+        for (range, content_range, expected_body) in [
+            ("bytes=0-9", "bytes 0-9/29", &expected[..10]),
+            ("bytes=10-", "bytes 10-28/29", &expected[10..]),
+            ("bytes=-5", "bytes 24-28/29", &expected[24..]),
+            ("bytes=20-100", "bytes 20-28/29", &expected[20..]),
+        ] {
+            let req = build_multipart_request(
+                Request::builder()
+                    .uri("/?buffered=true")
+                    .header(RANGE, range),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(res.headers().get(CONTENT_RANGE).unwrap(), content_range);
+            assert_eq!(
+                res.headers().get(CONTENT_LENGTH),
+                Some(&HeaderValue::from(expected_body.len()))
+            );
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected_body);
+        }
+
+        // Ranges that can't be parsed, and ranges of streamed responses, are ignored.
+        for (uri, range) in [("/?buffered=true", "bytes=0-1,4-5"), ("/", "bytes=0-9")] {
+            let req =
+                build_multipart_request(Request::builder().uri(uri).header(RANGE, range), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", range);
+            assert_eq!(res.headers().get(CONTENT_RANGE), None);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_with_range_not_satisfiable_for_out_of_bounds_range() -> Result<()> {
+        for range in ["bytes=29-", "bytes=-0"] {
+            let req = build_multipart_request(
+                Request::builder()
+                    .uri("/?buffered=true")
+                    .header(RANGE, range),
+                "field1,field2\n1,2",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+            assert_eq!(res.headers().get(CONTENT_RANGE).unwrap(), "bytes */29");
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(res_body["code"], "RANGE_NOT_SATISFIABLE");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responds_with_error_status_when_buffered_conversion_fails() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?buffered=true"),
+            "field1,field2\n1,2\n3",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert!(
+            res_body["error"]
+                .as_str()
+                .unwrap()
+                .contains("line 3: record has 1 fields, but there are 2 columns"),
+            "{}",
+            res_body
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffers_response_below_buffer_threshold() -> Result<()> {
+        let config = Config {
+            buffer_threshold: Some(100),
+            ..Config::default()
+        };
+        let expected = r#"[{"field1":"1","field2":"2"}]"#;
+        let req = build_multipart_request(Request::builder(), "field1,field2\n1,2");
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CONTENT_LENGTH),
+            Some(&HeaderValue::from(expected.len()))
+        );
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, expected);
+
+        // Like a buffered conversion, a failure is reported with the status.
+        let req = build_multipart_request(Request::builder(), "field1,field2\n1,2\n3");
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_response_above_buffer_threshold() -> Result<()> {
+        let config = Config {
+            buffer_threshold: Some(20),
+            ..Config::default()
+        };
+        let csv = "field1,field2\n1,2\n3,4\n5,6";
+        let expected = r#"[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"},{"field1":"5","field2":"6"}]"#;
+        let req = build_multipart_request(Request::builder(), csv);
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(CONTENT_LENGTH), None);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, expected);
+
+        // The output that was read before crossing the threshold is compressed along with the rest.
+        let req = build_multipart_request(Request::builder().header(ACCEPT_ENCODING, "gzip"), csv);
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.headers().get(CONTENT_LENGTH), None);
+        let compressed = hyper::body::to_bytes(res.into_body()).await?;
+        let mut res_body = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut res_body)
+            .await?;
+        assert_eq!(&res_body, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_decode_base64_uploads_with_query_param() -> Result<()> {
+        let expected = r#"[{"name":"Ann","email":"ann@example.com"}]"#;
+        // "name,email\nAnn,ann@example.com", wrapped over several lines.
+        let encoded = "bmFtZSxlbWFpbApBbm4s\r\nYW5uQGV4YW1wbGUuY29t";
+        let req =
+            build_multipart_request(Request::builder().uri("/?field-encoding=base64"), encoded);
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, expected);
+
+        // Groups of 4 characters can be split across chunks.
+        let chunks = ["bmFtZSxlbW", "FpbApBbm4sYW5uQGV", "4YW1wbGUuY29t"];
+        let decoded = collect_bytes(decode_base64_stream(futures::stream::iter(
+            chunks.map(|chunk| Ok(Bytes::from(chunk))),
+        )))
+        .await?;
+        assert_eq!(&decoded[..], b"name,email\nAnn,ann@example.com");
+
+        let req = build_multipart_request(
+            Request::builder().uri("/?field-encoding=base64"),
+            "name,email\nAnn,ann@example.com",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(res_body["code"], "INVALID_BASE64");
+        assert!(
+            res_body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid base64 in upload"),
+            "{}",
+            res_body
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_upload_checksum_header() -> Result<()> {
+        let csv = "field1\n1";
+        let digest = format!("{:x}", Sha256::digest(csv));
+        let req = build_multipart_request(
+            Request::builder().header(X_CONTENT_SHA256, digest.to_ascii_uppercase()),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            &read_to_string(res.into_body()).await,
+            r#"[{"field1":"1"}]"#
+        );
+
+        let req = build_multipart_request(
+            Request::builder().header(X_CONTENT_SHA256, &digest),
+            "field1\n2",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert!(
+            res_body["error"]
+                .as_str()
+                .unwrap()
+                .ends_with(&format!("but X-Content-SHA256 is {}", digest)),
+            "{}",
+            res_body
+        );
+
+        let req = build_multipart_request(
+            Request::builder().header(X_CONTENT_SHA256, "not-a-digest"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn does_not_compress_response_when_gzip_is_refused() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().header(ACCEPT_ENCODING, "gzip;q=0, identity"),
+            "field1\n1",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.headers().get(CONTENT_ENCODING), None);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"field1":"1"}]"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_wrap_records_in_envelope_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?envelope=true"),
+            "field1,field2\n1,2\n3,4",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"records":[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"}],"error":null}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_errors_in_envelope() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?envelope=true"),
+            b"field1,field2\n1,2\n3,\xff\xfe\n5,6",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        let envelope: Value = serde_json::from_str(&res_body)?;
+        assert_eq!(
+            envelope["records"],
+            serde_json::json!([{"field1":"1","field2":"2"}])
+        );
+        assert!(envelope["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid utf-8"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_error_positions() -> Result<()> {
+        for (csv, expected) in [
+            // The stray quote doesn't end the quoted field, so the rest of it becomes an extra field.
+            (
+                &b"a,b\n1,2\n\"x\"y,z,3\n4,5"[..],
+                "line 3: record has 3 fields, but there are 2 columns",
+            ),
+            (
+                &b"a,b\n1,2\n3,\xff"[..],
+                "line 3, column 2: invalid utf-8 near byte 0 of the field",
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri("/?envelope=true"), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(envelope["records"], serde_json::json!([{"a":"1","b":"2"}]));
+            assert!(
+                envelope["error"].as_str().unwrap().ends_with(expected),
+                "{}",
+                envelope["error"]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_pretty_print_with_query_param() -> Result<()> {
+        let csv = "a,b\n1,\"multi\nline\"\n3,4";
+        for (query, expected) in [
+            (
+                "pretty=true",
+                "[\n  {\n    \"a\": \"1\",\n    \"b\": \"multi\\nline\"\n  },\n  {\n    \"a\": \"3\",\n    \"b\": \"4\"\n  }\n]",
+            ),
+            (
+                "pretty=true&envelope=true&limit=1",
+                "{\n  \"records\": [\n    {\n      \"a\": \"1\",\n      \"b\": \"multi\\nline\"\n    }\n  ],\n  \"error\": null\n}",
+            ),
+            (
+                "pretty=true&output=ndjson&limit=1",
+                "{\"a\":\"1\",\"b\":\"multi\\nline\"}\n",
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pretty_prints_empty_output() -> Result<()> {
+        for (query, expected) in [
+            ("pretty=true", "[]"),
+            (
+                "pretty=true&envelope=true",
+                "{\n  \"records\": [],\n  \"error\": null\n}",
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), "");
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pretty_prints_multiple_files() -> Result<()> {
+        let req = build_multipart_request_with_files(
+            Request::builder().uri("/?multiple-files=true&pretty=true"),
+            &[("a.csv", "a\n1"), ("b.csv", "")],
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            "{\n  \"a.csv\": [\n    {\n      \"a\": \"1\"\n    }\n  ],\n  \"b.csv\": []\n}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_skip_comment_lines_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?comment=%23"),
+            "# generated by some tool\nfield1,field2\n1,2\n# trailing comment\n3,4",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_parse_cr_terminated_records() -> Result<()> {
+        let req = build_multipart_request(Request::builder(), "field1,field2\r1,2\r3,4\r");
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"2"},{"field1":"3","field2":"4"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_change_terminator_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?terminator=%7E"),
+            "field1,field2~1,\"multi\nline\"~3,4",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"field1":"1","field2":"multi\nline"},{"field1":"3","field2":"4"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_nest_dotted_headers_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?nest=true"),
+            "address.city,address.zip,name\nSpringfield,12345,Homer",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"address":{"city":"Springfield","zip":"12345"},"name":"Homer"}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_nest_dotted_headers_multiple_levels() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?nest=true&infer-types=true"),
+            "id,geo.point.lat,geo.point.lng,geo.name\n1,33.7,-118.1,Long Beach",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"[{"id":1,"geo":{"point":{"lat":33.7,"lng":-118.1},"name":"Long Beach"}}]"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_nested_header_collisions() -> Result<()> {
+        for (headers, collision) in [
+            (
+                "a,a.b",
+                r#"header "a" is used both as a field and as the parent of "a.b""#,
+            ),
+            (
+                "a.b.c,a.b",
+                r#"header "a.b" is used both as a field and as the parent of other fields"#,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri("/?nest=true&envelope=true"),
+                format!("{}\n1,2", headers),
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(envelope["records"], serde_json::json!([]));
+            assert!(
+                envelope["error"].as_str().unwrap().contains(collision),
+                "{}",
+                envelope["error"]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_include_columns_with_query_param() -> Result<()> {
+        let csv = "a,b,c
+1,2,3";
+        for (query, expected) in [
+            ("include=c,a", r#"[{"a":"1","c":"3"}]"#),
+            ("include=b,d", r#"[{"b":"2"}]"#),
+            ("include=d", r#"[{}]"#),
+            ("include=a,a", r#"[{"a":"1"}]"#),
+            ("include=1&has-headers=false", r#"[{"1":"b"},{"1":"2"}]"#),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_error_on_missing_included_columns_with_query_param() -> Result<()> {
+        for (query, expected) in [
+            ("include=a,c", Value::Null),
+            (
+                "include=b,d",
+                Value::from(r#"included column "d" is not in the CSV"#),
+            ),
+            (
+                "include=3&has-headers=false",
+                Value::from(r#"included column "3" is not in the CSV"#),
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?missing-columns=error&envelope=true&{}", query)),
+                "a,b,c
+1,2,3",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            match expected {
+                Value::Null => assert_eq!(envelope["error"], Value::Null, "{}", query),
+                expected => assert!(
+                    envelope["error"]
+                        .as_str()
+                        .unwrap()
+                        .contains(expected.as_str().unwrap()),
+                    "{}: {}",
+                    query,
+                    envelope["error"]
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handles_ragged_rows_with_query_param() -> Result<()> {
+        let short_row = "a,b,c\n1,2,3\n4,5";
+        let long_row = "a,b,c\n1,2,3\n4,5,6,7";
+        let short_row_error = Some("line 3: record has 2 fields, but there are 3 columns");
+        let long_row_error = Some("line 3: record has 4 fields, but there are 3 columns");
+        let first_record = r#"{"a":"1","b":"2","c":"3"}"#;
+        for (query, csv, expected_records, expected_error) in [
+            (
+                "",
+                short_row,
+                format!("[{}]", first_record),
+                short_row_error,
+            ),
+            (
+                "ragged=error",
+                long_row,
+                format!("[{}]", first_record),
+                long_row_error,
+            ),
+            (
+                "ragged=truncate",
+                short_row,
+                format!("[{}]", first_record),
+                short_row_error,
+            ),
+            (
+                "ragged=truncate",
+                long_row,
+                format!(r#"[{},{{"a":"4","b":"5","c":"6"}}]"#, first_record),
+                None,
+            ),
+            (
+                "ragged=pad",
+                short_row,
+                format!(r#"[{},{{"a":"4","b":"5","c":""}}]"#, first_record),
+                None,
+            ),
+            (
+                "ragged=pad&empty-as-null=true",
+                short_row,
+                format!(r#"[{},{{"a":"4","b":"5","c":null}}]"#, first_record),
+                None,
+            ),
+            (
+                "ragged=pad",
+                long_row,
+                format!("[{}]", first_record),
+                long_row_error,
+            ),
+            (
+                "ragged=pad&has-headers=false",
+                "1,2,3\n4,5",
+                r#"[{"0":"1","1":"2","2":"3"},{"0":"4","1":"5","2":""}]"#.to_string(),
+                None,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?envelope=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(&expected_records)?,
+                "{}: {:?}",
+                query,
+                csv
+            );
+            match expected_error {
+                None => assert_eq!(envelope["error"], Value::Null, "{}: {:?}", query, csv),
+                Some(expected) => assert!(
+                    envelope["error"].as_str().unwrap().contains(expected),
+                    "{}: {:?}: {}",
+                    query,
+                    csv,
+                    envelope["error"]
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_fast_on_records_with_too_many_fields_with_query_param() -> Result<()> {
+        // A comma separated file read with a semicolon delimiter has a single giant column, while data
+        // rows with semicolons in their values are split into many fields.
+        let csv = "name,tags\nalice,red\nbob,red;green;blue;yellow\n";
+        for (query, expected_records, expected_error) in [
+            (
+                "delimiter=;&ragged=truncate",
+                r#"[{"name,tags":"alice,red"},{"name,tags":"bob,red"}]"#,
+                None,
+            ),
+            (
+                "delimiter=;&ragged=truncate&max-field-factor=3",
+                r#"[{"name,tags":"alice,red"}]"#,
+                Some("line 3: record has 4 fields, more than 3 times the 1 columns; the delimiter ';' is probably wrong, try ',' instead"),
+            ),
+            (
+                "delimiter=;&ragged=truncate&max-field-factor=4",
+                r#"[{"name,tags":"alice,red"},{"name,tags":"bob,red"}]"#,
+                None,
+            ),
+            (
+                "max-field-factor=1",
+                r#"[{"name":"alice","tags":"red"},{"name":"bob","tags":"red;green;blue;yellow"}]"#,
+                None,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?envelope=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let envelope: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(expected_records)?,
+                "{}",
+                query
+            );
+            match expected_error {
+                None => assert_eq!(envelope["error"], Value::Null, "{}", query),
+                Some(expected) => assert!(
+                    envelope["error"].as_str().unwrap().contains(expected),
+                    "{}: {}",
+                    query,
+                    envelope["error"]
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_key_records_by_column_with_query_param() -> Result<()> {
+        let csv = "id,name,count\nb,bob,1\na,alice,2";
+        for (query, csv, expected) in [
+            (
+                "key-column=id",
+                csv,
+                r#"{"b":{"name":"bob","count":"1"},"a":{"name":"alice","count":"2"}}"#,
+            ),
+            (
+                "key-column=id&keep-key-column=true",
+                csv,
+                r#"{"b":{"id":"b","name":"bob","count":"1"},"a":{"id":"a","name":"alice","count":"2"}}"#,
+            ),
+            // The key is taken from the CSV as is, regardless of type inference and excluded columns.
+            (
+                "key-column=count&infer-types=true&exclude=name",
+                csv,
+                r#"{"1":{"id":"b"},"2":{"id":"a"}}"#,
+            ),
+            ("key-column=id", "id,name", "{}"),
+            (
+                "key-column=id&envelope=true",
+                csv,
+                r#"{"records":{"b":{"name":"bob","count":"1"},"a":{"name":"alice","count":"2"}},"error":null}"#,
+            ),
+            (
+                "key-column=id&pretty=true",
+                "id,name\nb,bob",
+                "{\n  \"b\": {\n    \"name\": \"bob\"\n  }\n}",
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK, "{}", query);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
         }
-        yield Bytes::copy_from_slice(&buffer);
-        buffer.clear();
+        Ok(())
+    }
 
-        // For all subsequent values, we have to emit a leading "," to separate each value in the JSON array.
-        for await value in values {
-            let value = value.context("failed to read from input stream")?;
-            buffer.push(b',');
-            serde_json::to_writer(&mut buffer, &value).context("failed to serialize value")?;
-            yield Bytes::copy_from_slice(&buffer);
-            buffer.clear();
+    #[tokio::test]
+    async fn reports_invalid_keys() -> Result<()> {
+        for (query, csv, expected_records, expected_error) in [
+            (
+                "key-column=id",
+                "id,name\na,alice\nb,bob\na,anne",
+                r#"{"a":{"name":"alice"},"b":{"name":"bob"}}"#,
+                r#"duplicate key \"a\""#,
+            ),
+            (
+                "key-column=missing",
+                "id,name\na,alice",
+                "{}",
+                r#"key column \"missing\" is not in the CSV"#,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?envelope=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(expected_records)?,
+                "{}",
+                query
+            );
+            assert!(
+                envelope["error"].to_string().contains(expected_error),
+                "{}: {}",
+                query,
+                envelope["error"]
+            );
         }
 
-        // Emit a final closing tag to finish the stream.
-        yield Bytes::from_static(b"]");
+        let req = build_multipart_request(
+            Request::builder().uri("/?key-column=id&output=ndjson"),
+            "id\na",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            &res_body,
+            r#"{"error":"invalid query parameters: key-column can only be used with JSON output","code":"INVALID_QUERY"}"#
+        );
+        Ok(())
     }
-}
 
-async fn convert_csv(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
-    let csv_parse_options = match serde_urlencoded::from_str::<CsvParseOptions>(
-        req.uri().query().unwrap_or_default(),
-    ) {
-        Ok(options) => options,
-        Err(error) => {
-            return Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(format!(r#"{{"error": "invalid query parameters: {}"}}"#, error).into())
+    #[tokio::test]
+    async fn can_convert_key_case_with_query_param() -> Result<()> {
+        let csv = "First Name,EmailAddress\nAda,ada@example.com";
+        for (query, csv, expected_records, expected_error) in [
+            (
+                "key-case=lower",
+                csv,
+                r#"[{"first name":"Ada","emailaddress":"ada@example.com"}]"#,
+                None,
+            ),
+            (
+                "key-case=snake",
+                csv,
+                r#"[{"first_name":"Ada","email_address":"ada@example.com"}]"#,
+                None,
+            ),
+            (
+                "key-case=camel&include=firstName",
+                csv,
+                r#"[{"firstName":"Ada"}]"#,
+                None,
+            ),
+            (
+                "key-case=lower",
+                "Name,name\na,b",
+                "[]",
+                Some(r#"header \"name\" is used by both column 1 and column 2"#),
+            ),
+            (
+                "key-case=lower&dedupe-headers=suffix",
+                "Name,name\na,b",
+                r#"[{"name":"a","name_2":"b"}]"#,
+                None,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?envelope=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(expected_records)?,
+                "{}",
+                query
+            );
+            match expected_error {
+                None => assert_eq!(envelope["error"], Value::Null, "{}", query),
+                Some(expected) => assert!(
+                    envelope["error"].to_string().contains(expected),
+                    "{}: {}",
+                    query,
+                    envelope["error"]
+                ),
+            }
         }
-    };
+        Ok(())
+    }
 
-    let boundary = req
-        .headers()
-        .get(hyper::header::CONTENT_TYPE)
-        .and_then(|ct| ct.to_str().ok())
-        .and_then(|ct| multer::parse_boundary(ct).ok());
-    let boundary = match boundary {
-        Some(boundary) => boundary,
-        None => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(
-                    r#"{"error":"missing boundary in multipart content type"}"#,
-                ))
-                .unwrap())
-        }
-    };
-    let (file_name, csv_file) = match read_multipart(req.into_body(), boundary).await {
-        Some(res) => res,
-        None => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(
-                    r#"{"error":"missing required multipart file field"}"#,
-                ))
-                .unwrap())
-        }
-    };
-    let csv_records = parse_csv_records(
-        csv_parse_options,
-        csv_file
-            // KLUDGE: csv_async currently requires errors to be std::io::Error since it assumes it's reading from
-            //         an io device directly. We're just mapping all errors as std::io::ErrorKind::Other for now, but
-            //         we could be more finely detailed if it turns out csv_async handles some std::io::Error variants
-            //         specially.
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error)),
-    );
-    let response = serialize_json_seq(csv_records).inspect_err(|error| {
-        // TODO: look for some trace header and log that with errors for more easily tracing errors and associate them
-        //       with requests.
-        eprintln!("error during CSV conversion: {:?}", error);
-    });
-    let download_file_name = replace_file_extension(&file_name, "json")
-        .ok()
-        .unwrap_or("download.csv".to_string());
-    Response::builder()
-        // NOTE: according to https://github.com/eligrey/FileSaver.js/wiki/Saving-a-remote-file it is better to
-        //       use octent-stream over the actual mime type when trying to stream data so that browsers don't
-        //       try to render the result, but instead force a file-save dialog.
-        .header(CONTENT_TYPE, "application/octet-stream; charset=utf-8")
-        .header(
-            CONTENT_DISPOSITION,
-            format!(
-                r#"attachment; filename="{0}"; filename*="{0}""#,
-                download_file_name
+    #[tokio::test]
+    async fn handles_duplicate_headers_with_query_param() -> Result<()> {
+        for (query, csv, expected_records, expected_error) in [
+            (
+                "",
+                "id,name,id\n1,a,2",
+                "[]",
+                Some(r#"header \"id\" is used by both column 1 and column 3"#),
             ),
-        )
-        .body(Body::wrap_stream(response))
-}
+            (
+                "dedupe-headers=suffix",
+                "id,name,id,id\n1,a,2,3",
+                r#"[{"id":"1","name":"a","id_2":"2","id_3":"3"}]"#,
+                None,
+            ),
+            (
+                "dedupe-headers=suffix",
+                "id,id,id_2\n1,2,3",
+                r#"[{"id":"1","id_3":"2","id_2":"3"}]"#,
+                None,
+            ),
+            (
+                "dedupe-headers=suffix&include=id_2",
+                "id,id\n1,2",
+                r#"[{"id_2":"2"}]"#,
+                None,
+            ),
+            // Without headers the columns are numbered, so they can't be duplicates.
+            (
+                "has-headers=false",
+                "id,id\n1,2",
+                r#"[{"0":"id","1":"id"},{"0":"1","1":"2"}]"#,
+                None,
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?envelope=true&{}", query)),
+                csv,
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(expected_records)?,
+                "{}: {:?}",
+                query,
+                csv
+            );
+            match expected_error {
+                None => assert_eq!(envelope["error"], Value::Null, "{}: {:?}", query, csv),
+                Some(expected) => assert!(
+                    envelope["error"].to_string().contains(expected),
+                    "{}: {:?}: {}",
+                    query,
+                    csv,
+                    envelope["error"]
+                ),
+            }
+        }
+        Ok(())
+    }
 
-async fn route_request(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
-    println!("got request: {:?}", &req);
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/") => convert_csv(req).await,
-        _ => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty()),
+    #[tokio::test]
+    async fn enforces_column_and_cell_limits() -> Result<()> {
+        let config = Config {
+            max_columns: Some(3),
+            max_cell_bytes: Some(5),
+            ..Config::default()
+        };
+        for (csv, expected_records, expected_error) in [
+            ("a,b,c\n1,2,3", r#"[{"a":"1","b":"2","c":"3"}]"#, None),
+            (
+                "a,b,c\n12345,2,3",
+                r#"[{"a":"12345","b":"2","c":"3"}]"#,
+                None,
+            ),
+            (
+                "a,b,c\n1,2,3\n1,2,3,4",
+                r#"[{"a":"1","b":"2","c":"3"}]"#,
+                Some("line 3: record has 4 fields, more than the limit of 3"),
+            ),
+            (
+                "a,b,c\n1,2,3\n1,123456,3",
+                r#"[{"a":"1","b":"2","c":"3"}]"#,
+                Some("line 3: field 2 is more than the limit of 5 bytes"),
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri("/?envelope=true&ragged=truncate"),
+                csv,
+            );
+            let res = convert_csv(&config, req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            let envelope: Value = serde_json::from_str(&res_body)?;
+            assert_eq!(
+                envelope["records"],
+                serde_json::from_str::<Value>(expected_records)?,
+                "{:?}",
+                csv
+            );
+            match expected_error {
+                None => assert_eq!(envelope["error"], Value::Null, "{:?}", csv),
+                Some(expected) => assert!(
+                    envelope["error"].as_str().unwrap().contains(expected),
+                    "{:?}: {}",
+                    csv,
+                    envelope["error"]
+                ),
+            }
+        }
+        Ok(())
     }
-}
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    #[clap(short, long, default_value_t = 8000)]
-    port: u16,
-}
+    #[tokio::test]
+    async fn stops_reading_oversized_fields_at_the_limit() -> Result<()> {
+        let config = Config {
+            max_cell_bytes: Some(64 * 1024),
+            ..Config::default()
+        };
+        // The last field never ends, so the conversion would go on buffering it forever if the limit
+        // were only checked once the whole record had been read.
+        let chunks_read = Arc::new(AtomicU64::new(0));
+        let counter = chunks_read.clone();
+        let input = futures::stream::once(async {
+            Ok::<_, std::io::Error>(Bytes::from_static(b"id,note\n1,short\n2,\""))
+        })
+        .chain(futures::stream::repeat_with(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::from(vec![b'x'; 1024]))
+        }));
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/?envelope=true")
+            .header(CONTENT_TYPE, "text/csv")
+            .body(Body::wrap_stream(input))?;
+        let res = convert_csv(&config, req).await?;
+        let res_body =
+            tokio::time::timeout(Duration::from_secs(5), read_to_string(res.into_body())).await?;
+        let envelope: Value = serde_json::from_str(&res_body)?;
+        assert_eq!(
+            envelope["records"],
+            serde_json::json!([{"id": "1", "note": "short"}])
+        );
+        assert!(
+            envelope["error"]
+                .as_str()
+                .unwrap()
+                .contains("line 3: field 2 is more than the limit of 65536 bytes"),
+            "{}",
+            envelope["error"]
+        );
+        assert!(chunks_read.load(Ordering::SeqCst) <= 65);
+        Ok(())
+    }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+    #[tokio::test]
+    async fn rejects_header_rows_over_the_limits() -> Result<()> {
+        let config = Config {
+            max_columns: Some(3),
+            max_cell_bytes: Some(5),
+            ..Config::default()
+        };
+        let wide_header = vec!["a"; 100_000].join(",");
+        let long_header = "a".repeat(1_000_000);
+        for (csv, expected_error) in [
+            (
+                "a,b,c,d\n1,2,3,4".to_string(),
+                "header row has more fields than the limit of 3",
+            ),
+            (
+                "a,bbbbbb\n1,2".to_string(),
+                "field 2 of the header row is more than the limit of 5 bytes",
+            ),
+            (
+                format!("{}\n1", wide_header),
+                "header row has more fields than the limit of 3",
+            ),
+            (
+                format!("{}\n1", long_header),
+                "field 1 of the header row is more than the limit of 5 bytes",
+            ),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri("/?envelope=true&ragged=truncate"),
+                &csv,
+            );
+            let res = convert_csv(&config, req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let res_body: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+            assert_eq!(res_body["code"], "CONVERSION_FAILED");
+            assert!(
+                res_body["error"].as_str().unwrap().contains(expected_error),
+                "{}",
+                res_body["error"]
+            );
+        }
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+        // Without headers, the first row is a record like any other.
+        let req = build_multipart_request(
+            Request::builder().uri("/?envelope=true&has-headers=false"),
+            "a,b,c,d\n1,2,3",
+        );
+        let res = convert_csv(&config, req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
 
-    let csv_service =
-        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(route_request)) });
+    #[tokio::test]
+    async fn can_normalize_dates_with_query_param() -> Result<()> {
+        let csv = "id,date,other\n1,01/02/2023,01/02/2023\n2,2023-01-02,\n3,Jan 2 2023,\n4,\"January 2, 2023\",\n5,,\n6,soon,";
+        let req = build_multipart_request(
+            Request::builder().uri("/?date-columns=date&output=ndjson"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            res_body.lines().collect::<Vec<_>>(),
+            [
+                r#"{"id":"1","date":"2023-01-02","other":"01/02/2023"}"#,
+                r#"{"id":"2","date":"2023-01-02","other":""}"#,
+                r#"{"id":"3","date":"2023-01-02","other":""}"#,
+                r#"{"id":"4","date":"2023-01-02","other":""}"#,
+                r#"{"id":"5","date":"","other":""}"#,
+                r#"{"id":"6","date":"soon","other":""}"#,
+            ]
+        );
 
-    let server = Server::bind(&addr).serve(csv_service);
+        let req = build_multipart_request(
+            Request::builder().uri("/?date-columns=a,b&date-format=%25d%2F%25m%2F%25Y"),
+            "a,b\n01/02/2023,31/12/1999",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, r#"[{"a":"2023-02-01","b":"1999-12-31"}]"#);
 
-    println!("listening on {}", server.local_addr());
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+        let req = build_multipart_request(
+            Request::builder().uri("/?date-columns=date&invalid-dates=error&envelope=true"),
+            "date\n2023-01-02\nsoon",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let envelope: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(
+            envelope["records"],
+            serde_json::json!([{"date": "2023-01-02"}])
+        );
+        assert!(
+            envelope["error"]
+                .as_str()
+                .unwrap()
+                .contains(r#""soon" in column "date" is not a valid date"#),
+            "{}",
+            envelope
+        );
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::TryStreamExt;
-    use hyper::header::HeaderValue;
-    use pretty_assertions::assert_eq;
+    #[tokio::test]
+    async fn can_coerce_column_types_with_query_param() -> Result<()> {
+        let csv = "id,price,active,name,code\n1,9.99,true,Widget,007\n2,10,FALSE,,\n3,,,Gadget,42";
+        let req = build_multipart_request(
+            Request::builder()
+                .uri("/?types=id:int,price:float,active:bool,code:string&output=ndjson"),
+            csv,
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(
+            res_body.lines().collect::<Vec<_>>(),
+            [
+                r#"{"id":1,"price":9.99,"active":true,"name":"Widget","code":"007"}"#,
+                r#"{"id":2,"price":10.0,"active":false,"name":"","code":""}"#,
+                r#"{"id":3,"price":null,"active":null,"name":"Gadget","code":"42"}"#,
+            ]
+        );
 
-    const BOUNDARY: &str = "X-BOUNDARY";
+        let req = build_multipart_request(
+            Request::builder().uri("/?types=id:int,price:float&envelope=true"),
+            "id,price\n1,9.99\n2,cheap",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        let envelope: Value = serde_json::from_str(&read_to_string(res.into_body()).await)?;
+        assert_eq!(
+            envelope["records"],
+            serde_json::json!([{"id": 1, "price": 9.99}])
+        );
+        assert!(
+            envelope["error"]
+                .as_str()
+                .unwrap()
+                .contains(r#"line 3: "cheap" in column "price" is not a valid float"#),
+            "{}",
+            envelope
+        );
 
-    fn build_multipart_request(
-        request: hyper::http::request::Builder,
-        data: &str,
-    ) -> Request<Body> {
-        request
-            .header(
-                CONTENT_TYPE,
-                format!("multipart/form-data; boundary={}", BOUNDARY),
-            )
-            .body(Body::from(format!(
-                "--{0}\r\nContent-Disposition: form-data; name=\"field\"; filename=\"example.csv\"\r\n\r\n{1}\r\n--{0}\r\n",
-                BOUNDARY, data
-            )))
-            .unwrap()
+        for query in ["types=id", "types=id:integer", "types=id:int,"] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+        Ok(())
     }
 
-    async fn read_to_string(body: Body) -> String {
-        body.try_fold(String::new(), |output, bytes| async move {
-            let parsed = std::str::from_utf8(&bytes).unwrap();
-            Ok(output + parsed)
-        })
-        .await
-        .unwrap()
+    #[tokio::test]
+    async fn can_exclude_columns_with_query_param() -> Result<()> {
+        let csv = "a,b,c
+1,2,3";
+        for (query, expected) in [
+            ("exclude=b", r#"[{"a":"1","c":"3"}]"#),
+            ("exclude=c,a,d", r#"[{"b":"2"}]"#),
+            ("exclude=a,b,c", r#"[{}]"#),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", query);
+        }
+        Ok(())
     }
 
     #[tokio::test]
-    async fn empty_csv() -> Result<()> {
-        let req = build_multipart_request(Request::builder(), "");
-        let res = convert_csv(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
+    async fn rejects_both_include_and_exclude() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?include=a&exclude=b"),
+            "a,b
+1,2",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
         let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, "[]");
+        assert!(res_body.contains("include and exclude"), "{}", res_body);
         Ok(())
     }
 
     #[tokio::test]
-    async fn returns_nothing_when_only_headers() -> Result<()> {
-        let req = build_multipart_request(Request::builder(), "field1,field2,field3");
-        let res = convert_csv(req).await?;
+    async fn can_limit_records_with_query_param() -> Result<()> {
+        let csv = (1..=100).fold("n\n".to_string(), |csv, n| format!("{}{}\n", csv, n));
+        let req = build_multipart_request(Request::builder().uri("/?limit=5"), csv);
+        let res = convert_csv(&Config::default(), req).await?;
         assert_eq!(res.status(), StatusCode::OK);
         let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, "[]");
+        assert_eq!(
+            &res_body,
+            r#"[{"n":"1"},{"n":"2"},{"n":"3"},{"n":"4"},{"n":"5"}]"#
+        );
         Ok(())
     }
 
     #[tokio::test]
-    async fn returns_single_record_for_single_line() -> Result<()> {
-        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3");
-        let res = convert_csv(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
-        let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+    async fn stops_reading_input_at_limit() -> Result<()> {
+        // The body never ends, so the response can only finish if the rest of the input isn't read.
+        let (mut sender, body) = Body::channel();
+        sender.send_data(Bytes::from("n\n1\n2\n3\n")).await?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/?limit=2")
+            .header(CONTENT_TYPE, "text/csv")
+            .body(body)?;
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body =
+            tokio::time::timeout(Duration::from_secs(5), read_to_string(res.into_body())).await?;
+        assert_eq!(&res_body, r#"[{"n":"1"},{"n":"2"}]"#);
+        drop(sender);
         Ok(())
     }
 
     #[tokio::test]
-    async fn returns_multiple_records_for_multiple_lines() -> Result<()> {
-        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3\n4,5,6");
-        let res = convert_csv(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
+    async fn can_convert_byte_ranges_with_query_param() -> Result<()> {
+        // The records start at bytes 6, 12, 21 and 29.
+        let csv = "id,n\r\n1,one\n2,\"t,wo\"\n3,three\n4,four\n";
+        let convert_range = |range: &str| {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?byte-range={}&output=ndjson", range)),
+                csv,
+            );
+            let range = range.to_string();
+            async move {
+                let res = convert_csv(&Config::default(), req).await?;
+                assert_eq!(res.status(), StatusCode::OK, "{}", range);
+                Result::<_>::Ok(read_to_string(res.into_body()).await)
+            }
+        };
+        let one = r#"{"id":"1","n":"one"}"#;
+        let two = r#"{"id":"2","n":"t,wo"}"#;
+        let three = r#"{"id":"3","n":"three"}"#;
+        let four = r#"{"id":"4","n":"four"}"#;
+        for (ranges, expected) in [
+            (vec!["0-21", "21-"], vec![vec![one, two], vec![three, four]]),
+            (
+                vec!["0-13", "13-1000"],
+                vec![vec![one, two], vec![three, four]],
+            ),
+            (vec!["0-12", "12-"], vec![vec![one], vec![two, three, four]]),
+            (
+                vec!["0-1", "1-29", "29-30", "30-"],
+                vec![vec![], vec![one, two, three], vec![four], vec![]],
+            ),
+        ] {
+            for (range, expected) in ranges.into_iter().zip(expected) {
+                let res_body = convert_range(range).await?;
+                assert_eq!(res_body.lines().collect::<Vec<_>>(), expected, "{}", range);
+            }
+        }
+
+        // Ranges are selected the same way when the input is split into many chunks.
+        let chunks =
+            futures::stream::iter(csv.bytes().map(|byte| Ok::<_, std::io::Error>(vec![byte])));
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/?byte-range=13-30&output=ndjson")
+            .header(CONTENT_TYPE, "text/csv")
+            .body(Body::wrap_stream(chunks))?;
+        let res = convert_csv(&Config::default(), req).await?;
         let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(
-            &res_body,
-            r#"[{"field1":"1","field2":"2","field3":"3"},{"field1":"4","field2":"5","field3":"6"}]"#
+        assert_eq!(res_body.lines().collect::<Vec<_>>(), [three, four]);
+
+        // Lines that are skipped come before the header row, and so are read from the start as well.
+        let req = build_multipart_request(
+            Request::builder().uri("/?byte-range=15-&skip-rows=1&output=ndjson"),
+            "skipped\nid\n1\n2\n3",
         );
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(res_body.lines().collect::<Vec<_>>(), [r#"{"id":"3"}"#]);
+
+        for query in [
+            "byte-range=10",
+            "byte-range=10-5",
+            "byte-range=-5",
+            "byte-range=0-10&encoding=utf-16le",
+        ] {
+            let req = build_multipart_request(Request::builder().uri(format!("/?{}", query)), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
         Ok(())
     }
 
     #[tokio::test]
-    async fn can_parse_quoted_fields() -> Result<()> {
-        let req =
-            build_multipart_request(Request::builder(), "\"field1\",field2,field3\n1,\"2\",3");
-        let res = convert_csv(req).await?;
+    async fn can_skip_rows_with_query_param() -> Result<()> {
+        let req = build_multipart_request(
+            Request::builder().uri("/?skip-rows=2"),
+            "Bird Survey Export\r\nGenerated 2022-04-08, all sites\r\ndate,number\r\n2022-04-06,12",
+        );
+        let res = convert_csv(&Config::default(), req).await?;
         assert_eq!(res.status(), StatusCode::OK);
         let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+        assert_eq!(&res_body, r#"[{"date":"2022-04-06","number":"12"}]"#);
         Ok(())
     }
 
     #[tokio::test]
-    async fn can_parse_newslines_in_quoted_fields() -> Result<()> {
+    async fn can_skip_blank_lines_with_query_param() -> Result<()> {
+        let csv = "a,b\n1,2\n\n,\n \t\n , \n\"\",\r\n3,4\n\n,x\n";
+        for (uri, expected) in [
+            // Lines without any bytes are always skipped, but other blank lines are records.
+            (
+                "/?ragged=pad",
+                r#"[{"a":"1","b":"2"},{"a":"","b":""},{"a":" \t","b":""},{"a":" ","b":" "},{"a":"","b":""},{"a":"3","b":"4"},{"a":"","b":"x"}]"#,
+            ),
+            (
+                "/?skip-blank-lines=true&add-row-number=row",
+                r#"[{"row":1,"a":"1","b":"2"},{"row":2,"a":"3","b":"4"},{"row":3,"a":"","b":"x"}]"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), csv);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_convert_fixed_width_fields_with_query_param() -> Result<()> {
+        let fixed = "id  name      born\r\n\
+                     1   Ada Lov   1815\r\n\
+                     \r\n\
+                     2   Grace     1906 extra\r\n\
+                     3   Édith\r\n";
+        for (uri, status, expected) in [
+            (
+                "/?format=fixed&widths=4,10,4&ragged=truncate&infer-types=true",
+                StatusCode::OK,
+                r#"[{"id":1,"name":"Ada Lov","born":1815},{"id":2,"name":"Grace","born":1906},{"id":3,"name":"Édith","born":null}]"#,
+            ),
+            (
+                "/?format=fixed&widths=4,10,4&has-headers=false&headers=a,b,c&limit=1",
+                StatusCode::OK,
+                r#"[{"a":"id","b":"name","c":"born"}]"#,
+            ),
+            (
+                "/?format=fixed&widths=4,10,4&envelope=true",
+                StatusCode::OK,
+                r#"{"records":[{"id":"1","name":"Ada Lov","born":"1815"}],"error":"failed to read from input stream: line 4: record has 4 fields, but there are 3 columns"}"#,
+            ),
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), fixed);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), status, "{}", uri);
+            assert_eq!(read_to_string(res.into_body()).await, expected, "{}", uri);
+        }
+
+        for uri in [
+            "/?format=fixed",
+            "/?format=fixed&widths=4,0",
+            "/?format=fixed&widths=4,10&delimiter=%3B",
+            "/?widths=4,10",
+        ] {
+            let req = build_multipart_request(Request::builder().uri(uri), fixed);
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_trim_whitespace_with_query_param() -> Result<()> {
+        for (trim, expected) in [
+            ("none", r#"[{"name":" alice"," age ":" 30 "}]"#),
+            ("headers", r#"[{"name":" alice","age":" 30 "}]"#),
+            ("fields", r#"[{"name":"alice"," age ":"30"}]"#),
+            ("all", r#"[{"name":"alice","age":"30"}]"#),
+        ] {
+            let req = build_multipart_request(
+                Request::builder().uri(format!("/?trim={}", trim)),
+                "name, age \n alice, 30 ",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res_body = read_to_string(res.into_body()).await;
+            assert_eq!(&res_body, expected, "trim={}", trim);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strips_utf8_bom() -> Result<()> {
+        for uri in ["/", "/?encoding-errors=replace"] {
+            let req = build_multipart_request(
+                Request::builder().uri(uri),
+                b"\xEF\xBB\xBFid,name\n1,\xEF\xBB\xBFa",
+            );
+            let res = convert_csv(&Config::default(), req).await?;
+            let res_body = read_to_string(res.into_body()).await;
+            // Only the BOM at the start of the input is removed.
+            assert_eq!(
+                &res_body, "[{\"id\":\"1\",\"name\":\"\u{feff}a\"}]",
+                "{}",
+                uri
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_transcode_input_encoding_with_query_param() -> Result<()> {
         let req = build_multipart_request(
-            Request::builder(),
-            "\"field1\",field2,field3\n1,\"2 &\n 3\",4",
+            Request::builder().uri("/?encoding=windows-1252"),
+            b"name,city\nJos\xe9,M\xfcnchen\n\x93quoted\x94,Z\xfcrich",
         );
-        let res = convert_csv(req).await?;
+        let res = convert_csv(&Config::default(), req).await?;
         assert_eq!(res.status(), StatusCode::OK);
         let res_body = read_to_string(res.into_body()).await;
         assert_eq!(
             &res_body,
-            r#"[{"field1":"1","field2":"2 &\n 3","field3":"4"}]"#
+            r#"[{"name":"José","city":"München"},{"name":"“quoted”","city":"Zürich"}]"#
         );
         Ok(())
     }
 
     #[tokio::test]
+    async fn can_replace_invalid_input_bytes_with_query_param() -> Result<()> {
+        let input = b"name\nJos\xe9";
+        let req =
+            build_multipart_request(Request::builder().uri("/?encoding-errors=replace"), input);
+        let res = convert_csv(&Config::default(), req).await?;
+        let res_body = read_to_string(res.into_body()).await;
+        assert_eq!(&res_body, "[{\"name\":\"Jos\u{fffd}\"}]");
 
-    async fn can_change_delimiter_with_query_param() -> Result<()> {
         let req = build_multipart_request(
-            Request::builder().uri("/?delimiter=%09"),
-            "field1\tfield2\tfield3\n1\t2\t3",
+            Request::builder().uri("/?encoding=utf-8&envelope=true"),
+            input,
         );
-        let res = convert_csv(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
+        let res = convert_csv(&Config::default(), req).await?;
         let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+        let envelope: Value = serde_json::from_str(&res_body)?;
+        assert!(envelope["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid utf-8"));
         Ok(())
     }
 
     #[tokio::test]
-
-    async fn can_change_quote_char_with_query_param() -> Result<()> {
-        let req = build_multipart_request(
-            Request::builder().uri("/?quote=%27"),
-            "field1,'field2','field3'\n1,'2',3",
-        );
-        let res = convert_csv(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
-        let res_body = read_to_string(res.into_body()).await;
-        assert_eq!(&res_body, r#"[{"field1":"1","field2":"2","field3":"3"}]"#);
+    async fn rejects_unknown_encoding() -> Result<()> {
+        let req = build_multipart_request(Request::builder().uri("/?encoding=klingon"), "a\n1");
+        let res = convert_csv(&Config::default(), req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
         Ok(())
     }
 
     #[tokio::test]
+    async fn times_out_stalled_requests() -> Result<()> {
+        let config = Config {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        // Keep the sender alive without ever sending anything, so the body stalls.
+        let (_sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)?;
+        let res = route_request(Arc::new(config), req).await?;
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        Ok(())
+    }
 
-    async fn responds_with_content_disposition_header() -> Result<()> {
-        let req = build_multipart_request(Request::builder(), "field1,field2,field3\n1,2,3");
-        let res = convert_csv(req).await?;
-
-        assert_eq!(
-            res.headers().get("content-disposition"),
-            Some(&HeaderValue::from_static(
-                r#"attachment; filename="example.json"; filename*="example.json""#
-            ))
-        );
+    #[tokio::test]
+    async fn stops_streaming_when_upload_stalls() -> Result<()> {
+        let config = Config {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let (mut sender, body) = Body::channel();
+        sender
+            .send_data(Bytes::from(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"field\"; filename=\"example.csv\"\r\n\r\na,b\n1,2\n",
+                BOUNDARY
+            )))
+            .await?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)?;
+        let res = route_request(Arc::new(config), req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let chunks: Vec<_> = StreamExt::collect(res.into_body()).await;
+        assert!(chunks.last().unwrap().is_err());
+        drop(sender);
         Ok(())
     }
 }
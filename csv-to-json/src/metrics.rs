@@ -0,0 +1,171 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the buckets of the request duration histogram.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A Prometheus histogram of durations, with fixed buckets.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Number of observations that fall into each bucket, not including those of smaller buckets.
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS
+            .iter()
+            .position(|&upper_bound| seconds <= upper_bound)
+        {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        // Prometheus buckets are cumulative, so each one includes the observations of all smaller buckets.
+        let mut cumulative = 0;
+        for (upper_bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name, upper_bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(output, "{}_sum {}", name, sum);
+        let _ = writeln!(output, "{}_count {}", name, count);
+    }
+}
+
+/// Server-wide counters for monitoring, exposed in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    records_converted: AtomicU64,
+    conversion_errors: AtomicU64,
+    request_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_record(&self) {
+        self.records_converted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_conversion_error(&self) {
+        self.conversion_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long it took to handle a request, up until its response started.
+    pub fn observe_request_duration(&self, duration: Duration) {
+        self.request_duration.observe(duration);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, help, counter) in [
+            (
+                "csv_to_json_requests_total",
+                "Total number of HTTP requests received.",
+                &self.requests,
+            ),
+            (
+                "csv_to_json_received_bytes_total",
+                "Total number of bytes of input read for conversions.",
+                &self.bytes_received,
+            ),
+            (
+                "csv_to_json_sent_bytes_total",
+                "Total number of bytes of converted output sent, before compression.",
+                &self.bytes_sent,
+            ),
+            (
+                "csv_to_json_records_converted_total",
+                "Total number of records converted.",
+                &self.records_converted,
+            ),
+            (
+                "csv_to_json_conversion_errors_total",
+                "Total number of conversions that failed.",
+                &self.conversion_errors,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        self.request_duration.render(
+            &mut output,
+            "csv_to_json_request_duration_seconds",
+            "Time taken to handle a request, up until its response started.",
+        );
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let metrics = Metrics::default();
+        metrics.observe_request_duration(Duration::from_millis(3));
+        metrics.observe_request_duration(Duration::from_millis(40));
+        metrics.observe_request_duration(Duration::from_secs(60));
+        let output = metrics.render();
+        let histogram = output
+            .lines()
+            .filter(|line| line.starts_with("csv_to_json_request_duration_seconds"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            histogram,
+            [
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.005\"} 1",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.01\"} 1",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.025\"} 1",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.05\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.1\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.25\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"0.5\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"1\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"2.5\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"5\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"10\"} 2",
+                "csv_to_json_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+                "csv_to_json_request_duration_seconds_sum 60.043",
+                "csv_to_json_request_duration_seconds_count 3",
+            ]
+        );
+    }
+}
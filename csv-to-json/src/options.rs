@@ -0,0 +1,1000 @@
+use chrono::NaiveDate;
+use clap::ArgEnum;
+use encoding_rs::Encoding;
+use serde::de::value::MapDeserializer;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+const fn default_quote() -> char {
+    '"'
+}
+
+const fn default_has_headers() -> bool {
+    true
+}
+
+/// Preset dialects of delimiter-separated values, which determine the default delimiter, or
+/// fixed-width fields.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvFormat {
+    /// Comma-separated values.
+    #[default]
+    Csv,
+    /// Tab-separated values.
+    Tsv,
+    /// Semicolon-separated values, as commonly used in locales with a decimal comma.
+    Ssv,
+    /// Fixed-width fields, sliced from each line by the `widths` rather than split on a delimiter.
+    Fixed,
+}
+
+impl CsvFormat {
+    pub(crate) fn delimiter(self) -> char {
+        match self {
+            CsvFormat::Csv => ',',
+            CsvFormat::Tsv => '\t',
+            CsvFormat::Ssv => ';',
+            // NOTE: fixed-width lines aren't split on a delimiter, but the option still needs one.
+            CsvFormat::Fixed => ',',
+        }
+    }
+}
+
+/// Field delimiter given with the `delimiter` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delimiter {
+    /// Detected from the first line of the CSV, as the most common of `,`, `;`, a tab and `|`.
+    Auto,
+    Char(char),
+}
+
+/// Names that can be given instead of a literal character for the delimiter, quote and other single
+/// character options, since control characters and punctuation are awkward to URL-encode.
+const CHAR_NAMES: [(&str, char); 9] = [
+    ("tab", '\t'),
+    ("space", ' '),
+    ("comma", ','),
+    ("semicolon", ';'),
+    ("pipe", '|'),
+    ("single", '\''),
+    ("double", '"'),
+    ("backslash", '\\'),
+    ("hash", '#'),
+];
+
+/// Parses a single character option, which is either a literal character or one of the CHAR_NAMES.
+fn parse_char(value: &str) -> Result<char, String> {
+    if let Some(&(_, named)) = CHAR_NAMES.iter().find(|(name, _)| *name == value) {
+        return Ok(named);
+    }
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(char), None) => Ok(char),
+        _ => Err(format!(
+            "invalid character {:?}, expected a single character or one of {}",
+            value,
+            CHAR_NAMES.map(|(name, _)| name).join(", ")
+        )),
+    }
+}
+
+fn deserialize_char<'de, D>(deserializer: D) -> Result<char, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_char(&value).map_err(D::Error::custom)
+}
+
+fn deserialize_optional_char<'de, D>(deserializer: D) -> Result<Option<char>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_char(deserializer).map(Some)
+}
+
+/// Parses a delimiter, which is either a single character, one of the CHAR_NAMES, or `auto`.
+fn parse_delimiter(value: &str) -> Result<Delimiter, String> {
+    if value == "auto" {
+        return Ok(Delimiter::Auto);
+    }
+    parse_char(value).map(Delimiter::Char).map_err(|_| {
+        format!(
+            "invalid delimiter {:?}, expected a single character, one of {}, or auto",
+            value,
+            CHAR_NAMES.map(|(name, _)| name).join(", ")
+        )
+    })
+}
+
+fn deserialize_delimiter<'de, D>(deserializer: D) -> Result<Option<Delimiter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_delimiter(&value).map(Some).map_err(D::Error::custom)
+}
+
+/// Which parts of a CSV should have leading and trailing whitespace trimmed.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrimMode {
+    #[default]
+    None,
+    Headers,
+    Fields,
+    All,
+}
+
+impl From<TrimMode> for csv_async::Trim {
+    fn from(mode: TrimMode) -> Self {
+        match mode {
+            TrimMode::None => csv_async::Trim::None,
+            TrimMode::Headers => csv_async::Trim::Headers,
+            TrimMode::Fields => csv_async::Trim::Fields,
+            TrimMode::All => csv_async::Trim::All,
+        }
+    }
+}
+
+/// How byte sequences that are invalid in the input encoding are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncodingErrors {
+    /// Fail the conversion with an error.
+    #[default]
+    Strict,
+    /// Replace the invalid bytes with the U+FFFD replacement character.
+    Replace,
+}
+
+/// Looks up an input encoding by its WHATWG label, e.g. `windows-1252` or `latin1`.
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding {:?}", label))
+}
+
+fn deserialize_encoding<'de, D>(deserializer: D) -> Result<Option<&'static Encoding>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let label = String::deserialize(deserializer)?;
+    parse_encoding(&label).map(Some).map_err(D::Error::custom)
+}
+
+/// Range of bytes of the input whose records are converted with the `byte-range` option. The end is
+/// exclusive, and without one the range extends to the end of the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// Parses a byte range given as `start-end`, or as `start-` for a range to the end of the input.
+fn parse_byte_range(value: &str) -> Result<ByteRange, String> {
+    let invalid = || format!("invalid byte range {:?}, expected start-end", value);
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    let start = start.parse().map_err(|_| invalid())?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().map_err(|_| invalid())?),
+    };
+    if end.is_some_and(|end| end <= start) {
+        return Err(format!(
+            "invalid byte range {:?}, the end must be after the start",
+            value
+        ));
+    }
+    Ok(ByteRange { start, end })
+}
+
+fn deserialize_byte_range<'de, D>(deserializer: D) -> Result<Option<ByteRange>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_byte_range(&value).map(Some).map_err(D::Error::custom)
+}
+
+/// How columns named in the `include` option that aren't in the CSV are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingColumns {
+    /// Silently leave the column out of the output.
+    #[default]
+    Ignore,
+    /// Fail the conversion with an error.
+    Error,
+}
+
+/// How fields in the `date-columns` that can't be parsed as dates are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InvalidDates {
+    /// Output the field as it is.
+    #[default]
+    Keep,
+    /// Fail the conversion with an error.
+    Error,
+}
+
+/// How inferred numbers that can't be represented exactly by a 64-bit integer or float are output:
+/// integers beyond 64 bits, and decimals with more significant digits than a float holds.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BigNumbers {
+    /// Output them as floats, losing precision.
+    #[default]
+    Float,
+    /// Output them as strings, exactly as they are in the CSV.
+    String,
+    /// Output integers that fit in 128 bits exactly, and anything else as a float.
+    I128,
+}
+
+/// JSON type that the fields of a column are coerced to with the `types` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    Int,
+    Float,
+    Bool,
+    String,
+}
+
+impl FieldType {
+    const NAMES: [(&'static str, FieldType); 4] = [
+        ("int", FieldType::Int),
+        ("float", FieldType::Float),
+        ("bool", FieldType::Bool),
+        ("string", FieldType::String),
+    ];
+
+    /// Returns the name of the type, as it's given in the `types` option.
+    pub fn name(self) -> &'static str {
+        FieldType::NAMES
+            .iter()
+            .find(|(_, field_type)| *field_type == self)
+            .map(|(name, _)| *name)
+            .expect("every type has a name")
+    }
+}
+
+/// Parses a `column:type` pair of the `types` option. The column name is everything before the last
+/// colon, so that names may contain colons themselves.
+fn parse_column_type(value: &str) -> Result<(String, FieldType), String> {
+    let (column, name) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("invalid column type {:?}, expected column:type", value))?;
+    let field_type = FieldType::NAMES
+        .iter()
+        .find(|(type_name, _)| *type_name == name)
+        .map(|(_, field_type)| *field_type)
+        .ok_or_else(|| {
+            format!(
+                "invalid type {:?} for column {:?}, expected one of {}",
+                name,
+                column,
+                FieldType::NAMES.map(|(name, _)| name).join(", ")
+            )
+        })?;
+    Ok((column.to_string(), field_type))
+}
+
+/// Splits a comma separated list of `column:type` pairs.
+fn deserialize_column_types<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<(String, FieldType)>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let types = String::deserialize(deserializer)?;
+    types
+        .split(',')
+        .map(parse_column_type)
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+/// Parses a field width for the `widths` option, which is at least one character.
+fn parse_width(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(width) if width > 0 => Ok(width),
+        _ => Err(format!(
+            "invalid width {:?}, expected a number of characters",
+            value
+        )),
+    }
+}
+
+/// Deserializes a comma-separated list of widths for the `widths` option.
+fn deserialize_widths<'de, D>(deserializer: D) -> Result<Option<Vec<usize>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let widths = String::deserialize(deserializer)?;
+    widths
+        .split(',')
+        .map(parse_width)
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+/// Formats that dates are parsed with when no `date-format` is given, in order of preference. Numeric
+/// dates with slashes are taken to be month-first.
+const DATE_FORMATS: [&str; 6] = [
+    "%Y-%m-%d",
+    "%m/%d/%Y",
+    "%Y/%m/%d",
+    "%B %d %Y",
+    "%B %d, %Y",
+    "%d %B %Y",
+];
+
+/// Parses a date with the given strftime-like format, or with any of the DATE_FORMATS if there's no
+/// format.
+fn parse_date(field: &str, format: Option<&str>) -> Option<NaiveDate> {
+    let field = field.trim();
+    match format {
+        Some(format) => NaiveDate::parse_from_str(field, format).ok(),
+        None => DATE_FORMATS
+            .iter()
+            .find_map(|format| NaiveDate::parse_from_str(field, format).ok()),
+    }
+}
+
+/// How records with a different number of fields than there are columns are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RaggedRows {
+    /// Fail the conversion with an error.
+    #[default]
+    Error,
+    /// Drop the extra fields of records with too many fields.
+    Truncate,
+    /// Fill in empty fields for records with too few fields.
+    Pad,
+}
+
+/// How header names that are used by more than one column are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateHeaders {
+    /// Fail the conversion with an error.
+    #[default]
+    Error,
+    /// Rename later occurrences by appending `_2`, `_3`, etc.
+    Suffix,
+}
+
+/// Casing that header names are converted to before they're used as the names of the JSON fields.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyCase {
+    /// Keep the header names as they are.
+    #[default]
+    Preserve,
+    /// `First Name` becomes `first name`.
+    Lower,
+    /// `First Name` becomes `first_name`.
+    Snake,
+    /// `First Name` becomes `firstName`.
+    Camel,
+}
+
+/// Splits a header name into its words, which are separated by any non-alphanumeric characters and
+/// by changes in case, e.g. `EmailAddress`, `email_address`, and `Email Address` all consist of the
+/// words `Email` and `Address`. A run of capitals is kept together as an acronym, so `HTTPStatus`
+/// consists of `HTTP` and `Status`.
+fn split_words(name: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut chars = name.char_indices().peekable();
+    let mut previous: Option<char> = None;
+    while let Some((index, c)) = chars.next() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&name[start..index]);
+            }
+            previous = None;
+            continue;
+        }
+        let next_is_lowercase = chars.peek().is_some_and(|&(_, next)| next.is_lowercase());
+        let word_boundary = match previous {
+            Some(previous) if c.is_uppercase() => !previous.is_uppercase() || next_is_lowercase,
+            _ => false,
+        };
+        if word_boundary {
+            if let Some(start) = start.replace(index) {
+                words.push(&name[start..index]);
+            }
+        } else if start.is_none() {
+            start = Some(index);
+        }
+        previous = Some(c);
+    }
+    if let Some(start) = start {
+        words.push(&name[start..]);
+    }
+    words
+}
+
+impl KeyCase {
+    /// Converts a header name to this casing. Each `.` separated part of the name is converted
+    /// separately, so that the name can still be nested with the `nest` option.
+    pub(crate) fn convert(self, name: &str) -> String {
+        let convert_part = |part: &str| match self {
+            KeyCase::Preserve => part.to_string(),
+            KeyCase::Lower => part.to_lowercase(),
+            KeyCase::Snake => split_words(part)
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            KeyCase::Camel => split_words(part)
+                .iter()
+                .enumerate()
+                .map(|(index, word)| {
+                    let word = word.to_lowercase();
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) if index > 0 => first.to_uppercase().chain(chars).collect(),
+                        _ => word,
+                    }
+                })
+                .collect(),
+        };
+        name.split('.')
+            .map(convert_part)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+/// Splits a comma separated list of column names.
+fn deserialize_column_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let columns = String::deserialize(deserializer)?;
+    Ok(Some(columns.split(',').map(str::to_string).collect()))
+}
+
+/// Options to customize CSV parsing behavior. These are taken from the URL query string by the server,
+/// and from command line flags by the `convert` subcommand.
+#[derive(clap::Args, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CsvParseOptions {
+    /// Preset for the field delimiter: `,` for csv, a tab for tsv, or `;` for ssv. With fixed, lines
+    /// are sliced into fields of the `widths` instead
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "csv")]
+    pub format: CsvFormat,
+    /// Widths of the fields in characters, with `format=fixed`
+    #[serde(default, deserialize_with = "deserialize_widths")]
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "WIDTHS",
+        parse(try_from_str = parse_width)
+    )]
+    pub widths: Option<Vec<usize>>,
+    /// Field delimiter, a name like `tab` or `pipe`, or `auto` to detect it from the first line.
+    /// Overrides the delimiter of the `format` preset
+    #[serde(default, deserialize_with = "deserialize_delimiter")]
+    #[clap(long, parse(try_from_str = parse_delimiter))]
+    pub delimiter: Option<Delimiter>,
+    /// Field quote, or a name like `single` for `'`
+    #[serde(default = "default_quote", deserialize_with = "deserialize_char")]
+    #[clap(long, default_value_t = default_quote(), parse(try_from_str = parse_char))]
+    pub quote: char,
+    /// Escape for quotes inside quoted fields, in addition to doubled quotes
+    #[serde(default, deserialize_with = "deserialize_optional_char")]
+    #[clap(long, parse(try_from_str = parse_char))]
+    pub escape: Option<char>,
+    /// Skip lines starting with this character
+    #[serde(default, deserialize_with = "deserialize_optional_char")]
+    #[clap(long, parse(try_from_str = parse_char))]
+    pub comment: Option<char>,
+    /// Record terminator. When unset, any of `\r`, `\n` or `\r\n` terminates a record.
+    #[serde(default, deserialize_with = "deserialize_optional_char")]
+    #[clap(long, parse(try_from_str = parse_char))]
+    pub terminator: Option<char>,
+    /// Which parts of the CSV to trim whitespace from
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "none")]
+    pub trim: TrimMode,
+    /// Whether the first record is a header row.
+    #[serde(default = "default_has_headers")]
+    #[clap(
+        long = "no-headers",
+        parse(from_flag = std::ops::Not::not),
+        help = "Treat the first record as data rather than as a header row"
+    )]
+    pub has_headers: bool,
+    /// Names of the columns of a CSV without a header row, in order. Columns beyond these are named by
+    /// their zero-based column index. Requires `has-headers=false`.
+    #[serde(default, deserialize_with = "deserialize_column_list")]
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "NAMES",
+        requires = "has-headers"
+    )]
+    pub headers: Option<Vec<String>>,
+    /// Infer the JSON type of each field
+    #[serde(default)]
+    #[clap(long)]
+    pub infer_types: bool,
+    /// How numbers that don't fit in a 64-bit integer or float are output with `infer-types`
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "float")]
+    pub big_number: BigNumbers,
+    /// Output empty fields as null
+    #[serde(default)]
+    #[clap(long)]
+    pub empty_as_null: bool,
+    /// Output fields that are one of these sentinel values, e.g. `NA` or `NULL`, as null
+    #[serde(default, deserialize_with = "deserialize_column_list")]
+    #[clap(long, use_value_delimiter = true, value_name = "TOKENS")]
+    pub null_tokens: Option<Vec<String>>,
+    /// Match the `null-tokens` case-insensitively
+    #[serde(default)]
+    #[clap(long)]
+    pub null_tokens_ignore_case: bool,
+    /// Rewrite `\r\n` and lone `\r` newlines within fields to `\n`
+    #[serde(default)]
+    #[clap(long)]
+    pub normalize_newlines: bool,
+    /// Split header names on `.` and nest the fields in JSON objects accordingly.
+    #[serde(default)]
+    #[clap(long)]
+    pub nest: bool,
+    /// Only output these columns. Can't be combined with `exclude`.
+    #[serde(default, deserialize_with = "deserialize_column_list")]
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "COLUMNS",
+        conflicts_with = "exclude"
+    )]
+    pub include: Option<Vec<String>>,
+    /// Output every column except these. Can't be combined with `include`.
+    #[serde(default, deserialize_with = "deserialize_column_list")]
+    #[clap(long, use_value_delimiter = true, value_name = "COLUMNS")]
+    pub exclude: Option<Vec<String>>,
+    /// How to handle included columns that aren't in the CSV
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "ignore")]
+    pub missing_columns: MissingColumns,
+    /// Encoding of the input, which is transcoded to UTF-8 before parsing. Defaults to UTF-8.
+    #[serde(default, deserialize_with = "deserialize_encoding")]
+    #[clap(long, parse(try_from_str = parse_encoding))]
+    pub encoding: Option<&'static Encoding>,
+    /// How to handle byte sequences that are invalid in the input encoding
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "strict")]
+    pub encoding_errors: EncodingErrors,
+    /// Stop after converting this many records
+    #[serde(default)]
+    #[clap(long)]
+    pub limit: Option<usize>,
+    /// Discard this many lines at the start of the input, before the header row
+    #[serde(default)]
+    #[clap(long, default_value_t = 0)]
+    pub skip_rows: usize,
+    /// Only convert the records that start within this range of bytes of the input, given as
+    /// `start-end` with an exclusive end, or as `start-` for the rest of the input. The header row is
+    /// still read from the start of the input
+    #[serde(default, deserialize_with = "deserialize_byte_range")]
+    #[clap(long, value_name = "START-END", parse(try_from_str = parse_byte_range))]
+    pub byte_range: Option<ByteRange>,
+    /// Discard records after the header row whose fields are all empty or only whitespace, i.e. lines
+    /// with nothing but delimiters and whitespace on them. Lines without any bytes are always skipped
+    #[serde(default)]
+    #[clap(long)]
+    pub skip_blank_lines: bool,
+    /// How to handle records with a different number of fields than there are columns
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "error")]
+    pub ragged: RaggedRows,
+    /// Fail as soon as a record has more than this many times as many fields as there are columns,
+    /// which usually means that the delimiter is wrong. Applies regardless of `ragged`
+    #[serde(default)]
+    #[clap(long, value_name = "FACTOR")]
+    pub max_field_factor: Option<f64>,
+    /// How to handle header names that are used by more than one column
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "error")]
+    pub dedupe_headers: DuplicateHeaders,
+    /// Casing to convert the header names to
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "preserve")]
+    pub key_case: KeyCase,
+    /// Output a JSON object with each record keyed by its field in this column, rather than an array.
+    /// Only applies to JSON output.
+    #[serde(default)]
+    #[clap(long, value_name = "COLUMN")]
+    pub key_column: Option<String>,
+    /// Keep the key column in the records rather than removing it, with `key-column`
+    #[serde(default)]
+    #[clap(long)]
+    pub keep_key_column: bool,
+    /// Output each record as a JSON array of its field values rather than as an object. Can't be
+    /// combined with `key-column`.
+    #[serde(default)]
+    #[clap(long, conflicts_with = "key-column")]
+    pub raw_rows: bool,
+    /// Add the number of each record, counting from 1, to the record under this key. Fails if the CSV
+    /// has a column of the same name. Can't be combined with `raw-rows`.
+    #[serde(default)]
+    #[clap(long, value_name = "NAME", conflicts_with = "raw-rows")]
+    pub add_row_number: Option<String>,
+    /// Parse the fields of these columns as dates and output them as ISO 8601 dates (YYYY-MM-DD)
+    #[serde(default, deserialize_with = "deserialize_column_list")]
+    #[clap(long, use_value_delimiter = true, value_name = "COLUMNS")]
+    pub date_columns: Option<Vec<String>>,
+    /// Format of the dates in the `date-columns`, e.g. `%d/%m/%Y`. By default a few common formats
+    /// are tried
+    #[serde(default)]
+    #[clap(long, value_name = "FORMAT")]
+    pub date_format: Option<String>,
+    /// How to handle fields in the `date-columns` that aren't valid dates
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "keep")]
+    pub invalid_dates: InvalidDates,
+    /// Coerce the fields of these columns to JSON types, given as `column:type` pairs where the type
+    /// is one of int, float, bool or string. Empty fields of int, float and bool columns are null
+    #[serde(default, deserialize_with = "deserialize_column_types")]
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "COLUMN:TYPE",
+        parse(try_from_str = parse_column_type)
+    )]
+    pub types: Option<Vec<(String, FieldType)>>,
+    /// Maximum number of fields in a record, including the header row. Not configurable by clients.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub max_columns: Option<usize>,
+    /// Maximum size in bytes of a single field. Not configurable by clients.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub max_cell_bytes: Option<usize>,
+}
+
+impl Default for CsvParseOptions {
+    /// Returns the same options as when the server is given no query params.
+    fn default() -> Self {
+        let no_params =
+            MapDeserializer::<_, serde::de::value::Error>::new(std::iter::empty::<(&str, &str)>());
+        Self::deserialize(no_params).expect("all options have defaults")
+    }
+}
+
+impl CsvParseOptions {
+    /// Converts a field to an ISO 8601 date if its column is one of the `date-columns`. Empty fields
+    /// are left as they are.
+    pub(crate) fn normalize_date(&self, column: &str, field: String) -> csv_async::Result<String> {
+        let is_date_column = self
+            .date_columns
+            .as_ref()
+            .is_some_and(|columns| columns.iter().any(|date_column| date_column == column));
+        if !is_date_column || field.is_empty() {
+            return Ok(field);
+        }
+        match parse_date(&field, self.date_format.as_deref()) {
+            Some(date) => Ok(date.format("%Y-%m-%d").to_string()),
+            None if self.invalid_dates == InvalidDates::Keep => Ok(field),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} in column {:?} is not a valid date", field, column),
+            )
+            .into()),
+        }
+    }
+
+    /// Returns the type that the column's fields are coerced to, if it's one of the `types`.
+    pub fn column_type(&self, column: &str) -> Option<FieldType> {
+        self.types.as_ref().and_then(|types| {
+            types
+                .iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, field_type)| *field_type)
+        })
+    }
+
+    /// Returns the field delimiter, where an explicit `delimiter` takes precedence over the `format`.
+    pub(crate) fn delimiter(&self) -> char {
+        match self.delimiter {
+            Some(Delimiter::Char(delimiter)) => delimiter,
+            // NOTE: an automatic delimiter is replaced by the detected one before the CSV is read.
+            Some(Delimiter::Auto) | None => self.format.delimiter(),
+        }
+    }
+
+    /// Returns whether the field is one of the `null-tokens`.
+    pub(crate) fn is_null_token(&self, field: &str) -> bool {
+        self.null_tokens.as_ref().is_some_and(|tokens| {
+            tokens.iter().any(|token| {
+                token == field
+                    || (self.null_tokens_ignore_case && token.eq_ignore_ascii_case(field))
+            })
+        })
+    }
+
+    /// Returns true if the column with the given name should be output according to the `include`
+    /// and `exclude` options.
+    pub(crate) fn includes_column(&self, name: &str) -> bool {
+        match (&self.include, &self.exclude) {
+            (Some(include), _) => include.iter().any(|column| column == name),
+            (None, Some(exclude)) => !exclude.iter().any(|column| column == name),
+            (None, None) => true,
+        }
+    }
+
+    /// Checks that every column named in the `include` option is one of the CSV's columns, if
+    /// missing columns should be reported as errors.
+    pub(crate) fn check_included_columns(&self, columns: &[String]) -> csv_async::Result<()> {
+        let include = match (&self.include, self.missing_columns) {
+            (Some(include), MissingColumns::Error) => include,
+            _ => return Ok(()),
+        };
+        match include.iter().find(|name| !columns.contains(name)) {
+            Some(name) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("included column {:?} is not in the CSV", name),
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether the record is left out with `skip-blank-lines`, i.e. all of its fields are empty
+    /// or only whitespace.
+    pub(crate) fn is_blank_record(&self, record: &csv_async::StringRecord) -> bool {
+        self.skip_blank_lines && record.iter().all(|field| field.trim().is_empty())
+    }
+
+    /// Checks that a record, or the header row, doesn't exceed the `max_columns` and `max_cell_bytes`
+    /// limits.
+    pub(crate) fn check_record_limits(
+        &self,
+        record: &csv_async::StringRecord,
+    ) -> csv_async::Result<()> {
+        let message = match (self.max_columns, self.max_cell_bytes) {
+            (Some(max_columns), _) if record.len() > max_columns => format!(
+                "record has {} fields, more than the limit of {}",
+                record.len(),
+                max_columns
+            ),
+            (_, Some(max_cell_bytes)) => {
+                match record.iter().position(|field| field.len() > max_cell_bytes) {
+                    Some(column) => format!(
+                        "field {} is {} bytes, more than the limit of {}",
+                        column + 1,
+                        record[column].len(),
+                        max_cell_bytes
+                    ),
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into())
+    }
+}
+
+/// Supported serialization formats for the converted records.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// A single JSON array containing every record.
+    #[default]
+    Json,
+    /// Newline-delimited JSON, one record object per line.
+    Ndjson,
+    /// Server-Sent Events, one `record` event per record followed by a `done` event.
+    Sse,
+    /// MessagePack, a concatenated sequence of one map per record without any framing.
+    Msgpack,
+}
+
+impl OutputFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            // NOTE: according to https://github.com/eligrey/FileSaver.js/wiki/Saving-a-remote-file it is better to
+            //       use octent-stream over the actual mime type when trying to stream data so that browsers don't
+            //       try to render the result, but instead force a file-save dialog.
+            OutputFormat::Json => "application/octet-stream; charset=utf-8",
+            OutputFormat::Ndjson => "application/x-ndjson; charset=utf-8",
+            OutputFormat::Sse => "text/event-stream; charset=utf-8",
+            OutputFormat::Msgpack => "application/msgpack",
+        }
+    }
+
+    /// Every output format, in the order they're preferred in when a client accepts several of them
+    /// equally.
+    pub const ALL: [OutputFormat; 4] = [
+        OutputFormat::Json,
+        OutputFormat::Ndjson,
+        OutputFormat::Sse,
+        OutputFormat::Msgpack,
+    ];
+
+    /// The media types that select the format in an Accept header. The first one is its canonical type.
+    pub fn media_types(self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Json => &["application/json", "application/octet-stream"],
+            OutputFormat::Ndjson => &["application/x-ndjson", "application/ndjson"],
+            OutputFormat::Sse => &["text/event-stream"],
+            OutputFormat::Msgpack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+        }
+    }
+
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Sse => "sse",
+            OutputFormat::Msgpack => "msgpack",
+        }
+    }
+}
+
+/// Options to customize the JSON output. These are taken from the URL query string by the server, and
+/// from command line flags by the `convert` subcommand.
+#[derive(clap::Args, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutputOptions {
+    /// Format of the converted records
+    #[serde(default)]
+    #[clap(long, arg_enum, default_value = "json")]
+    pub output: OutputFormat,
+    /// Wrap the JSON array of records in an object that also reports whether an error occurred
+    /// during the conversion. Only applies to JSON output.
+    #[serde(default)]
+    #[clap(long)]
+    pub envelope: bool,
+    /// Pretty-print the JSON with newlines and indentation. Only applies to JSON output.
+    #[serde(default)]
+    #[clap(long)]
+    pub pretty: bool,
+    /// Output the record on its own rather than in an array when there's exactly one. Only applies
+    /// to JSON output, and can't be combined with `envelope`.
+    #[serde(default)]
+    #[clap(long)]
+    pub unwrap_single: bool,
+    /// Wrap the JSON array of records in an object with metadata about the conversion: the file name
+    /// and conversion time before the records, and the number of records after them. Only used by the
+    /// server, and only applies to JSON output.
+    #[serde(default)]
+    #[clap(skip)]
+    pub meta: bool,
+    /// Start the output with a UTF-8 byte order mark, for consumers that require one. Doesn't apply to
+    /// MessagePack output.
+    #[serde(default)]
+    #[clap(long)]
+    pub bom: bool,
+    /// How deeply the output is nested in other JSON, so that pretty-printed output can be indented to
+    /// match. Not configurable by clients.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub depth: usize,
+    /// Convert the whole file before responding, so that the response has a Content-Length rather
+    /// than being streamed. Only used by the server.
+    #[serde(default)]
+    #[clap(skip)]
+    pub buffered: bool,
+    /// Convert output up to this many bytes in full before responding like with `buffered`, and only
+    /// stream larger output. Not configurable by clients, the server takes it from its own
+    /// `--buffer-threshold` option.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub buffer_threshold: Option<usize>,
+    /// Maximum size in bytes of the chunks of output, so that the output of a very large record is
+    /// sent in several chunks. Not configurable by clients, the server takes it from its own
+    /// `--max-chunk-bytes` option.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub max_chunk_bytes: Option<usize>,
+    /// Whether the values are output as the members of a JSON object rather than as the elements of an
+    /// array, according to the `key-column` option. Not configurable by clients.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub keyed: bool,
+    /// Number of bytes of converted output to accumulate before writing it, rather than writing
+    /// after every record. Not configurable by clients, the server takes it from its own
+    /// `--flush-bytes` option.
+    #[serde(skip)]
+    #[clap(long, value_name = "BYTES", default_value_t = 0)]
+    pub flush_bytes: usize,
+}
+
+impl Default for OutputOptions {
+    /// Returns the same options as when the server is given no query params.
+    fn default() -> Self {
+        let no_params =
+            MapDeserializer::<_, serde::de::value::Error>::new(std::iter::empty::<(&str, &str)>());
+        Self::deserialize(no_params).expect("all options have defaults")
+    }
+}
+
+impl OutputOptions {
+    /// Returns the whitespace that starts a new line of pretty-printed JSON at the given depth, relative
+    /// to the depth that the output is nested at. This is empty for compact output, so that it can be
+    /// used unconditionally.
+    pub fn newline(&self, depth: usize) -> Vec<u8> {
+        if !self.pretty || self.output != OutputFormat::Json {
+            return Vec::new();
+        }
+        let mut newline = vec![b'\n'];
+        newline.resize(1 + 2 * (self.depth + depth), b' ');
+        newline
+    }
+
+    /// Returns the whitespace that follows the colon between keys and values in JSON objects.
+    pub fn key_separator(&self) -> &'static [u8] {
+        if self.newline(0).is_empty() {
+            b":"
+        } else {
+            b": "
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn converts_key_case() {
+        for (name, lower, snake, camel) in [
+            ("First Name", "first name", "first_name", "firstName"),
+            (
+                "EmailAddress",
+                "emailaddress",
+                "email_address",
+                "emailAddress",
+            ),
+            (
+                "email_address",
+                "email_address",
+                "email_address",
+                "emailAddress",
+            ),
+            ("HTTPStatus", "httpstatus", "http_status", "httpStatus"),
+            ("ID", "id", "id", "id"),
+            (
+                "address2Line",
+                "address2line",
+                "address2_line",
+                "address2Line",
+            ),
+            ("  Total (USD) ", "  total (usd) ", "total_usd", "totalUsd"),
+            (
+                "Home Address.Zip Code",
+                "home address.zip code",
+                "home_address.zip_code",
+                "homeAddress.zipCode",
+            ),
+            (
+                "Ünïcode Wörd",
+                "ünïcode wörd",
+                "ünïcode_wörd",
+                "ünïcodeWörd",
+            ),
+        ] {
+            assert_eq!(KeyCase::Preserve.convert(name), name);
+            assert_eq!(KeyCase::Lower.convert(name), lower, "{:?}", name);
+            assert_eq!(KeyCase::Snake.convert(name), snake, "{:?}", name);
+            assert_eq!(KeyCase::Camel.convert(name), camel, "{:?}", name);
+        }
+    }
+}
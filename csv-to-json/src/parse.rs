@@ -0,0 +1,1590 @@
+use crate::options::{
+    BigNumbers, ByteRange, CsvFormat, CsvParseOptions, Delimiter, DuplicateHeaders, EncodingErrors,
+    FieldType, RaggedRows,
+};
+use async_stream::try_stream;
+use bytes::{Bytes, BytesMut};
+use encoding_rs::{Decoder, DecoderResult, Encoding, UTF_8};
+use futures::future::Either;
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use indexmap::map::Entry;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Makes the header names unique according to the `dedupe-headers` option, since fields with the
+/// same name would otherwise overwrite each other in the records.
+pub(crate) fn dedupe_headers(
+    names: Vec<String>,
+    policy: DuplicateHeaders,
+) -> csv_async::Result<Vec<String>> {
+    // NOTE: the original names are all reserved up front, so that renamed headers can't collide with
+    //       headers that come later, e.g. in `id,id,id_2`.
+    let originals: HashSet<&str> = names.iter().map(String::as_str).collect();
+    let mut columns: HashMap<String, usize> = HashMap::new();
+    let mut deduped = Vec::with_capacity(names.len());
+    for (column, name) in names.iter().enumerate() {
+        let name = match (columns.get(name), policy) {
+            (None, _) => name.clone(),
+            (Some(first_column), DuplicateHeaders::Error) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "header {:?} is used by both column {} and column {}",
+                        name,
+                        first_column + 1,
+                        column + 1
+                    ),
+                )
+                .into())
+            }
+            (Some(_), DuplicateHeaders::Suffix) => (2..)
+                .map(|suffix| format!("{}_{}", name, suffix))
+                .find(|renamed| {
+                    !originals.contains(renamed.as_str()) && !columns.contains_key(renamed)
+                })
+                .expect("there are always more suffixes to try"),
+        };
+        columns.insert(name.clone(), column);
+        deduped.push(name);
+    }
+    Ok(deduped)
+}
+
+/// Where in a CSV an error was found. Lines and columns are numbered from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorPosition {
+    pub line: u64,
+    pub column: Option<usize>,
+}
+
+/// An error in a CSV, along with where it was found if that's known, so that the position can be
+/// reported along with the error.
+#[derive(Debug)]
+pub struct CsvError {
+    position: Option<ErrorPosition>,
+    error: csv_async::Error,
+}
+
+impl CsvError {
+    /// Attributes an error to the record it was found in, unless it already has a more precise
+    /// position.
+    fn in_record(error: csv_async::Error, record: &csv_async::StringRecord) -> Self {
+        let mut error = CsvError::from(error);
+        if error.position.is_none() {
+            error.position = record.position().map(|position| ErrorPosition {
+                line: position.line(),
+                column: None,
+            });
+        }
+        error
+    }
+}
+
+impl From<csv_async::Error> for CsvError {
+    fn from(error: csv_async::Error) -> Self {
+        let position = match error.kind() {
+            csv_async::ErrorKind::Utf8 {
+                pos: Some(position),
+                err,
+            } => Some(ErrorPosition {
+                line: position.line(),
+                column: Some(err.field() + 1),
+            }),
+            _ => None,
+        };
+        CsvError { position, error }
+    }
+}
+
+impl std::fmt::Display for CsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(position) = self.position {
+            write!(f, "line {}", position.line)?;
+            if let Some(column) = position.column {
+                write!(f, ", column {}", column)?;
+            }
+            f.write_str(": ")?;
+        }
+        match self.error.kind() {
+            // NOTE: csv_async's own message for these repeats the position, with a zero-based field index.
+            csv_async::ErrorKind::Utf8 { err, .. } => write!(
+                f,
+                "invalid utf-8 near byte {} of the field",
+                err.valid_up_to()
+            ),
+            csv_async::ErrorKind::Io(error) => write!(f, "{}", error),
+            _ => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for CsvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.error.kind() {
+            csv_async::ErrorKind::Io(error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the fields of a record, fitted to the given number of columns according to the `ragged`
+/// option. Fails if the record has the wrong number of fields and the option doesn't handle it.
+fn fit_record_to_columns(
+    record: &csv_async::StringRecord,
+    columns: usize,
+    ragged: RaggedRows,
+) -> csv_async::Result<Vec<String>> {
+    let mut fields: Vec<String> = record.iter().map(str::to_string).collect();
+    match (fields.len().cmp(&columns), ragged) {
+        (std::cmp::Ordering::Equal, _) => {}
+        (std::cmp::Ordering::Greater, RaggedRows::Truncate) => fields.truncate(columns),
+        (std::cmp::Ordering::Less, RaggedRows::Pad) => fields.resize(columns, String::new()),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "record has {} fields, but there are {} columns",
+                    fields.len(),
+                    columns
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(fields)
+}
+
+/// Checks that a record doesn't have more than `max-field-factor` times as many fields as there are
+/// columns. A header row that's a single giant column, with data rows that are split into many fields,
+/// is typical of a CSV that's read with the wrong delimiter, so the error suggests the candidate
+/// delimiter that's most common in the header names instead.
+fn check_field_factor(
+    options: &CsvParseOptions,
+    record: &csv_async::StringRecord,
+    names: &[String],
+) -> csv_async::Result<()> {
+    let factor = match options.max_field_factor {
+        Some(factor) if record.len() as f64 > names.len() as f64 * factor => factor,
+        _ => return Ok(()),
+    };
+    let delimiter = options.delimiter() as u8;
+    let suggestion = DELIMITER_CANDIDATES
+        .iter()
+        .filter(|&&candidate| candidate != delimiter)
+        .map(|&candidate| {
+            let count: usize = names
+                .iter()
+                .map(|name| name.bytes().filter(|&byte| byte == candidate).count())
+                .sum();
+            (candidate, count)
+        })
+        .filter(|&(_, count)| count > 0)
+        .max_by_key(|&(_, count)| count);
+    let mut message = format!(
+        "record has {} fields, more than {} times the {} columns; the delimiter {:?} is probably wrong",
+        record.len(),
+        factor,
+        names.len(),
+        delimiter as char
+    );
+    if let Some((candidate, _)) = suggestion {
+        message.push_str(&format!(", try {:?} instead", candidate as char));
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into())
+}
+
+/// Representation of a single record or line in a CSV. Fields are named according to the headers
+/// in the original CSV.
+#[derive(Debug, Deserialize, Serialize)]
+struct CsvRecord(
+    // NOTE: Using an IndexMap to keep the ordering of fields the same order as in the CSV, which is what users
+    //       expect to see in the output. This also makes testing a lot easier since the output is predictable.
+    IndexMap<String, String>,
+);
+
+/// A converted CSV record, ready to be serialized as a JSON object, or as a JSON array with the
+/// `raw-rows` option. Unlike CsvRecord the field values may be any JSON value rather than always
+/// being strings.
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct JsonRecord {
+    /// The record's key in the JSON object that's output with the `key-column` option.
+    #[serde(skip)]
+    key: Option<String>,
+    fields: JsonFields,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum JsonFields {
+    Object(IndexMap<String, FieldValue>),
+    Array(Vec<FieldValue>),
+}
+
+/// The JSON value of a field, or of an object of nested fields. Integers beyond 64 bits can't be held
+/// by a Value without losing precision, so they're kept apart and serialized as they are.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum FieldValue {
+    Json(Value),
+    BigInteger(i128),
+    Object(IndexMap<String, FieldValue>),
+}
+
+impl From<Value> for FieldValue {
+    fn from(value: Value) -> Self {
+        FieldValue::Json(value)
+    }
+}
+
+impl JsonRecord {
+    fn from_csv_record(
+        record: CsvRecord,
+        options: &CsvParseOptions,
+        row_number: u64,
+    ) -> csv_async::Result<Self> {
+        let CsvRecord(fields) = record;
+        // The key is the field as it appears in the CSV, so that it doesn't depend on type inference.
+        let key = match &options.key_column {
+            Some(key_column) => Some(fields.get(key_column).cloned().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("key column {:?} is not in the CSV", key_column),
+                )
+            })?),
+            None => None,
+        };
+        let fields = fields
+            .into_iter()
+            .filter(|(name, _)| options.includes_column(name))
+            .filter(|(name, _)| {
+                options.keep_key_column || options.key_column.as_ref() != Some(name)
+            })
+            .map(|(name, field)| {
+                let field = options.normalize_date(&name, field)?;
+                let value = match options.column_type(&name) {
+                    Some(field_type) => coerce_field(field, field_type, &name, options)?,
+                    None => convert_field(field, options),
+                };
+                Ok((name, value))
+            })
+            .collect::<csv_async::Result<Vec<_>>>()?
+            .into_iter();
+        let mut root = IndexMap::new();
+        if let Some(row_number_key) = &options.add_row_number {
+            // NOTE: with nest, a nested field's top-level object would collide with the row number too.
+            let collides = |(name, _): &(String, FieldValue)| {
+                name == row_number_key
+                    || (options.nest && name.split('.').next() == Some(row_number_key))
+            };
+            if fields.as_slice().iter().any(collides) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "can't add the row number as {:?}, since the CSV has a column of that name",
+                        row_number_key
+                    ),
+                )
+                .into());
+            }
+            root.insert(row_number_key.clone(), Value::from(row_number).into());
+        }
+        if options.raw_rows {
+            return Ok(JsonRecord {
+                key,
+                fields: JsonFields::Array(fields.map(|(_, value)| value).collect()),
+            });
+        }
+        if !options.nest {
+            root.extend(fields);
+            return Ok(JsonRecord {
+                key,
+                fields: JsonFields::Object(root),
+            });
+        }
+        for (name, value) in fields {
+            insert_nested(&mut root, &name, value)
+                .map_err(|message| std::io::Error::new(std::io::ErrorKind::InvalidData, message))?;
+        }
+        Ok(JsonRecord {
+            key,
+            fields: JsonFields::Object(root),
+        })
+    }
+}
+
+/// A value that's serialized as an element of the JSON output. When the output is keyed, elements
+/// are serialized as the members of a JSON object rather than as the elements of an array.
+pub trait JsonElement: Serialize {
+    fn key(&self) -> Option<&str>;
+}
+
+impl JsonElement for JsonRecord {
+    fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+}
+
+/// Inserts a field into a JSON object, splitting the header name on `.` to nest the field in
+/// objects, e.g. `address.city` is inserted as `{"address":{"city":...}}`. Intermediate objects are
+/// created as needed. Fails if a header is used both as a field and as the parent of other fields,
+/// as in `address,address.city`.
+fn insert_nested(
+    root: &mut IndexMap<String, FieldValue>,
+    name: &str,
+    value: FieldValue,
+) -> Result<(), String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let (leaf, parents) = parts.split_last().expect("split always yields a part");
+    let mut object = root;
+    for (depth, parent) in parents.iter().enumerate() {
+        object = match object
+            .entry(parent.to_string())
+            .or_insert_with(|| FieldValue::Object(IndexMap::new()))
+        {
+            FieldValue::Object(object) => object,
+            _ => {
+                return Err(format!(
+                    "header {:?} is used both as a field and as the parent of {:?}",
+                    parts[..=depth].join("."),
+                    name
+                ))
+            }
+        };
+    }
+    match object.entry(leaf.to_string()) {
+        Entry::Vacant(entry) => {
+            entry.insert(value);
+            Ok(())
+        }
+        Entry::Occupied(_) => Err(format!(
+            "header {:?} is used both as a field and as the parent of other fields",
+            name
+        )),
+    }
+}
+
+/// Returns true if the field is a valid JSON number literal, i.e. it matches the grammar
+/// `-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?`. Notably this means that fields with leading
+/// zeros (`007`), a leading plus sign (`+1`), or a missing integer or fraction part (`.5`, `1.`)
+/// are not considered numbers.
+fn is_json_number(field: &str) -> bool {
+    fn digits(bytes: &[u8]) -> usize {
+        bytes.iter().take_while(|b| b.is_ascii_digit()).count()
+    }
+
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    if bytes.first() == Some(&b'-') {
+        i += 1;
+    }
+    match digits(&bytes[i..]) {
+        0 => return false,
+        n if n > 1 && bytes[i] == b'0' => return false,
+        n => i += n,
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        match digits(&bytes[i..]) {
+            0 => return false,
+            n => i += n,
+        }
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        i += 1;
+        if matches!(bytes.get(i), Some(b'+' | b'-')) {
+            i += 1;
+        }
+        match digits(&bytes[i..]) {
+            0 => return false,
+            n => i += n,
+        }
+    }
+    i == bytes.len()
+}
+
+/// Returns the number of significant digits of a JSON number literal, not counting leading zeros or
+/// trailing zeros of its fraction.
+fn significant_digits(number: &str) -> usize {
+    let mantissa = number.split(['e', 'E']).next().unwrap_or_default();
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!(
+        "{}{}",
+        integer.trim_start_matches('-'),
+        fraction.trim_end_matches('0')
+    );
+    digits.trim_start_matches('0').len()
+}
+
+/// Attempts to infer the JSON type of a CSV field:
+///
+/// - empty fields become `null`;
+/// - `true` and `false` (case-insensitive) become booleans;
+/// - valid JSON number literals (see `is_json_number`) become numbers. Integers are kept as
+///   integers when they fit in 64 bits, everything else is represented as a float unless the
+///   `big_numbers` policy says otherwise. Numbers that can't be represented as a finite float (e.g.
+///   `1e999`) are left as strings;
+/// - everything else is left as a string.
+fn infer_value(field: String, big_numbers: BigNumbers) -> FieldValue {
+    if field.is_empty() {
+        return Value::Null.into();
+    }
+    if field.eq_ignore_ascii_case("true") {
+        return Value::Bool(true).into();
+    }
+    if field.eq_ignore_ascii_case("false") {
+        return Value::Bool(false).into();
+    }
+    if is_json_number(&field) {
+        if let Ok(int) = field.parse::<i64>() {
+            return Value::from(int).into();
+        }
+        if let Ok(uint) = field.parse::<u64>() {
+            return Value::from(uint).into();
+        }
+        let is_integer = !field.contains(['.', 'e', 'E']);
+        match big_numbers {
+            BigNumbers::Float => {}
+            BigNumbers::String => {
+                if is_integer || significant_digits(&field) > f64::DIGITS as usize {
+                    return Value::String(field).into();
+                }
+            }
+            BigNumbers::I128 => {
+                if let Some(int) = is_integer.then(|| field.parse::<i128>().ok()).flatten() {
+                    return FieldValue::BigInteger(int);
+                }
+            }
+        }
+        if let Some(float) = field.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Value::Number(float).into();
+        }
+    }
+    Value::String(field).into()
+}
+
+/// Rewrites the `\r\n` and lone `\r` newlines in a field to `\n`.
+fn normalize_newlines(field: String) -> String {
+    if !field.contains('\r') {
+        return field;
+    }
+    field.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Converts a single CSV field into a JSON value according to the parse options.
+fn convert_field(field: String, options: &CsvParseOptions) -> FieldValue {
+    let field = if options.normalize_newlines {
+        normalize_newlines(field)
+    } else {
+        field
+    };
+    if options.is_null_token(&field) {
+        Value::Null.into()
+    } else if options.infer_types {
+        infer_value(field, options.big_number)
+    } else if options.empty_as_null && field.is_empty() {
+        Value::Null.into()
+    } else {
+        Value::String(field).into()
+    }
+}
+
+/// Converts a field of one of the `types` columns into a JSON value of that type, failing if it isn't
+/// a valid value of the type. Empty fields of int, float and bool columns are null, as are
+/// `null-tokens` in any typed column.
+fn coerce_field(
+    field: String,
+    field_type: FieldType,
+    column: &str,
+    options: &CsvParseOptions,
+) -> csv_async::Result<FieldValue> {
+    let field = if options.normalize_newlines {
+        normalize_newlines(field)
+    } else {
+        field
+    };
+    if options.is_null_token(&field)
+        || (field.is_empty() && (field_type != FieldType::String || options.empty_as_null))
+    {
+        return Ok(Value::Null.into());
+    }
+    let value = match field_type {
+        FieldType::String => return Ok(Value::String(field).into()),
+        FieldType::Int => field
+            .parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| field.parse::<u64>().map(Value::from))
+            .ok(),
+        FieldType::Float => field
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        FieldType::Bool if field.eq_ignore_ascii_case("true") => Some(Value::Bool(true)),
+        FieldType::Bool if field.eq_ignore_ascii_case("false") => Some(Value::Bool(false)),
+        FieldType::Bool => None,
+    };
+    value.map(FieldValue::from).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{:?} in column {:?} is not a valid {}",
+                field,
+                column,
+                field_type.name()
+            ),
+        )
+        .into()
+    })
+}
+
+/// Decodes a single chunk of input into UTF-8, given the offset of the chunk within the whole input
+/// so that invalid byte sequences can be reported by their position.
+fn decode_chunk(
+    decoder: &mut Decoder,
+    errors: EncodingErrors,
+    chunk: &[u8],
+    offset: usize,
+    last: bool,
+) -> std::io::Result<Bytes> {
+    let too_large = || std::io::Error::other("input chunk too large to transcode");
+    let mut output = String::new();
+    match errors {
+        EncodingErrors::Replace => {
+            output.reserve(
+                decoder
+                    .max_utf8_buffer_length(chunk.len())
+                    .ok_or_else(too_large)?,
+            );
+            // NOTE: enough capacity was reserved for the whole chunk, so decoding always consumes
+            //       all of the input and we don't need to check the result.
+            let _ = decoder.decode_to_string(chunk, &mut output, last);
+        }
+        EncodingErrors::Strict => {
+            output.reserve(
+                decoder
+                    .max_utf8_buffer_length_without_replacement(chunk.len())
+                    .ok_or_else(too_large)?,
+            );
+            let (result, read) =
+                decoder.decode_to_string_without_replacement(chunk, &mut output, last);
+            if let DecoderResult::Malformed(length, extra) = result {
+                let position = offset + read - length as usize - extra as usize;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid {} byte sequence at byte {}",
+                        decoder.encoding().name(),
+                        position
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(Bytes::from(output))
+}
+
+pub(crate) const UTF_8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Stream producer that removes the UTF-8 byte order mark that e.g. Excel prepends to CSVs, if the
+/// input starts with one. Otherwise it would end up as part of the first header name. Since the BOM
+/// could be split across chunks, chunks are buffered until there's enough input to check for it.
+fn strip_utf8_bom<S>(input: S) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    try_stream! {
+        pin_mut!(input);
+        let mut start = BytesMut::new();
+        while start.len() < UTF_8_BOM.len() {
+            match input.try_next().await? {
+                Some(chunk) => start.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        let mut start = start.freeze();
+        if start.starts_with(UTF_8_BOM) {
+            start = start.slice(UTF_8_BOM.len()..);
+        }
+        if !start.is_empty() {
+            yield start;
+        }
+        for await chunk in input {
+            yield chunk?;
+        }
+    }
+}
+
+/// Stream producer that discards the first `lines` lines of a stream of input bytes, where lines are
+/// terminated by the `terminator` byte.
+///
+/// NOTE: the lines are skipped before the input is parsed as CSV, so a terminator inside a quoted field
+///       is still counted as the end of a line.
+fn skip_lines<S>(
+    input: S,
+    lines: usize,
+    terminator: u8,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    try_stream! {
+        let mut remaining = lines;
+        for await chunk in input {
+            let mut chunk = chunk?;
+            while remaining > 0 {
+                match chunk.iter().position(|&byte| byte == terminator) {
+                    Some(end) => {
+                        chunk = chunk.slice(end + 1..);
+                        remaining -= 1;
+                    }
+                    None => {
+                        chunk.clear();
+                        break;
+                    }
+                }
+            }
+            if !chunk.is_empty() {
+                yield chunk;
+            }
+        }
+    }
+}
+
+/// Selects the lines of the input that start within a byte range for select_byte_range, keeping track
+/// of where in the input the chunks it's given are.
+#[derive(Debug)]
+struct ByteRangeSelection {
+    range: ByteRange,
+    context_lines: usize,
+    terminator: u8,
+    position: u64,
+    /// Whether a line starts at the position, i.e. the previous byte was a terminator.
+    line_start: bool,
+    in_range: bool,
+    finished: bool,
+}
+
+impl ByteRangeSelection {
+    /// Returns the length of the start of the chunk up to and including the first terminator.
+    fn through_terminator(&self, chunk: &[u8]) -> usize {
+        chunk
+            .iter()
+            .position(|&byte| byte == self.terminator)
+            .map_or(chunk.len(), |end| end + 1)
+    }
+
+    /// Returns the part of the next chunk of input that's passed on, which may be empty. The lines
+    /// before the first record and the start of the range can be in the same chunk, in which case
+    /// they're joined.
+    fn select(&mut self, mut chunk: Bytes) -> Bytes {
+        let mut parts = Vec::new();
+        while !chunk.is_empty() && !self.finished {
+            if self.context_lines > 0 {
+                let line = chunk.split_to(self.through_terminator(&chunk));
+                self.position += line.len() as u64;
+                if line.last() == Some(&self.terminator) {
+                    self.context_lines -= 1;
+                }
+                parts.push(line);
+                continue;
+            }
+            // Bytes are skipped up to the first line that starts at or after the start of the range, and
+            // then passed on up to the first line that starts at or after its end.
+            let target = if self.in_range {
+                self.range.end.unwrap_or(u64::MAX)
+            } else {
+                self.range.start
+            };
+            let len = if self.position < target {
+                chunk
+                    .len()
+                    .min((target - self.position).try_into().unwrap_or(usize::MAX))
+            } else if !self.line_start {
+                self.through_terminator(&chunk)
+            } else {
+                self.finished = self.in_range;
+                self.in_range = true;
+                continue;
+            };
+            let part = chunk.split_to(len);
+            self.position += len as u64;
+            self.line_start = part.last() == Some(&self.terminator);
+            if self.in_range {
+                parts.push(part);
+            }
+        }
+        match parts.len() {
+            0 => Bytes::new(),
+            1 => parts.pop().expect("there's a part"),
+            _ => parts.concat().into(),
+        }
+    }
+}
+
+/// Stream producer that only passes on the lines of a stream of input bytes that start within the
+/// `byte-range`, along with the lines before the first record, i.e. the header row and any `skip-rows`,
+/// which are always passed on. A line that starts within the range is passed on in full even if it ends
+/// after it, so that splitting the input into adjacent ranges passes on every line exactly once. Once
+/// the range has been passed on the rest of the input isn't read. Without a range the input is passed
+/// on as it is.
+///
+/// NOTE: like with skip_lines, lines are split on the terminator byte before the input is parsed as CSV,
+///       so a range that starts or ends inside a quoted field with a terminator in it splits the record.
+fn select_byte_range<S>(
+    options: &CsvParseOptions,
+    input: S,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    // NOTE: the input isn't wrapped in an Either when there's no range, since that would double the size
+    //       of the types of every stream built on top of it, which makes compiling the server very slow.
+    let selection = options.byte_range.map(|range| ByteRangeSelection {
+        range,
+        context_lines: options.skip_rows + usize::from(options.has_headers),
+        terminator: options.terminator.map_or(b'\n', |t| t as u8),
+        position: 0,
+        line_start: true,
+        in_range: false,
+        finished: false,
+    });
+    input.scan(selection, |selection, chunk| {
+        let chunk = match (selection, chunk) {
+            (Some(selection), _) if selection.finished => None,
+            (Some(selection), Ok(chunk)) => Some(Ok(selection.select(chunk))),
+            (_, chunk) => Some(chunk),
+        };
+        futures::future::ready(chunk)
+    })
+}
+
+/// Stream producer that transcodes a stream of input bytes in the given encoding into UTF-8.
+fn transcode_to_utf8<S>(
+    encoding: &'static Encoding,
+    errors: EncodingErrors,
+    input: S,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    try_stream! {
+        let mut decoder = encoding.new_decoder_with_bom_removal();
+        let mut offset = 0;
+        for await chunk in input {
+            let chunk = chunk?;
+            yield decode_chunk(&mut decoder, errors, &chunk, offset, false)?;
+            offset += chunk.len();
+        }
+        yield decode_chunk(&mut decoder, errors, &[], offset, true)?;
+    }
+}
+
+/// Stream producer that prepares a stream of CSV file contents for parsing, by transcoding it to UTF-8
+/// and skipping lines according to the parse options.
+pub fn decode_csv_input<S, E>(
+    csv_parse_options: &CsvParseOptions,
+    csv_file: S,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // KLUDGE: csv_async currently requires errors to be std::io::Error since it assumes it's reading from
+    //         an io device directly. We're just mapping all errors as std::io::ErrorKind::Other for now, but
+    //         we could be more finely detailed if it turns out csv_async handles some std::io::Error variants
+    //         specially.
+    let csv_file = csv_file.map_err(std::io::Error::other);
+    // NOTE: the byte range is of the input as it was uploaded, so it's selected before transcoding.
+    let csv_file = select_byte_range(csv_parse_options, csv_file);
+    // UTF-8 input is passed straight through since csv_async already rejects invalid UTF-8, so we only
+    // need to transcode other encodings or when invalid sequences should be replaced. Either way a BOM
+    // at the start of the input is removed.
+    let encoding = csv_parse_options.encoding.unwrap_or(UTF_8);
+    let errors = csv_parse_options.encoding_errors;
+    let csv_file = if encoding != UTF_8 || errors == EncodingErrors::Replace {
+        Either::Left(transcode_to_utf8(encoding, errors, csv_file))
+    } else {
+        Either::Right(strip_utf8_bom(csv_file))
+    };
+    // Lines are skipped after transcoding so that they're split on the terminator character rather than on
+    // a byte that may have a different meaning in the input encoding.
+    match csv_parse_options.skip_rows {
+        0 => Either::Left(csv_file),
+        skip_rows => {
+            let terminator = csv_parse_options.terminator.map_or(b'\n', |t| t as u8);
+            Either::Right(skip_lines(csv_file, skip_rows, terminator))
+        }
+    }
+}
+
+/// Creates a CSV reader for the input, configured according to the parse options.
+fn create_csv_reader<R>(options: &CsvParseOptions, input: R) -> csv_async::AsyncReader<R>
+where
+    R: futures::io::AsyncRead + Unpin + Send,
+{
+    let mut builder = csv_async::AsyncReaderBuilder::new();
+    builder
+        .delimiter(options.delimiter() as u8)
+        .quote(options.quote as u8)
+        .escape(options.escape.map(|escape| escape as u8))
+        .comment(options.comment.map(|comment| comment as u8))
+        .terminator(match options.terminator {
+            Some(terminator) => csv_async::Terminator::Any(terminator as u8),
+            None => csv_async::Terminator::CRLF,
+        })
+        .trim(options.trim.into())
+        .has_headers(options.has_headers)
+        // NOTE: records are always read flexibly so that records with the wrong number of fields can
+        //       be handled according to the ragged option, rather than always failing.
+        .flexible(true);
+    builder.create_reader(input)
+}
+
+/// Scans the bytes of the rows as they're read, to check them against the `max_columns` and
+/// `max_cell_bytes` limits before the CSV reader holds on to a whole row. The number of fields is only
+/// checked for the header row, since the fields of later rows are checked once they're read anyway.
+#[derive(Debug)]
+struct RowLimitScan {
+    delimiter: u8,
+    quote: u8,
+    comment: Option<u8>,
+    terminator: Option<u8>,
+    max_columns: Option<usize>,
+    max_cell_bytes: Option<usize>,
+    header: bool,
+    line: u64,
+    row_line: u64,
+    quoted: bool,
+    line_start: bool,
+    in_comment: bool,
+    fields: usize,
+    field_bytes: usize,
+    done: bool,
+}
+
+impl RowLimitScan {
+    fn new(options: &CsvParseOptions) -> Self {
+        let header = options.has_headers;
+        RowLimitScan {
+            delimiter: options.delimiter() as u8,
+            quote: options.quote as u8,
+            comment: options.comment.map(|comment| comment as u8),
+            terminator: options.terminator.map(|terminator| terminator as u8),
+            max_columns: options.max_columns,
+            max_cell_bytes: options.max_cell_bytes,
+            header,
+            line: 1,
+            row_line: 1,
+            quoted: false,
+            line_start: true,
+            in_comment: false,
+            fields: 1,
+            field_bytes: 0,
+            done: options.max_cell_bytes.is_none() && !(header && options.max_columns.is_some()),
+        }
+    }
+
+    fn is_terminator(&self, byte: u8) -> bool {
+        match self.terminator {
+            Some(terminator) => byte == terminator,
+            None => byte == b'\n' || byte == b'\r',
+        }
+    }
+
+    fn end_row(&mut self) {
+        self.header = false;
+        self.fields = 1;
+        self.field_bytes = 0;
+        self.line_start = true;
+        self.done = self.max_cell_bytes.is_none();
+    }
+
+    /// Scans the next chunk of input, returning the offset of the byte that takes a row over the limits
+    /// along with the error. Quotes aren't counted towards the size of a field, so this never fails for
+    /// a row that the exact check after reading it would allow.
+    fn scan(&mut self, chunk: &[u8]) -> Option<(usize, std::io::Error)> {
+        for (offset, &byte) in chunk.iter().enumerate() {
+            if self.done {
+                break;
+            }
+            if byte == b'\n' {
+                self.line += 1;
+            }
+            if self.in_comment || (self.line_start && Some(byte) == self.comment) {
+                self.in_comment = !self.is_terminator(byte);
+                self.line_start = !self.in_comment;
+                continue;
+            }
+            if byte == self.quote {
+                self.quoted = !self.quoted;
+            } else if !self.quoted && byte == self.delimiter {
+                self.fields += 1;
+                self.field_bytes = 0;
+            } else if !self.quoted && self.is_terminator(byte) {
+                // Empty lines are skipped by the CSV reader, so they don't end a row.
+                if !self.line_start {
+                    self.end_row();
+                }
+                continue;
+            } else {
+                self.field_bytes += 1;
+            }
+            if self.line_start {
+                self.row_line = self.line;
+                self.line_start = false;
+            }
+            let message = match (self.max_columns, self.max_cell_bytes) {
+                (Some(max_columns), _) if self.header && self.fields > max_columns => format!(
+                    "header row has more fields than the limit of {}",
+                    max_columns
+                ),
+                (_, Some(max_cell_bytes)) if self.header && self.field_bytes > max_cell_bytes => {
+                    format!(
+                        "field {} of the header row is more than the limit of {} bytes",
+                        self.fields, max_cell_bytes
+                    )
+                }
+                (_, Some(max_cell_bytes)) if self.field_bytes > max_cell_bytes => format!(
+                    "line {}: field {} is more than the limit of {} bytes",
+                    self.row_line, self.fields, max_cell_bytes
+                ),
+                _ => continue,
+            };
+            self.done = true;
+            return Some((
+                offset,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, message),
+            ));
+        }
+        None
+    }
+}
+
+/// The start of a chunk of input, so that the part of a chunk before an error can still be read.
+struct ChunkPrefix<B> {
+    chunk: B,
+    len: usize,
+}
+
+impl<B: AsRef<[u8]>> AsRef<[u8]> for ChunkPrefix<B> {
+    fn as_ref(&self) -> &[u8] {
+        &self.chunk.as_ref()[..self.len]
+    }
+}
+
+/// Stream adapter that fails as soon as a row exceeds the `max_columns` or `max_cell_bytes` limits,
+/// rather than after the CSV reader has read it in full, so that a huge header row or field can't take
+/// up memory before the limits are checked. The input before the offending byte is still passed on,
+/// so that the records before it are converted as usual.
+fn limit_rows<S, B>(
+    options: &CsvParseOptions,
+    input: S,
+) -> impl Stream<Item = std::io::Result<ChunkPrefix<B>>>
+where
+    S: Stream<Item = std::io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let mut scan = RowLimitScan::new(options);
+    input.flat_map(move |chunk| {
+        let (chunk, error) = match chunk {
+            Ok(chunk) => {
+                let (len, error) = match scan.scan(chunk.as_ref()) {
+                    Some((offset, error)) => (offset, Some(error)),
+                    None => (chunk.as_ref().len(), None),
+                };
+                (Some(ChunkPrefix { chunk, len }), error)
+            }
+            Err(error) => (None, Some(error)),
+        };
+        futures::stream::iter(chunk.map(Ok).into_iter().chain(error.map(Err)))
+    })
+}
+
+/// Candidate delimiters that `delimiter=auto` chooses from.
+const DELIMITER_CANDIDATES: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// Maximum number of bytes that are read to detect the delimiter, if the first line is longer.
+const DELIMITER_DETECTION_BYTES: usize = 64 * 1024;
+
+/// Resolves `delimiter=auto` to the delimiter that's detected in the first line of the input. This has
+/// to read the start of the input, so the chunks that were read are put back in front of the rest of it.
+async fn resolve_delimiter<S, B>(
+    options: &mut CsvParseOptions,
+    mut input: S,
+) -> csv_async::Result<impl Stream<Item = std::io::Result<B>>>
+where
+    S: Stream<Item = std::io::Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut chunks = Vec::new();
+    if options.delimiter == Some(Delimiter::Auto) {
+        let mut head = Vec::new();
+        while !head.contains(&b'\n')
+            && !head.contains(&b'\r')
+            && head.len() < DELIMITER_DETECTION_BYTES
+        {
+            match input.next().await {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    head.extend_from_slice(chunk.as_ref());
+                    chunks.push(chunk);
+                }
+                None => break,
+            }
+        }
+        let delimiter = detect_delimiter(&head, options.quote as u8);
+        options.delimiter = Some(Delimiter::Char(delimiter));
+    }
+    Ok(futures::stream::iter(chunks.into_iter().map(Ok)).chain(input))
+}
+
+/// Detects the delimiter of a CSV as the candidate that occurs most often in its first line, outside of
+/// quoted fields. Falls back to a comma if none of them occur, or if it's a tie.
+fn detect_delimiter(head: &[u8], quote: u8) -> char {
+    let mut counts = [0; DELIMITER_CANDIDATES.len()];
+    let mut quoted = false;
+    for &byte in head {
+        if byte == quote {
+            quoted = !quoted;
+        } else if quoted {
+            continue;
+        } else if byte == b'\n' || byte == b'\r' {
+            break;
+        } else if let Some(candidate) = DELIMITER_CANDIDATES.iter().position(|&c| c == byte) {
+            counts[candidate] += 1;
+        }
+    }
+    let max = counts.iter().copied().max().unwrap_or_default();
+    let mut most_common = DELIMITER_CANDIDATES
+        .iter()
+        .zip(counts)
+        .filter(|&(_, count)| count == max);
+    match (most_common.next(), most_common.next()) {
+        (Some((&delimiter, _)), None) if max > 0 => delimiter as char,
+        _ => ',',
+    }
+}
+
+/// Reads the header row and returns the column names it defines, after converting their case and
+/// deduplicating them. Returns None if the CSV doesn't have a header row.
+async fn read_column_names<R>(
+    options: &CsvParseOptions,
+    reader: &mut csv_async::AsyncReader<R>,
+) -> Result<Option<Vec<String>>, CsvError>
+where
+    R: futures::io::AsyncRead + Unpin + Send,
+{
+    if !options.has_headers {
+        return Ok(None);
+    }
+    let headers = reader.headers().await?;
+    // An empty input doesn't even have a header row, so there are no columns to check.
+    if headers.is_empty() {
+        return Ok(None);
+    }
+    header_column_names(options, headers)
+        .map(Some)
+        .map_err(|error| CsvError::in_record(error, headers))
+}
+
+/// Slices a line of fixed-width input into fields of the `widths`, which are counted in characters.
+/// Fields are trimmed of the whitespace they're padded with, and fields past the end of a short line
+/// are empty. Any text after the last field is kept as an extra field, so that it's handled like any
+/// other ragged row.
+fn split_fixed_width_line(
+    line: &str,
+    widths: &[usize],
+    line_number: u64,
+) -> csv_async::StringRecord {
+    let mut fields = Vec::with_capacity(widths.len() + 1);
+    let mut rest = line;
+    for &width in widths {
+        let end = rest
+            .char_indices()
+            .nth(width)
+            .map_or(rest.len(), |(end, _)| end);
+        fields.push(rest[..end].trim());
+        rest = &rest[end..];
+    }
+    if !rest.trim().is_empty() {
+        fields.push(rest.trim());
+    }
+    let mut record = csv_async::StringRecord::from(fields);
+    let mut position = csv_async::Position::new();
+    position.set_line(line_number);
+    record.set_position(Some(position));
+    record
+}
+
+/// Stream producer that reads the records of fixed-width input, which is split into lines on the
+/// terminator rather than parsed by a CSV reader. Like with the CSV reader, a `\r` before a newline is
+/// removed, and empty lines and comment lines are skipped.
+fn fixed_width_records<S, B>(
+    options: &CsvParseOptions,
+    input: S,
+) -> impl Stream<Item = csv_async::Result<csv_async::StringRecord>>
+where
+    S: Stream<Item = std::io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let widths = options.widths.clone().unwrap_or_default();
+    let terminator = options.terminator.map_or(b'\n', |t| t as u8);
+    let comment = options.comment.map(|comment| comment as u8);
+    try_stream! {
+        pin_mut!(input);
+        let mut buffer = BytesMut::new();
+        let mut scanned = 0;
+        let mut line_number = 0;
+        loop {
+            let chunk = input.next().await.transpose()?;
+            match &chunk {
+                Some(chunk) => buffer.extend_from_slice(chunk.as_ref()),
+                None if buffer.is_empty() => break,
+                // The last line doesn't need to be terminated.
+                None => buffer.extend_from_slice(&[terminator]),
+            }
+            while let Some(end) = buffer[scanned..].iter().position(|&byte| byte == terminator) {
+                let line = buffer.split_to(scanned + end + 1);
+                scanned = 0;
+                line_number += 1;
+                let mut line = &line[..line.len() - 1];
+                if terminator == b'\n' {
+                    line = line.strip_suffix(b"\r").unwrap_or(line);
+                }
+                if line.is_empty() || line.first() == comment.as_ref() {
+                    continue;
+                }
+                let line = std::str::from_utf8(line).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("line {}: invalid UTF-8", line_number),
+                    )
+                })?;
+                let record = split_fixed_width_line(line, &widths, line_number);
+                yield record;
+            }
+            scanned = buffer.len();
+            if chunk.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+/// Reads the column names from the header row, if there is one, and returns them along with the
+/// records that follow it. Records are read with a CSV reader, or as lines of fixed-width fields with
+/// `format=fixed`.
+async fn read_records<'r, S, B>(
+    options: &mut CsvParseOptions,
+    input: S,
+) -> Result<
+    (
+        Option<Vec<String>>,
+        impl Stream<Item = csv_async::Result<csv_async::StringRecord>> + 'r,
+    ),
+    CsvError,
+>
+where
+    S: Stream<Item = std::io::Result<B>> + Unpin + Send + 'r,
+    B: AsRef<[u8]> + Send + 'r,
+{
+    if options.format == CsvFormat::Fixed {
+        let mut records = Box::pin(fixed_width_records(options, input));
+        let mut columns = None;
+        if options.has_headers {
+            if let Some(headers) = records.next().await {
+                let headers = headers?;
+                columns = Some(
+                    header_column_names(options, &headers)
+                        .map_err(|error| CsvError::in_record(error, &headers))?,
+                );
+            }
+        }
+        return Ok((columns, Either::Left(records)));
+    }
+    let input = resolve_delimiter(options, input).await?;
+    let input = limit_rows(options, input);
+    let mut reader = create_csv_reader(options, input.into_async_read());
+    let columns = read_column_names(options, &mut reader).await?;
+    Ok((columns, Either::Right(reader.into_records())))
+}
+
+/// Returns the column names that a header row defines.
+fn header_column_names(
+    options: &CsvParseOptions,
+    headers: &csv_async::StringRecord,
+) -> csv_async::Result<Vec<String>> {
+    options.check_record_limits(headers)?;
+    // Header names are deduplicated after converting their case, since headers that only differ in case
+    // may end up with the same name.
+    let names = headers
+        .iter()
+        .map(|name| options.key_case.convert(name))
+        .collect();
+    let names = dedupe_headers(names, options.dedupe_headers)?;
+    options.check_included_columns(&names)?;
+    Ok(names)
+}
+
+/// Returns the column names for a CSV without a header row. Columns are named by the `headers` option
+/// if given, and otherwise each field is keyed by its zero-based column index. The first record
+/// determines the number of columns, unless more names were given.
+fn index_column_names(
+    options: &CsvParseOptions,
+    record: &csv_async::StringRecord,
+) -> csv_async::Result<Vec<String>> {
+    let given = options.headers.as_deref().unwrap_or_default();
+    let names = given
+        .iter()
+        .map(|name| options.key_case.convert(name))
+        .chain((given.len()..record.len()).map(|index| index.to_string()))
+        .collect();
+    let names = dedupe_headers(names, options.dedupe_headers)?;
+    options.check_included_columns(&names)?;
+    Ok(names)
+}
+
+/// Converts a record of the CSV after checking it against the limits, and fitting it to the columns.
+/// Without a header row, the columns are determined by the first record.
+fn convert_csv_record(
+    options: &CsvParseOptions,
+    columns: &mut Option<Vec<String>>,
+    record: &csv_async::StringRecord,
+    row_number: u64,
+) -> csv_async::Result<JsonRecord> {
+    options.check_record_limits(record)?;
+    if columns.is_none() {
+        *columns = Some(index_column_names(options, record)?);
+    }
+    let names = columns.as_ref().expect("columns were just set");
+    check_field_factor(options, record, names)?;
+    let fields = fit_record_to_columns(record, names.len(), options.ragged)?;
+    let fields = names.iter().cloned().zip(fields).collect();
+    JsonRecord::from_csv_record(CsvRecord(fields), options, row_number)
+}
+
+// Stream producer that takes a stream of input bytes and attempts to deserialize them as CsvRecords.
+// This assumes that the input stream represents UTF-8 encoded string data, and will produce errors
+// if input data is not properly UTF-8 encoded.
+pub fn parse_csv_records<S, B>(
+    options: CsvParseOptions,
+    input: S,
+) -> impl Stream<Item = Result<JsonRecord, CsvError>>
+where
+    S: Stream<Item = std::io::Result<B>> + Send,
+    B: AsRef<[u8]> + Send,
+{
+    try_stream! {
+        pin_mut!(input);
+        let mut options = options;
+        let (mut columns, records) = read_records(&mut options, input).await?;
+        let mut row_number = 0;
+        for await record in records {
+            let record = record?;
+            // Blank records are left out before they're counted, so that they don't get a row number.
+            if options.is_blank_record(&record) {
+                continue;
+            }
+            row_number += 1;
+            yield convert_csv_record(&options, &mut columns, &record, row_number)
+                .map_err(|error| CsvError::in_record(error, &record))?;
+        }
+    }
+}
+
+/// Summary of a CSV that was checked with the validate endpoint, without converting it.
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationSummary {
+    /// Number of records that were read before the end of the CSV, or before an error.
+    pub records: u64,
+    /// Names of the columns, which are zero-based indices when the CSV doesn't have a header row.
+    pub columns: Vec<String>,
+    /// The field delimiter, which is the detected delimiter with `delimiter=auto`.
+    pub delimiter: char,
+    /// Number of records with a different number of fields than there are columns, that were
+    /// handled according to the `ragged` option.
+    pub ragged_rows: u64,
+    /// Message of the first error in the CSV, which stops validating it. Null if the CSV is valid.
+    pub error: Option<String>,
+}
+
+/// Reads a whole CSV the same way as parse_csv_records, but only counts the records rather than
+/// yielding them. Any error is reported in the summary rather than returned.
+pub async fn validate_csv_records<S, B>(mut options: CsvParseOptions, input: S) -> ValidationSummary
+where
+    S: Stream<Item = std::io::Result<B>> + Send,
+    B: AsRef<[u8]> + Send,
+{
+    let mut summary = ValidationSummary::default();
+    let mut columns = None;
+    let result = async {
+        pin_mut!(input);
+        let (read_columns, mut records) = read_records(&mut options, input).await?;
+        columns = read_columns;
+        while let Some(record) = records.next().await {
+            let record = record?;
+            if options.is_blank_record(&record) {
+                continue;
+            }
+            // The record is still converted, since the conversion itself can fail, e.g. on invalid dates.
+            convert_csv_record(&options, &mut columns, &record, summary.records + 1)
+                .map_err(|error| CsvError::in_record(error, &record))?;
+            if columns
+                .as_ref()
+                .is_some_and(|columns| columns.len() != record.len())
+            {
+                summary.ragged_rows += 1;
+            }
+            summary.records += 1;
+        }
+        Result::<(), CsvError>::Ok(())
+    }
+    .await;
+    summary.columns = columns.unwrap_or_default();
+    summary.delimiter = options.delimiter();
+    if let Err(error) = result {
+        summary.error = Some(error.to_string());
+    }
+    summary
+}
+
+/// JSON type of a column, as inferred from its fields in the same way as with `infer-types`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Integer,
+    Number,
+    Boolean,
+    String,
+}
+
+impl ColumnType {
+    /// Returns the type of a single non-empty field.
+    fn of_field(field: &str, big_numbers: BigNumbers) -> Self {
+        match infer_value(field.to_string(), big_numbers) {
+            FieldValue::Json(Value::Number(number)) if number.is_f64() => ColumnType::Number,
+            FieldValue::Json(Value::Number(_)) | FieldValue::BigInteger(_) => ColumnType::Integer,
+            FieldValue::Json(Value::Bool(_)) => ColumnType::Boolean,
+            _ => ColumnType::String,
+        }
+    }
+
+    /// Returns the type that fits fields of both types. Integers widen to numbers, and anything else
+    /// that's mixed is a string.
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Integer, ColumnType::Number)
+            | (ColumnType::Number, ColumnType::Integer) => ColumnType::Number,
+            _ => ColumnType::String,
+        }
+    }
+}
+
+/// Name and inferred type of a column of a CSV.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ColumnSchema {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+/// The columns of a CSV, as returned with the `schema-only` option.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CsvSchema {
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl CsvSchema {
+    /// Returns a JSON Schema of the array of records that the CSV is converted to with the options.
+    /// Columns with one of the `types` have that type, and without `infer-types` every other field is
+    /// a string. Fields that may be converted to null allow null as well.
+    pub fn to_json_schema(&self, options: &CsvParseOptions) -> Value {
+        let string_nullable = options.empty_as_null || options.null_tokens.is_some();
+        let row_number = options
+            .add_row_number
+            .iter()
+            .map(|key| (key.clone(), serde_json::json!({ "type": "integer" })));
+        let properties: serde_json::Map<String, Value> = row_number
+            .chain(self.columns.iter().map(|column| {
+                let (column_type, nullable) = match options.column_type(&column.name) {
+                    Some(FieldType::Int) => ("integer", true),
+                    Some(FieldType::Float) => ("number", true),
+                    Some(FieldType::Bool) => ("boolean", true),
+                    Some(FieldType::String) => ("string", string_nullable),
+                    None => {
+                        let column_type = match (options.infer_types, column.column_type) {
+                            (true, ColumnType::Integer) => "integer",
+                            (true, ColumnType::Number) => "number",
+                            (true, ColumnType::Boolean) => "boolean",
+                            _ => "string",
+                        };
+                        (column_type, options.infer_types || string_nullable)
+                    }
+                };
+                let column_type = if nullable {
+                    serde_json::json!([column_type, "null"])
+                } else {
+                    serde_json::json!(column_type)
+                };
+                (
+                    column.name.clone(),
+                    serde_json::json!({ "type": column_type }),
+                )
+            }))
+            .collect();
+        serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": properties,
+                "required": properties.keys().collect::<Vec<_>>(),
+                "additionalProperties": false,
+            },
+        })
+    }
+}
+
+/// Reads the column names of a CSV and infers their types from up to `sample_rows` records, without
+/// reading the rest of it. Empty fields and `null-tokens` don't affect the types, and columns without any non-empty
+/// fields in the sample are strings. Columns are named and selected the same way as when converting
+/// the CSV.
+pub async fn read_csv_schema<S, B>(
+    options: CsvParseOptions,
+    input: S,
+    sample_rows: usize,
+) -> Result<CsvSchema, CsvError>
+where
+    S: Stream<Item = std::io::Result<B>> + Send,
+    B: AsRef<[u8]> + Send,
+{
+    let mut options = options;
+    pin_mut!(input);
+    let (mut columns, mut records) = read_records(&mut options, input).await?;
+    let mut types: Vec<Option<ColumnType>> = Vec::new();
+    let mut sampled_rows = 0;
+    while sampled_rows < sample_rows {
+        let Some(record) = records.next().await else {
+            break;
+        };
+        let record = record?;
+        // Blank records aren't sampled, since fields of only whitespace would make their columns strings.
+        if options.is_blank_record(&record) {
+            continue;
+        }
+        sampled_rows += 1;
+        if columns.is_none() {
+            columns = Some(
+                index_column_names(&options, &record)
+                    .map_err(|error| CsvError::in_record(error, &record))?,
+            );
+        }
+        let names = columns.as_ref().expect("columns were just set");
+        check_field_factor(&options, &record, names)
+            .map_err(|error| CsvError::in_record(error, &record))?;
+        let fields = fit_record_to_columns(&record, names.len(), options.ragged)
+            .map_err(|error| CsvError::in_record(error, &record))?;
+        types.resize(names.len(), None);
+        for (column_type, field) in types.iter_mut().zip(&fields) {
+            if !field.is_empty() && !options.is_null_token(field) {
+                let field_type = ColumnType::of_field(field, options.big_number);
+                *column_type = Some(column_type.map_or(field_type, |t| t.merge(field_type)));
+            }
+        }
+    }
+    let columns = columns
+        .unwrap_or_default()
+        .into_iter()
+        .zip(types.into_iter().chain(std::iter::repeat(None)))
+        .filter(|(name, _)| options.includes_column(name))
+        .map(|(name, column_type)| ColumnSchema {
+            name,
+            column_type: column_type.unwrap_or(ColumnType::String),
+        })
+        .collect();
+    Ok(CsvSchema { columns })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn json_number_grammar() {
+        for number in [
+            "0", "-0", "42", "-42", "1.5", "0.25", "1e10", "1E-3", "-2.5e+7",
+        ] {
+            assert!(is_json_number(number), "{} should be a number", number);
+        }
+        for not_number in [
+            "", "-", "007", "+1", ".5", "1.", "1e", "1e+", "0x1F", "1,000", " 1",
+        ] {
+            assert!(
+                !is_json_number(not_number),
+                "{} should not be a number",
+                not_number
+            );
+        }
+    }
+
+    #[test]
+    fn counts_significant_digits() {
+        for (number, expected) in [
+            ("0", 0),
+            ("-42", 2),
+            ("0.00125", 3),
+            ("1.50", 2),
+            ("100", 3),
+            ("6.02214076e23", 9),
+            ("3.14159265358979323846", 21),
+        ] {
+            assert_eq!(significant_digits(number), expected, "{}", number);
+        }
+    }
+
+    #[test]
+    fn detects_delimiter_split_across_chunks() -> csv_async::Result<()> {
+        let mut options = CsvParseOptions {
+            delimiter: Some(Delimiter::Auto),
+            ..CsvParseOptions::default()
+        };
+        let chunks = ["a;", "b", ";c\n1;2;3\n"].map(|chunk| Ok(Bytes::from(chunk)));
+        let output: Vec<Bytes> = block_on(async {
+            let input = resolve_delimiter(&mut options, futures::stream::iter(chunks)).await?;
+            Ok::<_, csv_async::Error>(input.try_collect().await?)
+        })?;
+        assert_eq!(options.delimiter, Some(Delimiter::Char(';')));
+        assert_eq!(output, ["a;", "b", ";c\n1;2;3\n"].map(Bytes::from));
+        Ok(())
+    }
+
+    #[test]
+    fn skips_lines_split_across_chunks() -> std::io::Result<()> {
+        let chunks = ["ban", "ner\n\nhea", "der\n1\n"].map(|chunk| Ok(Bytes::from(chunk)));
+        let output: Vec<Bytes> =
+            block_on(skip_lines(futures::stream::iter(chunks), 2, b'\n').try_collect())?;
+        assert_eq!(output, vec![Bytes::from("hea"), Bytes::from("der\n1\n")]);
+        Ok(())
+    }
+
+    #[test]
+    fn strips_utf8_bom_split_across_chunks() -> std::io::Result<()> {
+        let chunks =
+            [&b"\xEF"[..], b"", b"\xBB\xBFi", b"d"].map(|chunk| Ok(Bytes::from_static(chunk)));
+        let output: Vec<Bytes> =
+            block_on(strip_utf8_bom(futures::stream::iter(chunks)).try_collect())?;
+        assert_eq!(output, vec![Bytes::from("i"), Bytes::from("d")]);
+
+        let chunks = [Ok(Bytes::from_static(b"\xEF\xBB"))];
+        let output: Vec<Bytes> =
+            block_on(strip_utf8_bom(futures::stream::iter(chunks)).try_collect())?;
+        assert_eq!(output, vec![Bytes::from_static(b"\xEF\xBB")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Requests and uploaded bytes of a single client within the current window.
+#[derive(Debug)]
+struct ClientWindow {
+    started: Instant,
+    requests: u64,
+    bytes: u64,
+}
+
+impl ClientWindow {
+    fn new(started: Instant) -> Self {
+        ClientWindow {
+            started,
+            requests: 0,
+            bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Clients {
+    windows: HashMap<IpAddr, ClientWindow>,
+    /// When windows that have ended were last removed, which is done at most once per window.
+    last_expired: Instant,
+}
+
+/// Limits the number of requests, and the number of uploaded bytes, per client IP address within a
+/// fixed window of time. Each client's window starts with its first request, and once it has ended the
+/// client's counts start over.
+#[derive(Debug)]
+pub struct RateLimiter {
+    window: Duration,
+    max_requests: Option<u64>,
+    max_bytes: Option<u64>,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(window: Duration, max_requests: Option<u64>, max_bytes: Option<u64>) -> Self {
+        RateLimiter {
+            window,
+            max_requests,
+            max_bytes,
+            clients: Mutex::new(Clients {
+                windows: HashMap::new(),
+                last_expired: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request from the client, unless it has already reached one of the limits within its
+    /// current window. In that case returns how long it has to wait until the window ends.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self
+            .clients
+            .lock()
+            .expect("rate limiter lock isn't poisoned");
+        // Windows of clients that stopped making requests are removed, so that the map doesn't keep
+        // growing with every client that was ever seen.
+        if now.duration_since(clients.last_expired) >= self.window {
+            let window = self.window;
+            clients
+                .windows
+                .retain(|_, client| now.duration_since(client.started) < window);
+            clients.last_expired = now;
+        }
+        let client = clients
+            .windows
+            .entry(client)
+            .or_insert_with(|| ClientWindow::new(now));
+        if now.duration_since(client.started) >= self.window {
+            *client = ClientWindow::new(now);
+        }
+        let limited = self.max_requests.is_some_and(|max| client.requests >= max)
+            || self.max_bytes.is_some_and(|max| client.bytes >= max);
+        if limited {
+            return Err(self.window - now.duration_since(client.started));
+        }
+        client.requests += 1;
+        Ok(())
+    }
+
+    /// Counts bytes uploaded by the client towards its current window. Uploads that are already in
+    /// progress aren't cut off once the limit is reached, but the client's next request is limited.
+    pub fn count_bytes(&self, client: IpAddr, bytes: usize) {
+        if self.max_bytes.is_none() {
+            return;
+        }
+        let mut clients = self
+            .clients
+            .lock()
+            .expect("rate limiter lock isn't poisoned");
+        if let Some(client) = clients.windows.get_mut(&client) {
+            client.bytes += bytes as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn limits_requests_and_bytes_per_client() {
+        let alice = IpAddr::from([10, 0, 0, 1]);
+        let bob = IpAddr::from([10, 0, 0, 2]);
+        let limiter = RateLimiter::new(Duration::from_secs(60), Some(2), Some(100));
+        assert_eq!(limiter.check(alice), Ok(()));
+        assert_eq!(limiter.check(alice), Ok(()));
+        let retry_after = limiter.check(alice).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60), "{:?}", retry_after);
+
+        assert_eq!(limiter.check(bob), Ok(()));
+        limiter.count_bytes(bob, 100);
+        assert!(limiter.check(bob).is_err());
+    }
+
+    #[test]
+    fn starts_over_once_the_window_ends() {
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let limiter = RateLimiter::new(Duration::from_millis(20), Some(1), None);
+        assert_eq!(limiter.check(client), Ok(()));
+        assert!(limiter.check(client).is_err());
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(limiter.check(client), Ok(()));
+    }
+}
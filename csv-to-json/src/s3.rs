@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use bytes::{Bytes, BytesMut};
+use futures::{pin_mut, Stream, StreamExt};
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Minimum size of each part of a multipart upload, apart from the last one, as required by S3.
+const MIN_PART_BYTES: usize = 5 * 1024 * 1024;
+
+/// Builds an S3 client for the region, using the given static credentials. With an `endpoint` the
+/// client talks to an S3-compatible store at that URL instead of AWS, addressing buckets by path
+/// since such stores often don't support bucket subdomains.
+pub fn build_client(
+    region: &str,
+    endpoint: Option<&str>,
+    access_key_id: &str,
+    secret_access_key: &str,
+) -> Client {
+    let credentials = Credentials::new(access_key_id, secret_access_key, None, None, "csv-to-json");
+    let mut builder = aws_sdk_s3::Config::builder()
+        .region(Region::new(region.to_string()))
+        .credentials_provider(credentials);
+    if let Some(endpoint) = endpoint {
+        builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+    Client::from_conf(builder.build())
+}
+
+/// The bucket and key of an S3 object, parsed from an `s3://bucket/key` URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl FromStr for S3Location {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (bucket, key) = url
+            .strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+            .ok_or_else(|| format!("{:?} isn't an s3://bucket/key URL", url))?;
+        if bucket.is_empty() || key.is_empty() {
+            return Err(format!("{:?} is missing a bucket or key", url));
+        }
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Why streaming output to S3 failed.
+#[derive(Debug)]
+pub enum UploadError {
+    /// The output stream failed, e.g. because of an error in the CSV.
+    Output(anyhow::Error),
+    /// S3 rejected the upload, or couldn't be reached.
+    S3(anyhow::Error),
+}
+
+/// Streams output to an S3 object with a multipart upload, so that arbitrarily large output can be
+/// uploaded without holding it in memory. The output is collected into parts of at least
+/// MIN_PART_BYTES, and each part is uploaded as soon as it's complete. If anything fails, the upload
+/// is aborted so that S3 doesn't keep the parts that were already uploaded.
+pub async fn upload_stream<S>(
+    client: &Client,
+    location: &S3Location,
+    content_type: &str,
+    output: S,
+) -> Result<(), UploadError>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let upload = client
+        .create_multipart_upload()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .content_type(content_type)
+        .send()
+        .await
+        .map_err(|error| {
+            UploadError::S3(anyhow!(
+                "failed to start uploading to {}: {}",
+                location,
+                DisplayErrorContext(error)
+            ))
+        })?;
+    let upload_id = upload
+        .upload_id()
+        .context("S3 didn't return an upload ID")
+        .map_err(UploadError::S3)?;
+    let parts = match upload_parts(client, location, upload_id, output).await {
+        Ok(parts) => parts,
+        Err(error) => {
+            let abort = client
+                .abort_multipart_upload()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+            if let Err(abort_error) = abort {
+                warn!(
+                    "failed to abort upload to {}: {}",
+                    location,
+                    DisplayErrorContext(abort_error)
+                );
+            }
+            return Err(error);
+        }
+    };
+    client
+        .complete_multipart_upload()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|error| {
+            UploadError::S3(anyhow!(
+                "failed to complete upload to {}: {}",
+                location,
+                DisplayErrorContext(error)
+            ))
+        })?;
+    Ok(())
+}
+
+/// Uploads the output in parts, returning the completed parts. An upload always has at least one
+/// part, even if the output is empty.
+async fn upload_parts<S>(
+    client: &Client,
+    location: &S3Location,
+    upload_id: &str,
+    output: S,
+) -> Result<Vec<CompletedPart>, UploadError>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    pin_mut!(output);
+    let mut parts = Vec::new();
+    let mut part = BytesMut::new();
+    loop {
+        let chunk = output.next().await;
+        let finished = chunk.is_none();
+        if let Some(chunk) = chunk {
+            part.extend_from_slice(&chunk.map_err(UploadError::Output)?);
+        }
+        // Once the output has finished, whatever is left of it is the last part. An upload needs at least
+        // one part though, so empty output is uploaded as a single empty part.
+        let last_part = finished && (!part.is_empty() || parts.is_empty());
+        if part.len() < MIN_PART_BYTES && !last_part {
+            if finished {
+                break;
+            }
+            continue;
+        }
+        let part_number = parts.len() as i32 + 1;
+        let uploaded = client
+            .upload_part()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(part.split().freeze()))
+            .send()
+            .await
+            .map_err(|error| {
+                UploadError::S3(anyhow!(
+                    "failed to upload part {} to {}: {}",
+                    part_number,
+                    location,
+                    DisplayErrorContext(error)
+                ))
+            })?;
+        parts.push(
+            CompletedPart::builder()
+                .set_e_tag(uploaded.e_tag().map(str::to_string))
+                .part_number(part_number)
+                .build(),
+        );
+        if finished {
+            break;
+        }
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_s3_locations() {
+        assert_eq!(
+            "s3://bucket/path/to/output.json".parse(),
+            Ok(S3Location {
+                bucket: "bucket".to_string(),
+                key: "path/to/output.json".to_string(),
+            })
+        );
+        for url in [
+            "https://bucket/key",
+            "s3://bucket",
+            "s3://bucket/",
+            "s3:///key",
+        ] {
+            assert!(url.parse::<S3Location>().is_err(), "{}", url);
+        }
+        let location: S3Location = "s3://bucket/key".parse().unwrap();
+        assert_eq!(location.to_string(), "s3://bucket/key");
+    }
+}
@@ -0,0 +1,325 @@
+use crate::options::{OutputFormat, OutputOptions};
+use crate::parse::{JsonElement, UTF_8_BOM};
+use anyhow::{anyhow, Context, Result};
+use async_stream::try_stream;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{pin_mut, Stream, TryStreamExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use tracing::warn;
+
+/// Writes to the end of a BytesMut. Unlike `BufMut::writer` every write is a plain `extend_from_slice`,
+/// which matters since serializers make many small writes.
+struct BufferWriter<'a>(&'a mut BytesMut);
+
+impl Write for BufferWriter<'_> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes a value to the buffer, compactly if `newline` is empty, or pretty-printed with each of its
+/// lines starting with `newline` so that it lines up with the surrounding JSON otherwise.
+fn write_json_value<T: Serialize>(
+    buffer: &mut BytesMut,
+    value: &T,
+    newline: &[u8],
+) -> serde_json::Result<()> {
+    if newline.is_empty() {
+        return serde_json::to_writer(BufferWriter(buffer), value);
+    }
+    let pretty_value = serde_json::to_vec_pretty(value)?;
+    // NOTE: JSON strings can't contain raw newlines, so every newline in the output is between tokens and can
+    //       safely be indented.
+    buffer.reserve(pretty_value.len());
+    for byte in pretty_value {
+        if byte == b'\n' {
+            buffer.extend_from_slice(newline);
+        } else {
+            buffer.put_u8(byte);
+        }
+    }
+    Ok(())
+}
+
+/// Stream producer that takes a stream of serde::Serialize values and serializes them to
+/// JSON in a UTF-8-encoed, binary chunked format. Depending on the `output` format the values are
+/// either wrapped in a single JSON array, emitted as newline-delimited JSON, or emitted as the data of
+/// Server-Sent Events. With `output=msgpack` they're serialized to MessagePack instead of JSON.
+///
+/// With the `envelope` option the JSON array is wrapped in an object: `{"records":[...],"error":null}`.
+/// Errors no longer terminate the stream in this case. Instead the array is closed early and the error
+/// message is reported in the `error` field, so that the output is always valid JSON.
+///
+/// With the `pretty` option the JSON is pretty-printed, with each value of the array on its own
+/// indented lines.
+///
+/// With the `unwrap_single` option a single value is output on its own rather than in a JSON array. The
+/// first value is held back until the next one has been read to tell whether it's the only one.
+///
+/// With the `bom` option the output starts with a UTF-8 byte order mark, unless it's nested in other
+/// output.
+///
+/// When the output is `keyed` the values are the members of a JSON object instead of the elements of
+/// an array, keyed by their JsonElement::key. Keys have to be unique, so a duplicate key is an error.
+pub fn serialize_json_seq<S, T, E>(
+    options: OutputOptions,
+    values: S,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: JsonElement,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let (open, close) = if options.keyed {
+        (b'{', b'}')
+    } else {
+        (b'[', b']')
+    };
+    let mut keys = HashSet::new();
+    // Writes a value of the JSON array, or a member of the JSON object if the output is keyed.
+    let mut write_element = move |buffer: &mut BytesMut, value: &T, newline: &[u8]| -> Result<()> {
+        if options.keyed {
+            let key = value.key().context("value has no key")?;
+            if !keys.insert(key.to_string()) {
+                return Err(anyhow!("duplicate key {:?}", key));
+            }
+            serde_json::to_writer(BufferWriter(buffer), key).context("failed to serialize key")?;
+            buffer.extend_from_slice(options.key_separator());
+        }
+        write_json_value(buffer, value, newline).context("failed to serialize value")
+    };
+    try_stream! {
+        // To give downstream consumers the most opportunity for optimization we'll have a single bytes buffer
+        // and periodically flush that buffer and yield it's contents to the stream. This is *probably* much
+        // better than yielding individual , and [ characters. With `flush_bytes` the buffer accumulates several
+        // values before it's flushed, which means fewer and larger chunks when there are many small values.
+        // Flushed chunks are split off the front of the buffer and frozen rather than copied, so they share its
+        // allocation, and the buffer reuses that allocation once the chunks have been dropped downstream.
+        let mut buffer = BytesMut::with_capacity(options.flush_bytes.max(1024));
+        pin_mut!(values);
+        let key_separator = options.key_separator();
+        if options.bom && options.depth == 0 {
+            buffer.extend_from_slice(UTF_8_BOM);
+        }
+
+        match options.output {
+            OutputFormat::Json if options.envelope => {
+                let value_newline = options.newline(2);
+                buffer.put_u8(b'{');
+                buffer.extend_from_slice(&options.newline(1));
+                buffer.extend_from_slice(br#""records""#);
+                buffer.extend_from_slice(key_separator);
+                buffer.put_u8(open);
+                let mut error = None;
+                let mut first = true;
+                for await value in values {
+                    // If anything goes wrong we roll the buffer back to before this value, so that the array only
+                    // contains complete values.
+                    let rollback = buffer.len();
+                    if !first {
+                        buffer.put_u8(b',');
+                    }
+                    buffer.extend_from_slice(&value_newline);
+                    let result = value
+                        .context("failed to read from input stream")
+                        .and_then(|value| write_element(&mut buffer, &value, &value_newline));
+                    if let Err(value_error) = result {
+                        buffer.truncate(rollback);
+                        error = Some(value_error);
+                        break;
+                    }
+                    first = false;
+                    if buffer.len() >= options.flush_bytes {
+                        yield buffer.split().freeze();
+                    }
+                }
+
+                if let Some(error) = &error {
+                    warn!("error during JSON serialization, reporting it in the envelope: {:?}", error);
+                }
+                if !first {
+                    buffer.extend_from_slice(&options.newline(1));
+                }
+                buffer.put_u8(close);
+                buffer.put_u8(b',');
+                buffer.extend_from_slice(&options.newline(1));
+                buffer.extend_from_slice(br#""error""#);
+                buffer.extend_from_slice(key_separator);
+                let error = error.map(|error| format!("{:#}", error));
+                serde_json::to_writer(BufferWriter(&mut buffer), &error).context("failed to serialize error")?;
+                buffer.extend_from_slice(&options.newline(0));
+                buffer.put_u8(b'}');
+                yield buffer.split().freeze();
+            }
+            OutputFormat::Json => {
+                let value_newline = options.newline(1);
+                // The first value won't need a leading array element separator "," so we treat it specially.
+                let first_value = values.try_next().await.context("failed to read from input stream")?;
+                // With `unwrap_single` a single value isn't wrapped in an array, but that's only known once the
+                // next value has been read, so the first value is held back until then.
+                let second_value = match &first_value {
+                    Some(_) if options.unwrap_single => {
+                        values.try_next().await.context("failed to read from input stream")?
+                    }
+                    _ => None,
+                };
+                if let (Some(first_value), None, true) = (&first_value, &second_value, options.unwrap_single) {
+                    write_json_value(&mut buffer, first_value, &options.newline(0))
+                        .context("failed to serialize value")?;
+                    yield buffer.split().freeze();
+                } else {
+                    buffer.put_u8(open);
+                    let empty = first_value.is_none();
+                    if let Some(first_value) = first_value {
+                        buffer.extend_from_slice(&value_newline);
+                        write_element(&mut buffer, &first_value, &value_newline)?;
+                    }
+                    if let Some(second_value) = second_value {
+                        buffer.put_u8(b',');
+                        buffer.extend_from_slice(&value_newline);
+                        write_element(&mut buffer, &second_value, &value_newline)?;
+                    }
+                    if buffer.len() >= options.flush_bytes {
+                        yield buffer.split().freeze();
+                    }
+
+                    // For all subsequent values, we have to emit a leading "," to separate each value in the JSON array.
+                    for await value in values {
+                        let rollback = buffer.len();
+                        let result = value
+                            .context("failed to read from input stream")
+                            .and_then(|value| {
+                                buffer.put_u8(b',');
+                                buffer.extend_from_slice(&value_newline);
+                                write_element(&mut buffer, &value, &value_newline)
+                            });
+                        if let Err(error) = result {
+                            // Values that were accumulated before the error are still sent, but not a partial value.
+                            buffer.truncate(rollback);
+                            if !buffer.is_empty() {
+                                yield buffer.split().freeze();
+                            }
+                            Err(error)?;
+                        }
+                        if buffer.len() >= options.flush_bytes {
+                            yield buffer.split().freeze();
+                        }
+                    }
+
+                    // Emit a final closing tag to finish the stream. Empty arrays are kept on a single line.
+                    if !empty {
+                        buffer.extend_from_slice(&options.newline(0));
+                    }
+                    buffer.put_u8(close);
+                    yield buffer.split().freeze();
+                }
+            }
+            OutputFormat::Ndjson => {
+                // Every value is terminated by a newline, so there's no leading or trailing framing and an empty
+                // input results in an empty output.
+                for await value in values {
+                    let rollback = buffer.len();
+                    let result = value
+                        .context("failed to read from input stream")
+                        .and_then(|value| {
+                            serde_json::to_writer(BufferWriter(&mut buffer), &value).context("failed to serialize value")
+                        });
+                    if let Err(error) = result {
+                        buffer.truncate(rollback);
+                        if !buffer.is_empty() {
+                            yield buffer.split().freeze();
+                        }
+                        Err(error)?;
+                    }
+                    buffer.put_u8(b'\n');
+                    if buffer.len() >= options.flush_bytes {
+                        yield buffer.split().freeze();
+                    }
+                }
+                if !buffer.is_empty() {
+                    yield buffer.split().freeze();
+                }
+            }
+            OutputFormat::Msgpack => {
+                // MessagePack values are self-delimiting, so like NDJSON the values are simply concatenated and
+                // can be decoded one after the other. Structs are serialized as maps to keep the field names.
+                for await value in values {
+                    let rollback = buffer.len();
+                    let result = value
+                        .context("failed to read from input stream")
+                        .and_then(|value| {
+                            rmp_serde::encode::write_named(&mut BufferWriter(&mut buffer), &value)
+                                .context("failed to serialize value")
+                        });
+                    if let Err(error) = result {
+                        buffer.truncate(rollback);
+                        if !buffer.is_empty() {
+                            yield buffer.split().freeze();
+                        }
+                        Err(error)?;
+                    }
+                    if buffer.len() >= options.flush_bytes {
+                        yield buffer.split().freeze();
+                    }
+                }
+                if !buffer.is_empty() {
+                    yield buffer.split().freeze();
+                }
+            }
+            OutputFormat::Sse => {
+                // Like with the envelope, errors are reported in an `error` event rather than terminating the
+                // stream, since EventSource clients can't tell a truncated stream from a dropped connection.
+                let mut records = 0u64;
+                let mut error = None;
+                for await value in values {
+                    let rollback = buffer.len();
+                    buffer.extend_from_slice(b"event: record\ndata: ");
+                    let result = value
+                        .context("failed to read from input stream")
+                        .and_then(|value| {
+                            serde_json::to_writer(BufferWriter(&mut buffer), &value).context("failed to serialize value")
+                        });
+                    if let Err(value_error) = result {
+                        buffer.truncate(rollback);
+                        error = Some(value_error);
+                        break;
+                    }
+                    buffer.extend_from_slice(b"\n\n");
+                    records += 1;
+                    if buffer.len() >= options.flush_bytes {
+                        yield buffer.split().freeze();
+                    }
+                }
+                match error {
+                    Some(error) => {
+                        warn!("error during JSON serialization, reporting it in an event: {:?}", error);
+                        buffer.extend_from_slice(b"event: error\ndata: ");
+                        let error = serde_json::json!({ "error": format!("{:#}", error) });
+                        serde_json::to_writer(BufferWriter(&mut buffer), &error).context("failed to serialize error")?;
+                    }
+                    None => {
+                        buffer.extend_from_slice(b"event: done\ndata: ");
+                        let done = serde_json::json!({ "records": records });
+                        serde_json::to_writer(BufferWriter(&mut buffer), &done).context("failed to serialize event")?;
+                    }
+                }
+                buffer.extend_from_slice(b"\n\n");
+                yield buffer.split().freeze();
+            }
+        }
+    }
+}
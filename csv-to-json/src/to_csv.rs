@@ -0,0 +1,412 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::{pin_mut, Stream, TryStreamExt};
+use serde_json::{Map, Value};
+
+/// A single flat JSON object that will be written as one CSV record.
+pub type JsonObject = Map<String, Value>;
+
+/// Incremental splitter that finds the top-level elements of a JSON array as its bytes arrive,
+/// without having to buffer the whole array in memory. Only the bytes of the element currently
+/// being read are buffered.
+#[derive(Default)]
+struct JsonArraySplitter {
+    state: SplitterState,
+    element: Vec<u8>,
+    elements_read: usize,
+}
+
+#[derive(Default, PartialEq)]
+enum SplitterState {
+    /// Waiting for the opening `[` of the array.
+    #[default]
+    Start,
+    /// Just read the opening `[`, so either an element or the closing `]` may follow.
+    FirstElementOrEnd,
+    /// Just read a `,` so an element must follow.
+    Element,
+    /// Inside an object element, tracking nesting so we know where the element ends.
+    InElement {
+        depth: usize,
+        in_string: bool,
+        escaped: bool,
+    },
+    /// Just finished an element, so either a `,` or the closing `]` may follow.
+    SeparatorOrEnd,
+    /// Read the closing `]`, only whitespace may follow.
+    End,
+}
+
+impl JsonArraySplitter {
+    /// Feeds the next chunk of input into the splitter, returning any elements completed by it.
+    fn push(&mut self, chunk: &[u8]) -> Result<Vec<JsonObject>> {
+        let mut objects = Vec::new();
+        for &byte in chunk {
+            match &mut self.state {
+                SplitterState::InElement {
+                    depth,
+                    in_string,
+                    escaped,
+                } => {
+                    self.element.push(byte);
+                    if *in_string {
+                        match byte {
+                            _ if *escaped => *escaped = false,
+                            b'\\' => *escaped = true,
+                            b'"' => *in_string = false,
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    match byte {
+                        b'"' => *in_string = true,
+                        b'{' | b'[' => *depth += 1,
+                        b'}' | b']' => *depth -= 1,
+                        _ => {}
+                    }
+                    if *depth == 0 {
+                        objects.push(self.finish_element()?);
+                        self.state = SplitterState::SeparatorOrEnd;
+                    }
+                }
+                _ if byte.is_ascii_whitespace() => {}
+                SplitterState::Start if byte == b'[' => {
+                    self.state = SplitterState::FirstElementOrEnd;
+                }
+                SplitterState::FirstElementOrEnd | SplitterState::SeparatorOrEnd
+                    if byte == b']' =>
+                {
+                    self.state = SplitterState::End;
+                }
+                SplitterState::SeparatorOrEnd if byte == b',' => {
+                    self.state = SplitterState::Element;
+                }
+                SplitterState::FirstElementOrEnd | SplitterState::Element if byte == b'{' => {
+                    self.element.push(byte);
+                    self.state = SplitterState::InElement {
+                        depth: 1,
+                        in_string: false,
+                        escaped: false,
+                    };
+                }
+                SplitterState::FirstElementOrEnd | SplitterState::Element => {
+                    bail!(
+                        "expected JSON object for array element {} but found {:?}",
+                        self.elements_read + 1,
+                        byte as char
+                    );
+                }
+                SplitterState::Start => bail!("expected a JSON array but found {:?}", byte as char),
+                SplitterState::SeparatorOrEnd => bail!(
+                    "expected ',' or ']' after array element {} but found {:?}",
+                    self.elements_read,
+                    byte as char
+                ),
+                SplitterState::End => {
+                    bail!("unexpected {:?} after end of JSON array", byte as char)
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    fn finish_element(&mut self) -> Result<JsonObject> {
+        self.elements_read += 1;
+        let object = serde_json::from_slice(&self.element)
+            .with_context(|| format!("invalid JSON in array element {}", self.elements_read))?;
+        self.element.clear();
+        Ok(object)
+    }
+
+    /// Signals the end of input, erroring if the array was not properly closed.
+    fn finish(self) -> Result<()> {
+        match self.state {
+            SplitterState::End => Ok(()),
+            _ => bail!("unexpected end of input before end of JSON array"),
+        }
+    }
+}
+
+/// Describes the type of a JSON value for error messages.
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Incremental splitter for JSON Lines input, where every line contains a single JSON object. Blank
+/// lines are skipped. Only the bytes of the line currently being read are buffered.
+#[derive(Default)]
+struct JsonLinesSplitter {
+    line: Vec<u8>,
+    lines_read: usize,
+}
+
+impl JsonLinesSplitter {
+    /// Feeds the next chunk of input into the splitter, adding any objects on lines completed by it to
+    /// `objects`. Objects on lines before an invalid line are still added, so that they can be
+    /// converted before the error is reported.
+    fn push(&mut self, chunk: &[u8], objects: &mut Vec<JsonObject>) -> Result<()> {
+        let mut lines = chunk.split(|&byte| byte == b'\n');
+        // The last part of the chunk isn't newline terminated (yet), so it's buffered until the rest of
+        // its line arrives.
+        let rest = lines.next_back().unwrap_or_default();
+        for line in lines {
+            self.line.extend_from_slice(line);
+            objects.extend(self.finish_line()?);
+        }
+        self.line.extend_from_slice(rest);
+        Ok(())
+    }
+
+    fn finish_line(&mut self) -> Result<Option<JsonObject>> {
+        self.lines_read += 1;
+        if self.line.iter().all(u8::is_ascii_whitespace) {
+            self.line.clear();
+            return Ok(None);
+        }
+        let value = serde_json::from_slice(&self.line)
+            .with_context(|| format!("invalid JSON on line {}", self.lines_read))?;
+        self.line.clear();
+        match value {
+            Value::Object(object) => Ok(Some(object)),
+            value => bail!(
+                "expected JSON object on line {} but found {}",
+                self.lines_read,
+                value_kind(&value)
+            ),
+        }
+    }
+
+    /// Signals the end of input, returning the object on the last line if it wasn't newline terminated.
+    fn finish(mut self) -> Result<Option<JsonObject>> {
+        if self.line.is_empty() {
+            Ok(None)
+        } else {
+            self.finish_line()
+        }
+    }
+}
+
+/// Stream producer that takes a stream of input bytes containing a JSON array of objects and
+/// yields each object as soon as it has been completely read.
+pub fn parse_json_objects<S, B, E>(input: S) -> impl Stream<Item = Result<JsonObject>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    try_stream! {
+        let mut splitter = JsonArraySplitter::default();
+        for await chunk in input {
+            let chunk = chunk
+                .map_err(Into::<anyhow::Error>::into)
+                .context("failed to read from input stream")?;
+            for object in splitter.push(chunk.as_ref())? {
+                yield object;
+            }
+        }
+        splitter.finish()?;
+    }
+}
+
+/// Stream producer that takes a stream of input bytes containing JSON Lines, i.e. one JSON object per
+/// line, and yields each object as soon as its line has been completely read.
+pub fn parse_json_lines<S, B, E>(input: S) -> impl Stream<Item = Result<JsonObject>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    try_stream! {
+        let mut splitter = JsonLinesSplitter::default();
+        let mut objects = Vec::new();
+        for await chunk in input {
+            let chunk = chunk
+                .map_err(Into::<anyhow::Error>::into)
+                .context("failed to read from input stream")?;
+            let result = splitter.push(chunk.as_ref(), &mut objects);
+            for object in objects.drain(..) {
+                yield object;
+            }
+            result?;
+        }
+        if let Some(object) = splitter.finish()? {
+            yield object;
+        }
+    }
+}
+
+/// Converts a flat JSON value into the text of a CSV field. Nested arrays and objects have no
+/// sensible CSV representation so they're rejected.
+fn json_value_to_field(name: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::Null => Ok(String::new()),
+        Value::String(string) => Ok(string.clone()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(_) | Value::Object(_) => Err(anyhow!(
+            "field {:?} contains a nested value, only flat objects can be converted to CSV",
+            name
+        )),
+    }
+}
+
+/// Writes a single CSV record into a buffer of UTF-8-encoded bytes.
+async fn write_csv_record<I, T>(builder: &csv_async::AsyncWriterBuilder, record: I) -> Result<Bytes>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    // KLUDGE: csv_async's writer takes ownership of the underlying writer and has no way to drain it
+    //         without consuming the writer, so we create a new writer for each record.
+    let mut writer = builder.create_writer(Vec::new());
+    writer
+        .write_record(record)
+        .await
+        .context("failed to write CSV record")?;
+    let buffer = writer
+        .into_inner()
+        .await
+        .map_err(|_| anyhow!("failed to flush CSV record"))?;
+    Ok(Bytes::from(buffer))
+}
+
+/// Builds the fields of a CSV record from a JSON object, ordered according to the headers. Fields
+/// for headers missing from the object are left empty.
+fn object_to_fields(headers: &[String], object: &JsonObject, number: usize) -> Result<Vec<String>> {
+    if let Some(unknown) = object.keys().find(|key| !headers.contains(key)) {
+        bail!(
+            "object {} has field {:?} which is not in the header derived from the first object",
+            number,
+            unknown
+        );
+    }
+    headers
+        .iter()
+        .map(|name| match object.get(name) {
+            Some(value) => json_value_to_field(name, value),
+            None => Ok(String::new()),
+        })
+        .collect()
+}
+
+/// Stream producer that takes a stream of flat JSON objects and serializes them as CSV records in
+/// a UTF-8-encoded, binary chunked format.
+///
+/// The header row is derived from the keys of the first object. Later objects that are missing some
+/// of those keys have empty fields written in their place, but later objects that introduce keys not
+/// present in the header result in an error, since the header has already been written by then.
+pub fn serialize_csv_records<S>(objects: S) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<JsonObject>>,
+{
+    try_stream! {
+        let mut builder = csv_async::AsyncWriterBuilder::new();
+        builder.has_headers(false);
+
+        // The first object determines the header row so we treat it specially.
+        pin_mut!(objects);
+        if let Some(first_object) = objects.try_next().await? {
+            let headers: Vec<String> = first_object.keys().cloned().collect();
+            yield write_csv_record(&builder, &headers).await?;
+            let fields = object_to_fields(&headers, &first_object, 1)?;
+            yield write_csv_record(&builder, &fields).await?;
+
+            let mut number = 1;
+            for await object in objects {
+                number += 1;
+                let fields = object_to_fields(&headers, &object?, number)?;
+                yield write_csv_record(&builder, &fields).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn split(chunks: &[&str]) -> Result<Vec<JsonObject>> {
+        let mut splitter = JsonArraySplitter::default();
+        let mut objects = Vec::new();
+        for chunk in chunks {
+            objects.extend(splitter.push(chunk.as_bytes())?);
+        }
+        splitter.finish()?;
+        Ok(objects)
+    }
+
+    #[test]
+    fn splits_objects_across_chunk_boundaries() -> Result<()> {
+        let objects = split(&[r#" [{"a":"1","#, r#""b":"}]\"{"},"#, r#" {"a":2}] "#])?;
+        assert_eq!(
+            objects,
+            vec![
+                serde_json::from_str::<JsonObject>(r#"{"a":"1","b":"}]\"{"}"#)?,
+                serde_json::from_str::<JsonObject>(r#"{"a":2}"#)?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn splits_empty_array() -> Result<()> {
+        assert_eq!(split(&["[ ]"])?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_non_object_elements() {
+        assert!(split(&["[1]"]).is_err());
+        assert!(split(&[r#"[{"a":1},"b"]"#]).is_err());
+    }
+
+    fn split_lines(chunks: &[&str]) -> Result<Vec<JsonObject>> {
+        let mut splitter = JsonLinesSplitter::default();
+        let mut objects = Vec::new();
+        for chunk in chunks {
+            splitter.push(chunk.as_bytes(), &mut objects)?;
+        }
+        objects.extend(splitter.finish()?);
+        Ok(objects)
+    }
+
+    #[test]
+    fn splits_lines_across_chunk_boundaries() -> Result<()> {
+        let objects = split_lines(&[r#"{"a":"1","#, "\"b\":2}\r\n\n  \n{\"a\"", r#":3}"#])?;
+        assert_eq!(
+            objects,
+            vec![
+                serde_json::from_str::<JsonObject>(r#"{"a":"1","b":2}"#)?,
+                serde_json::from_str::<JsonObject>(r#"{"a":3}"#)?,
+            ]
+        );
+        assert_eq!(split_lines(&["{\"a\":1}\n"])?.len(), 1);
+        assert_eq!(split_lines(&[""])?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_non_object_lines_by_line_number() {
+        let error = split_lines(&["{\"a\":1}\n\n[1]\n"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "expected JSON object on line 3 but found an array"
+        );
+        let error = split_lines(&["{\"a\":1}\n{\"a\":"]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid JSON on line 2");
+    }
+
+    #[test]
+    fn rejects_unterminated_array() {
+        assert!(split(&[r#"[{"a":1}"#]).is_err());
+        assert!(split(&[""]).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow_array::builder::{BooleanBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use bytes::Bytes;
+use csv_to_json::{ColumnSchema, ColumnType};
+use futures::{pin_mut, Stream, StreamExt};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::{Map, Value};
+
+/// Number of records in each row group of the Parquet file. The records of a row group are held in
+/// memory as Arrow arrays until it's written.
+const ROW_GROUP_SIZE: usize = 8192;
+
+/// The Parquet file grew beyond the maximum size that's buffered for a response.
+#[derive(Debug)]
+pub struct ParquetTooLarge {
+    pub max_bytes: usize,
+}
+
+impl std::fmt::Display for ParquetTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the Parquet file is larger than {} bytes",
+            self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for ParquetTooLarge {}
+
+/// Builder of the Arrow array of a single column, of the Arrow type that matches the column's type.
+enum ColumnBuilder {
+    Integer(Int64Builder),
+    Number(Float64Builder),
+    Boolean(BooleanBuilder),
+    String(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(column_type: ColumnType) -> Self {
+        match column_type {
+            ColumnType::Integer => ColumnBuilder::Integer(Int64Builder::new()),
+            ColumnType::Number => ColumnBuilder::Number(Float64Builder::new()),
+            ColumnType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            ColumnType::String => ColumnBuilder::String(StringBuilder::new()),
+        }
+    }
+
+    /// Appends the value of a field, which is a string unless the CSV was converted with `infer-types`
+    /// or `types`. Missing fields and nulls are appended as nulls, and so are empty fields of columns
+    /// other than strings. Returns false if the value doesn't fit the column's type.
+    fn append(&mut self, value: Option<&Value>) -> bool {
+        let value = match value {
+            None | Some(Value::Null) => {
+                self.append_null();
+                return true;
+            }
+            Some(Value::String(field)) if field.is_empty() && !matches!(self, Self::String(_)) => {
+                self.append_null();
+                return true;
+            }
+            Some(value) => value,
+        };
+        match (self, value) {
+            (ColumnBuilder::Integer(builder), Value::Number(number)) => match number.as_i64() {
+                Some(int) => builder.append_value(int),
+                None => return false,
+            },
+            (ColumnBuilder::Integer(builder), Value::String(field)) => match field.parse() {
+                Ok(int) => builder.append_value(int),
+                Err(_) => return false,
+            },
+            (ColumnBuilder::Number(builder), Value::Number(number)) => match number.as_f64() {
+                Some(float) => builder.append_value(float),
+                None => return false,
+            },
+            (ColumnBuilder::Number(builder), Value::String(field)) => match field.parse() {
+                Ok(float) => builder.append_value(float),
+                Err(_) => return false,
+            },
+            (ColumnBuilder::Boolean(builder), Value::Bool(bool)) => builder.append_value(*bool),
+            (ColumnBuilder::Boolean(builder), Value::String(field))
+                if field.eq_ignore_ascii_case("true") || field.eq_ignore_ascii_case("false") =>
+            {
+                builder.append_value(field.eq_ignore_ascii_case("true"))
+            }
+            (ColumnBuilder::String(builder), Value::String(field)) => builder.append_value(field),
+            (ColumnBuilder::String(builder), value) => builder.append_value(value.to_string()),
+            _ => return false,
+        }
+        true
+    }
+
+    fn append_null(&mut self) {
+        match self {
+            ColumnBuilder::Integer(builder) => builder.append_null(),
+            ColumnBuilder::Number(builder) => builder.append_null(),
+            ColumnBuilder::Boolean(builder) => builder.append_null(),
+            ColumnBuilder::String(builder) => builder.append_null(),
+        }
+    }
+
+    /// Returns the array of the values appended so far, leaving the builder empty for the next row
+    /// group.
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Integer(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Number(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Boolean(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::String(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+fn data_type(column_type: ColumnType) -> DataType {
+    match column_type {
+        ColumnType::Integer => DataType::Int64,
+        ColumnType::Number => DataType::Float64,
+        ColumnType::Boolean => DataType::Boolean,
+        ColumnType::String => DataType::Utf8,
+    }
+}
+
+fn type_name(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Integer => "integer",
+        ColumnType::Number => "number",
+        ColumnType::Boolean => "boolean",
+        ColumnType::String => "string",
+    }
+}
+
+/// Writes the records that were appended to the builders as a row group.
+fn write_row_group(
+    writer: &mut ArrowWriter<Vec<u8>>,
+    schema: &SchemaRef,
+    builders: &mut [ColumnBuilder],
+) -> Result<()> {
+    let columns = builders.iter_mut().map(ColumnBuilder::finish).collect();
+    writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes records, as converted to JSON objects, to a Snappy compressed Parquet file with the given
+/// columns. Every column is nullable, and the fields of each record have to fit the column types,
+/// otherwise writing fails with an error naming the first field that doesn't.
+///
+/// Parquet files end with the metadata of all of their row groups, so the whole file is written to
+/// memory before it's returned. Writing fails with ParquetTooLarge once it's larger than `max_bytes`.
+pub async fn write_parquet<S>(
+    columns: &[ColumnSchema],
+    max_bytes: usize,
+    records: S,
+) -> Result<Bytes>
+where
+    S: Stream<Item = Result<Map<String, Value>>>,
+{
+    let schema: SchemaRef = Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|column| Field::new(&column.name, data_type(column.column_type), true))
+            .collect::<Vec<_>>(),
+    ));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_row_count(Some(ROW_GROUP_SIZE))
+        .build();
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(properties))?;
+    let mut builders: Vec<_> = columns
+        .iter()
+        .map(|column| ColumnBuilder::new(column.column_type))
+        .collect();
+    let mut rows = 0;
+    let mut records_read = 0;
+    pin_mut!(records);
+    while let Some(record) = records.next().await {
+        let record = record?;
+        records_read += 1;
+        for (column, builder) in columns.iter().zip(&mut builders) {
+            let value = record.get(&column.name);
+            if !builder.append(value) {
+                return Err(anyhow!(
+                    "field {} of column {:?} in record {} doesn't fit the column's {} type",
+                    value.expect("missing fields are null"),
+                    column.name,
+                    records_read,
+                    type_name(column.column_type),
+                ));
+            }
+        }
+        rows += 1;
+        if rows == ROW_GROUP_SIZE {
+            write_row_group(&mut writer, &schema, &mut builders)?;
+            rows = 0;
+            if writer.bytes_written() > max_bytes {
+                return Err(ParquetTooLarge { max_bytes }.into());
+            }
+        }
+    }
+    if rows > 0 {
+        write_row_group(&mut writer, &schema, &mut builders)?;
+    }
+    let file = writer.into_inner()?;
+    if file.len() > max_bytes {
+        return Err(ParquetTooLarge { max_bytes }.into());
+    }
+    Ok(Bytes::from(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn writes_records_in_row_groups() -> Result<()> {
+        let columns = [ColumnSchema {
+            name: "id".to_string(),
+            column_type: ColumnType::Integer,
+        }];
+        let records = (0..ROW_GROUP_SIZE + 1).map(|id| {
+            let mut record = Map::new();
+            record.insert("id".to_string(), Value::String(id.to_string()));
+            Ok(record)
+        });
+        let parquet = block_on(write_parquet(
+            &columns,
+            usize::MAX,
+            futures::stream::iter(records),
+        ))?;
+        let metadata = SerializedFileReader::new(parquet)?.metadata().clone();
+        let row_groups: Vec<_> = metadata
+            .row_groups()
+            .iter()
+            .map(|row_group| row_group.num_rows())
+            .collect();
+        assert_eq!(row_groups, vec![ROW_GROUP_SIZE as i64, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_once_larger_than_max_bytes() {
+        let columns = [ColumnSchema {
+            name: "a".to_string(),
+            column_type: ColumnType::String,
+        }];
+        let error = block_on(write_parquet(&columns, 16, futures::stream::empty())).unwrap_err();
+        assert!(error.is::<ParquetTooLarge>());
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use predicates::prelude::*;
+use pretty_assertions::assert_eq;
+use std::fs;
+
+#[test]
+fn converts_file_to_stdout() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("birds.csv");
+    fs::write(
+        &input,
+        "date;number of birds\n2022-04-06;12\n2022-04-07;8\n",
+    )?;
+
+    Command::cargo_bin("csv-to-json")?
+        .arg("--convert")
+        .arg(&input)
+        .args(["--delimiter", ";", "--infer-types"])
+        .assert()
+        .success()
+        .stdout(
+            r#"[{"date":"2022-04-06","number of birds":12},{"date":"2022-04-07","number of birds":8}]"#,
+        );
+    Ok(())
+}
+
+#[test]
+fn converts_stdin_to_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let output = dir.path().join("birds.ndjson");
+
+    Command::cargo_bin("csv-to-json")?
+        .args(["convert", "--output", "ndjson", "--output-file"])
+        .arg(&output)
+        .write_stdin("a,b\n1,2\n3,4\n")
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "{\"a\":\"1\",\"b\":\"2\"}\n{\"a\":\"3\",\"b\":\"4\"}\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn fails_on_invalid_input() -> Result<()> {
+    Command::cargo_bin("csv-to-json")?
+        .arg("--convert")
+        .write_stdin(&b"a,b\n1,\xff\n"[..])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid utf-8"));
+    Command::cargo_bin("csv-to-json")?
+        .args(["--convert", "does-not-exist.csv"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "failed to open does-not-exist.csv",
+        ));
+    Ok(())
+}